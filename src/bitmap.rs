@@ -0,0 +1,200 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::checksum::{adler32, crc32};
+use crate::memory::{Memory, DATA_SEGMENT_BASE, STACK_POINTER};
+
+/// counts frames, a write ends the current frame and a read
+/// returns the number of frames ended so far
+pub const FRAME_REGISTER: u32 = 0xffff0010;
+
+/// a display drawn from a framebuffer in memory, like MARS's Bitmap
+/// Display tool, each unit of the display is a word holding its
+/// color as 0x00RRGGBB, stored row by row from the base address
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Bitmap {
+    base: u32,
+    // in pixels
+    width: u32,
+    height: u32,
+    // pixels covered by each unit
+    unit_width: u32,
+    unit_height: u32,
+}
+
+impl Default for Bitmap {
+    fn default() -> Self {
+        Bitmap { base: DATA_SEGMENT_BASE, width: 512, height: 256, unit_width: 1, unit_height: 1 }
+    }
+}
+
+impl Bitmap {
+
+    /// the display must be a whole number of units and its
+    /// framebuffer must fit in memory
+    pub fn new(base: u32, (width, height): (u32, u32), (unit_width, unit_height): (u32, u32)) -> Result<Bitmap, String> {
+        if width == 0 || height == 0 || unit_width == 0 || unit_height == 0 {
+            return Err("the display and its units cannot be empty".to_string());
+        }
+        if !width.is_multiple_of(unit_width) || !height.is_multiple_of(unit_height) {
+            return Err(format!("a {width}x{height} display cannot be split into {unit_width}x{unit_height} units"));
+        }
+        let bitmap = Bitmap { base, width, height, unit_width, unit_height };
+        let size = 4 * bitmap.columns() as u64 * bitmap.rows() as u64;
+        if !base.is_multiple_of(4) || base < DATA_SEGMENT_BASE || base as u64 + size > STACK_POINTER as u64 + 4 {
+            return Err(format!("a framebuffer of {size} bytes does not fit in memory at 0x{base:08x}"));
+        }
+        Ok(bitmap)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// units on each row
+    pub fn columns(&self) -> u32 {
+        self.width / self.unit_width
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.height / self.unit_height
+    }
+
+    pub fn framebuffer(&self) -> Range<u32> {
+        self.base..self.base + 4 * self.columns() * self.rows()
+    }
+
+    /// the display as rows of RGB pixels
+    pub fn render(&self, memory: &Memory) -> Vec<u8> {
+        let units: Vec<[u8; 3]> = self.framebuffer().step_by(4)
+            .map(|addr| {
+                let [b, g, r, _] = memory.read_word(addr).unwrap_or_default().to_le_bytes();
+                [r, g, b]
+            })
+            .collect();
+        let mut pixels = Vec::with_capacity(3 * (self.width * self.height) as usize);
+        for y in 0..self.height {
+            let row = (y / self.unit_height * self.columns()) as usize;
+            for x in 0..self.width {
+                pixels.extend(units[row + (x / self.unit_width) as usize]);
+            }
+        }
+        pixels
+    }
+
+    /// writes the display as a PNG if the path ends in .png
+    /// and as a binary PPM otherwise
+    pub fn save(&self, memory: &Memory, path: &Path) -> std::io::Result<()> {
+        let pixels = self.render(memory);
+        let image = match path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+            true => encode_png(self.width, self.height, &pixels),
+            false => encode_ppm(self.width, self.height, &pixels),
+        };
+        std::fs::write(path, image)
+    }
+
+}
+
+/// `WIDTHxHEIGHT`, or a single number for a square
+pub fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let number = |n: &str| n.trim().parse::<u32>().map_err(|_| format!("invalid size: {s}, expected WIDTHxHEIGHT"));
+    match s.split_once('x') {
+        Some((width, height)) => Ok((number(width)?, number(height)?)),
+        None => number(s).map(|n| (n, n)),
+    }
+}
+
+/// path of the nth frame, `out.png` becomes `out-0003.png`
+pub fn frame_path(path: &Path, frame: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{frame:04}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{frame:04}"),
+    };
+    path.with_file_name(name)
+}
+
+fn encode_ppm(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    let mut image = format!("P6\n{width} {height}\n255\n").into_bytes();
+    image.extend_from_slice(pixels);
+    image
+}
+
+/// an uncompressed PNG, zlib data is written as stored deflate
+/// blocks so that no compressor is needed
+fn encode_png(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    // every scanline starts with filter type 0
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks((3 * width) as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filtering and no interlacing
+    header.extend([8, 2, 0, 0, 0]);
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", header.as_slice()), (b"IDAT", zlib.as_slice()), (b"IEND", &[])] {
+        image.extend((data.len() as u32).to_be_bytes());
+        let start = image.len();
+        image.extend_from_slice(kind);
+        image.extend_from_slice(data);
+        let crc = crc32(&image[start..]);
+        image.extend(crc.to_be_bytes());
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let bitmap = Bitmap::new(DATA_SEGMENT_BASE, (4, 2), (2, 1)).unwrap();
+        assert_eq!(bitmap.framebuffer(), DATA_SEGMENT_BASE..DATA_SEGMENT_BASE + 16);
+        let mut memory = Memory::new();
+        memory.write_word(DATA_SEGMENT_BASE, 0x00ff0000).unwrap();
+        memory.write_word(DATA_SEGMENT_BASE + 12, 0x000080ff).unwrap();
+        assert_eq!(bitmap.render(&memory), [
+            255, 0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 128, 255, 0, 128, 255,
+        ]);
+        assert_eq!(&encode_ppm(4, 2, &bitmap.render(&memory))[..11], b"P6\n4 2\n255\n");
+
+        let png = encode_png(4, 2, &bitmap.render(&memory));
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        assert!(Bitmap::new(DATA_SEGMENT_BASE, (4, 2), (3, 1)).is_err());
+        assert!(Bitmap::new(0x1000_0000, (4, 2), (1, 1)).is_err());
+        assert_eq!(parse_size("512x256"), Ok((512, 256)));
+        assert_eq!(parse_size("8"), Ok((8, 8)));
+        assert!(parse_size("8x").is_err());
+        assert_eq!(frame_path(Path::new("out/frame.png"), 3), PathBuf::from("out/frame-0003.png"));
+    }
+}
@@ -39,9 +39,34 @@ pub enum Bytecode {
     // =======================
     ADD,
 
+    // Memory Specific
+    // =======================
+    // pops an address and pushes the word at address + offset
+    LOAD(u32),
+    // pops an address then a value and stores the value at address + offset
+    STORE(u32),
+
     // Branch Specific
     // =======================
     JUMP(u32),
+    // pops a text address and jumps to it
+    JUMPR,
+    // sets $ra to the return address and jumps, entering a call frame
+    CALL(u32),
+    // pops a text address, sets the register to the return address
+    // and jumps to it, entering a call frame
+    CALLR(Value),
+    // same as JUMPR but also leaves the current call frame
+    RETURN,
+
+    // Coprocessor 0 Specific
+    // =======================
+    // pushes the value of a CP0 register
+    GETC(u32),
+    // pops a value and sets a CP0 register to it
+    SETC(u32),
+    // returns from the exception handler to EPC
+    ERET,
 
 }
 
@@ -55,14 +80,14 @@ pub enum WhereTo {
 impl WhereTo {
 
     pub fn lift_label(&self) -> String {
-        return match self {
+        match self {
             WhereTo::Label(label) => { label.clone() }
             _ => panic!("cannot lift label")
         }
     }
 
     pub fn lift_line(&self) -> u32 {
-        return match self {
+        match self {
             WhereTo::Line(line) => { *line }
             _ => panic!("cannot lift line")
         }
@@ -74,21 +99,24 @@ impl WhereTo {
 pub enum Value {
     Register(u32),
     Immediate(i16),
+    // full 32 bit values such as addresses
+    Word(u32),
 }
 
 impl Value {
 
     pub fn lift_register(&self) -> u32 {
-        return match self {
+        match self {
             Value::Register(reg) => { *reg }
             _ => panic!("cannot lift register value")
         }
     }
 
     pub fn lift_immediate(&self) -> u32 {
-        return match self {
+        match self {
             // i16 to u32
             Value::Immediate(imm) => { *imm as u32 }
+            Value::Word(word) => { *word }
             _ => panic!("cannot life immediate value")
         }
     }
@@ -98,8 +126,22 @@ impl Value {
 #[derive(Serialize, Deserialize)]
 pub enum AsmInstruction {
     LI(String, i16),
+    LA(String, u32),
     ADD(String, String, String),
+    // register, offset and base register, a label operand is
+    // encoded as its address with $zero as the base
+    LW(String, u32, String),
+    SW(String, u32, String),
     JUMP(WhereTo),
+    JAL(WhereTo),
+    // link register and target register
+    JALR(String, String),
+    JR(String),
+    SYSCALL,
+    // general purpose register and CP0 register
+    MFC0(String, u32),
+    MTC0(String, u32),
+    ERET,
 }
 
 impl std::str::FromStr for AsmInstruction {
@@ -109,7 +151,16 @@ impl std::str::FromStr for AsmInstruction {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "li" => Ok(AsmInstruction::LI(Default::default(), Default::default())),
+            "la" => Ok(AsmInstruction::LA(Default::default(), Default::default())),
             "add" => Ok(AsmInstruction::ADD(Default::default(), Default::default(), Default::default())),
+            "lw" => Ok(AsmInstruction::LW(Default::default(), Default::default(), Default::default())),
+            "sw" => Ok(AsmInstruction::SW(Default::default(), Default::default(), Default::default())),
+            "jalr" => Ok(AsmInstruction::JALR(Default::default(), Default::default())),
+            "jr" => Ok(AsmInstruction::JR(Default::default())),
+            "syscall" => Ok(AsmInstruction::SYSCALL),
+            "mfc0" => Ok(AsmInstruction::MFC0(Default::default(), Default::default())),
+            "mtc0" => Ok(AsmInstruction::MTC0(Default::default(), Default::default())),
+            "eret" => Ok(AsmInstruction::ERET),
             // "j" => Ok(AsmInstruction::JUMP(Default::default())),
             _ => Err(format!("invalid instruction: {s}"))
         }
@@ -122,7 +173,11 @@ impl AsmInstruction {
         match self {
             AsmInstruction::LI(reg, imm) => {
                 let reg_name = register_to_addr(reg.clone()).expect("invalid register name: {reg}");
-                translate::convert_li(reg_name, imm.clone())
+                translate::convert_li(reg_name, *imm)
+            },
+            AsmInstruction::LA(reg, addr) => {
+                let reg_name = register_to_addr(reg.clone()).expect("invalid register name: {reg}");
+                translate::convert_la(reg_name, *addr)
             },
             AsmInstruction::ADD(src, op1, op2) => {
                 let reg_name = register_to_addr(src.clone()).expect("invalid register name: {src}");
@@ -130,9 +185,19 @@ impl AsmInstruction {
                 let op2_name = register_to_addr(op2.clone()).expect("invalid register name: {op2}");
                 translate::convert_add(reg_name, op1_name, op2_name)
             },
+            AsmInstruction::LW(reg, offset, base) => {
+                let reg_name = register_to_addr(reg.clone()).expect("invalid register name: {reg}");
+                let base_name = register_to_addr(base.clone()).expect("invalid register name: {base}");
+                translate::convert_lw(reg_name, *offset, base_name)
+            },
+            AsmInstruction::SW(reg, offset, base) => {
+                let reg_name = register_to_addr(reg.clone()).expect("invalid register name: {reg}");
+                let base_name = register_to_addr(base.clone()).expect("invalid register name: {base}");
+                translate::convert_sw(reg_name, *offset, base_name)
+            },
             AsmInstruction::JUMP(where_to) => {
                 match where_to {
-                    WhereTo::Label(label) => {
+                    WhereTo::Label(_label) => {
                         unimplemented!()
                     },
                     WhereTo::Line(line) => { 
@@ -140,7 +205,46 @@ impl AsmInstruction {
                     },
                 }
             },
-            _ => { unimplemented!() }
+            AsmInstruction::JAL(where_to) => translate::convert_jal(where_to.lift_line()),
+            AsmInstruction::JALR(rd, rs) => {
+                let rd_name = register_to_addr(rd.clone()).expect("invalid register name: {rd}");
+                let rs_name = register_to_addr(rs.clone()).expect("invalid register name: {rs}");
+                translate::convert_jalr(rd_name, rs_name)
+            },
+            AsmInstruction::JR(rs) => {
+                let rs_name = register_to_addr(rs.clone()).expect("invalid register name: {rs}");
+                translate::convert_jr(rs_name)
+            },
+            AsmInstruction::SYSCALL => {
+                translate::convert_syscall()
+            },
+            AsmInstruction::MFC0(rt, rd) => {
+                let rt_name = register_to_addr(rt.clone()).expect("invalid register name: {rt}");
+                translate::convert_mfc0(rt_name, *rd)
+            },
+            AsmInstruction::MTC0(rt, rd) => {
+                let rt_name = register_to_addr(rt.clone()).expect("invalid register name: {rt}");
+                translate::convert_mtc0(rt_name, *rd)
+            },
+            AsmInstruction::ERET => vec![Bytecode::ERET],
+        }
+    }
+
+    /// register the instruction writes its result to, if any
+    pub fn destination(&self) -> Option<&str> {
+        match self {
+            AsmInstruction::LI(rd, _) | AsmInstruction::LA(rd, _) | AsmInstruction::ADD(rd, _, _) | AsmInstruction::LW(rd, _, _) | AsmInstruction::JALR(rd, _) | AsmInstruction::MFC0(rd, _) => Some(rd),
+            AsmInstruction::JAL(_) => Some("$ra"),
+            AsmInstruction::SW(..) | AsmInstruction::JUMP(_) | AsmInstruction::JR(_) | AsmInstruction::SYSCALL | AsmInstruction::MTC0(..) | AsmInstruction::ERET => None,
+        }
+    }
+
+    /// number of bytecodes the instruction lowers to, this is
+    /// known even before jump targets have been resolved
+    pub fn bytecode_len(&self) -> usize {
+        match self {
+            AsmInstruction::JUMP(_) | AsmInstruction::JAL(_) => 1,
+            _ => self.to_bytecode().len(),
         }
     }
 
@@ -165,11 +269,79 @@ mod translate {
         ]
     }
 
+    pub fn convert_la(reg: u32, addr: u32) -> Vec<Bytecode> {
+        vec![
+            Bytecode::PUSH(Value::Word(addr)),
+            Bytecode::SETO(Value::Register(reg)),
+        ]
+    }
+
+    pub fn convert_lw(reg: u32, offset: u32, base: u32) -> Vec<Bytecode> {
+        vec![
+            Bytecode::GETP(Value::Register(base)),
+            Bytecode::LOAD(offset),
+            Bytecode::SETO(Value::Register(reg)),
+        ]
+    }
+
+    pub fn convert_sw(reg: u32, offset: u32, base: u32) -> Vec<Bytecode> {
+        vec![
+            Bytecode::GETP(Value::Register(reg)),
+            Bytecode::GETP(Value::Register(base)),
+            Bytecode::STORE(offset),
+        ]
+    }
+
+    pub fn convert_syscall() -> Vec<Bytecode> {
+        vec![
+            Bytecode::SYSCALL,
+        ]
+    }
+
     pub fn convert_jump(line: u32) -> Vec<Bytecode> {
         vec![
             Bytecode::JUMP(line),
         ]
     }
+
+    pub fn convert_jal(line: u32) -> Vec<Bytecode> {
+        vec![
+            Bytecode::CALL(line),
+        ]
+    }
+
+    pub fn convert_jalr(rd: u32, rs: u32) -> Vec<Bytecode> {
+        vec![
+            Bytecode::GETP(Value::Register(rs)),
+            Bytecode::CALLR(Value::Register(rd)),
+        ]
+    }
+
+    pub fn convert_mfc0(rt: u32, rd: u32) -> Vec<Bytecode> {
+        vec![
+            Bytecode::GETC(rd),
+            Bytecode::SETO(Value::Register(rt)),
+        ]
+    }
+
+    pub fn convert_mtc0(rt: u32, rd: u32) -> Vec<Bytecode> {
+        vec![
+            Bytecode::GETP(Value::Register(rt)),
+            Bytecode::SETC(rd),
+        ]
+    }
+
+    // only a jump through $ra is treated as a return
+    pub fn convert_jr(rs: u32) -> Vec<Bytecode> {
+        let jump = match rs {
+            31 => Bytecode::RETURN,
+            _ => Bytecode::JUMPR,
+        };
+        vec![
+            Bytecode::GETP(Value::Register(rs)),
+            jump,
+        ]
+    }
 }
 
 #[cfg(test)]
@@ -187,6 +359,16 @@ mod tests {
         assert!(matches!(asm_out[1], Bytecode::SETO(Value::Register(8))));
     }
 
+    #[test]
+    fn test_to_la() {
+        let asm_in = AsmInstruction::LA("$a0".to_string(), 0x1001_0000);
+        let asm_out = asm_in.to_bytecode();
+
+        assert!(asm_out.len() == 2);
+        assert!(matches!(asm_out[0], Bytecode::PUSH(Value::Word(0x1001_0000))));
+        assert!(matches!(asm_out[1], Bytecode::SETO(Value::Register(4))));
+    }
+
     #[test]
     fn test_to_add() {
         let asm_in = AsmInstruction::ADD("$t0".to_string(), "$t1".to_string(), "$t2".to_string());
@@ -199,6 +381,23 @@ mod tests {
         assert!(matches!(asm_out[3], Bytecode::SETO(Value::Register(8))));
     }
 
+    #[test]
+    fn test_to_lw_sw() {
+        let asm_out = AsmInstruction::LW("$t0".to_string(), -4i32 as u32, "$sp".to_string()).to_bytecode();
+        assert_eq!(asm_out, vec![
+            Bytecode::GETP(Value::Register(29)),
+            Bytecode::LOAD(0xffff_fffc),
+            Bytecode::SETO(Value::Register(8)),
+        ]);
+
+        let asm_out = AsmInstruction::SW("$t0".to_string(), 8, "$sp".to_string()).to_bytecode();
+        assert_eq!(asm_out, vec![
+            Bytecode::GETP(Value::Register(8)),
+            Bytecode::GETP(Value::Register(29)),
+            Bytecode::STORE(8),
+        ]);
+    }
+
     #[test]
     fn test_to_jump() {
         let asm_in = AsmInstruction::JUMP(WhereTo::Line(0));
@@ -212,5 +411,22 @@ mod tests {
         assert!(asm_out.len() == 1);
         assert!(matches!(asm_out[0], Bytecode::JUMP(123)));
     }
-}
 
+    #[test]
+    fn test_to_calls() {
+        assert_eq!(AsmInstruction::JAL(WhereTo::Line(6)).to_bytecode(), vec![Bytecode::CALL(6)]);
+        assert_eq!(AsmInstruction::JALR("$ra".to_string(), "$t0".to_string()).to_bytecode(), vec![
+            Bytecode::GETP(Value::Register(8)),
+            Bytecode::CALLR(Value::Register(31)),
+        ]);
+        assert_eq!(AsmInstruction::JR("$ra".to_string()).to_bytecode(), vec![Bytecode::GETP(Value::Register(31)), Bytecode::RETURN]);
+        assert_eq!(AsmInstruction::JR("$t0".to_string()).to_bytecode(), vec![Bytecode::GETP(Value::Register(8)), Bytecode::JUMPR]);
+    }
+
+    #[test]
+    fn test_to_cp0() {
+        assert_eq!(AsmInstruction::MFC0("$k0".to_string(), 13).to_bytecode(), vec![Bytecode::GETC(13), Bytecode::SETO(Value::Register(26))]);
+        assert_eq!(AsmInstruction::MTC0("$t0".to_string(), 12).to_bytecode(), vec![Bytecode::GETP(Value::Register(8)), Bytecode::SETC(12)]);
+        assert_eq!(AsmInstruction::ERET.to_bytecode(), vec![Bytecode::ERET]);
+    }
+}
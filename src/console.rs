@@ -0,0 +1,306 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::debug_table::MachineException;
+use crate::snapshot::{put_bytes, Fields};
+
+/// where a program's console input comes from and its output goes
+pub trait ConsoleIo {
+    /// appends the next line of input, newline included, to buf and
+    /// returns the number of bytes read, 0 at the end of input
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize>;
+
+    /// writes all of the bytes, output is not buffered
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()>;
+}
+
+/// stdin and stdout of the process
+pub struct Terminal;
+
+impl ConsoleIo for Terminal {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        std::io::stdin().lock().read_until(b'\n', buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes)?;
+        stdout.flush()
+    }
+}
+
+/// scripted input and captured output, for tests
+pub struct Buffer {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Buffer {
+
+    pub fn new(input: impl Into<Vec<u8>>) -> Buffer {
+        Buffer {
+            input: Cursor::new(input.into()),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+}
+
+impl ConsoleIo for Buffer {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        self.input.read_until(b'\n', buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// input read from and output written to files, the terminal
+/// is used for whichever of the two is not given
+pub struct FileConsole {
+    input: Option<BufReader<File>>,
+    output: Option<File>,
+}
+
+impl FileConsole {
+
+    /// opens the input file and creates or truncates the output file
+    pub fn open(input: Option<&Path>, output: Option<&Path>) -> std::io::Result<FileConsole> {
+        Ok(FileConsole {
+            input: input.map(File::open).transpose()?.map(BufReader::new),
+            output: output.map(File::create).transpose()?,
+        })
+    }
+
+}
+
+impl ConsoleIo for FileConsole {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        match self.input.as_mut() {
+            Some(input) => input.read_until(b'\n', buf),
+            None => Terminal.read_line(buf),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        match self.output.as_mut() {
+            Some(output) => output.write_all(bytes),
+            None => Terminal.write(bytes),
+        }
+    }
+}
+
+/// a TCP connection, e.g. from `nc 127.0.0.1 4000`
+pub struct SocketConsole {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl SocketConsole {
+
+    pub fn new(stream: TcpStream) -> std::io::Result<SocketConsole> {
+        Ok(SocketConsole {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// waits for a single connection on addr
+    pub fn listen(addr: &str) -> std::io::Result<SocketConsole> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        SocketConsole::new(stream)
+    }
+
+}
+
+impl ConsoleIo for SocketConsole {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        self.reader.read_until(b'\n', buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.writer.flush()
+    }
+}
+
+/// the console syscalls read and write, a line of input is
+/// read from the backend at a time and handed out as needed
+#[derive(Serialize, Deserialize)]
+pub struct Console {
+    // everything written so far
+    output: Vec<u8>,
+    // input read from the backend that no syscall consumed yet
+    pending: VecDeque<u8>,
+    // input consumed since it was last taken, given back on undo
+    consumed: Vec<u8>,
+    #[serde(skip, default = "Console::terminal")]
+    io: Box<dyn ConsoleIo>,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Console {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Console")
+            .field("output", &String::from_utf8_lossy(&self.output))
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+impl Console {
+
+    pub fn new() -> Console {
+        Console::with_io(Console::terminal())
+    }
+
+    pub fn with_io(io: Box<dyn ConsoleIo>) -> Console {
+        Console {
+            output: Vec::new(),
+            pending: VecDeque::new(),
+            consumed: Vec::new(),
+            io,
+        }
+    }
+
+    fn terminal() -> Box<dyn ConsoleIo> {
+        Box::new(Terminal)
+    }
+
+    /// swaps the backend, input already read from the old one is kept
+    pub fn set_io(&mut self, io: Box<dyn ConsoleIo>) {
+        self.io = io;
+    }
+
+    /// the console for a snapshot, the output so far and the input
+    /// read from the backend that no syscall consumed yet
+    pub fn save(&self) -> Vec<u8> {
+        let mut section = Vec::new();
+        put_bytes(&mut section, &self.output);
+        put_bytes(&mut section, &self.pending.iter().copied().collect::<Vec<u8>>());
+        section
+    }
+
+    pub fn restore(&mut self, fields: &mut Fields) -> std::io::Result<()> {
+        self.output = fields.bytes()?.to_vec();
+        self.pending = fields.bytes()?.iter().copied().collect();
+        self.consumed.clear();
+        Ok(())
+    }
+
+    /// everything written so far, byte for byte
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// number of bytes written so far
+    pub fn output_len(&self) -> usize {
+        self.output.len()
+    }
+
+    /// forgets output written after the first len bytes
+    pub fn truncate_output(&mut self, len: usize) {
+        self.output.truncate(len);
+    }
+
+    pub fn print(&mut self, bytes: &[u8]) -> Result<(), MachineException> {
+        self.output.extend_from_slice(bytes);
+        self.io.write(bytes).map_err(|e| MachineException::Io(e.to_string()))
+    }
+
+    /// reads another line from the backend if nothing is pending,
+    /// false at the end of input
+    fn fill(&mut self) -> Result<bool, MachineException> {
+        if self.pending.is_empty() {
+            let mut line = Vec::new();
+            self.io.read_line(&mut line).map_err(|e| MachineException::Io(e.to_string()))?;
+            self.pending.extend(line);
+        }
+        Ok(!self.pending.is_empty())
+    }
+
+    /// the rest of the current line, without its newline
+    pub fn read_line(&mut self) -> Result<Vec<u8>, MachineException> {
+        if !self.fill()? {
+            return Err(MachineException::Io("end of input".to_string()));
+        }
+        let end = self.pending.iter().position(|b| *b == b'\n').map_or(self.pending.len(), |n| n + 1);
+        let line: Vec<u8> = self.pending.drain(..end).collect();
+        self.consumed.extend_from_slice(&line);
+        Ok(line.strip_suffix(b"\n").map(|line| line.to_vec()).unwrap_or(line))
+    }
+
+    /// up to max bytes of the current line, none at the end of input
+    pub fn read_bytes(&mut self, max: usize) -> Result<Vec<u8>, MachineException> {
+        if !self.fill()? {
+            return Ok(Vec::new());
+        }
+        let end = self.pending.iter().position(|b| *b == b'\n').map_or(self.pending.len(), |n| n + 1).min(max);
+        let bytes: Vec<u8> = self.pending.drain(..end).collect();
+        self.consumed.extend_from_slice(&bytes);
+        Ok(bytes)
+    }
+
+    pub fn read_char(&mut self) -> Result<u8, MachineException> {
+        if !self.fill()? {
+            return Err(MachineException::Io("end of input".to_string()));
+        }
+        let byte = self.pending.pop_front().unwrap_or_default();
+        self.consumed.push(byte);
+        Ok(byte)
+    }
+
+    /// input consumed since the last call
+    pub fn take_consumed(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.consumed)
+    }
+
+    /// hands input back so it is read again
+    pub fn unread(&mut self, input: &[u8]) {
+        for byte in input.iter().rev() {
+            self.pending.push_front(*byte);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_console() {
+        let mut console = Console::with_io(Box::new(Buffer::new("12\nab")));
+        assert_eq!(console.read_char().unwrap(), b'1');
+        assert_eq!(console.read_line().unwrap(), b"2");
+        assert_eq!(console.take_consumed(), b"12\n");
+        assert_eq!(console.read_line().unwrap(), b"ab");
+        let consumed = console.take_consumed();
+        console.unread(&consumed);
+        assert_eq!(console.read_line().unwrap(), b"ab");
+        assert!(matches!(console.read_char(), Err(MachineException::Io(_))));
+
+        console.print(b"hi\n").unwrap();
+        console.print(&[0xff]).unwrap();
+        assert_eq!(console.output(), b"hi\n\xff");
+        console.truncate_output(2);
+        assert_eq!(console.output(), b"hi");
+    }
+}
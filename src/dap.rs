@@ -0,0 +1,483 @@
+use std::io::{BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::console::Buffer;
+use crate::debug_table::MachineException;
+use crate::debugger::{Debugger, Stop};
+use crate::diagnostics::Renderer;
+use crate::framing::{read_message, write_message};
+use crate::memory::STACK_POINTER;
+use crate::parser::parse_source;
+use crate::registers::addr_to_register;
+use crate::virtual_machine::{Clock, VirtualMachine};
+
+// there is a single thread
+const THREAD_ID: u64 = 1;
+
+// variables references of the scopes
+const REGISTERS: u64 = 1;
+const HILO: u64 = 2;
+const MEMORY: u64 = 3;
+
+// at most this many stack words are shown in the memory scope
+const MAX_STACK_WORDS: u32 = 64;
+
+/// what to do after handling a request
+enum Session {
+    Continue,
+    End,
+}
+
+/// Debug Adapter Protocol server, `launch` takes the path of the
+/// source file as `program`, an optional `stopOnEntry`, `checkCalls`,
+/// `uninit` (`"warn"` or `"trap"`), a `randomize` seed and the
+/// program's console `input`, its output is sent as output events,
+/// file syscalls are confined to `sandbox`, by default the directory
+/// of the program, random syscalls can be given a `seed` and the
+/// time syscall a `virtualClock` starting at the given milliseconds
+///
+/// requests are handled one at a time, so `pause` cannot interrupt
+/// a program that is running
+pub struct DapServer {
+    seq: u64,
+    source_path: String,
+    debugger: Option<Debugger>,
+    stop_on_entry: bool,
+    // lines breakpoints were requested on, set once the program is loaded
+    breakpoint_lines: Vec<u32>,
+    // bytes of the program's console output sent to the client
+    output_sent: usize,
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn format_value(value: u32) -> String {
+    format!("0x{value:08x} ({})", value as i32)
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+impl DapServer {
+
+    pub fn new() -> DapServer {
+        DapServer {
+            seq: 1,
+            source_path: String::new(),
+            debugger: None,
+            stop_on_entry: false,
+            breakpoint_lines: Vec::new(),
+            output_sent: 0,
+        }
+    }
+
+    /// handles requests until the client disconnects
+    pub fn serve<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> std::io::Result<()> {
+        while let Some(request) = read_message(&mut input)? {
+            if let Session::End = self.handle(&request, &mut output)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn send<W: Write>(&mut self, output: &mut W, mut message: Value) -> std::io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(output, &message)
+    }
+
+    fn event<W: Write>(&mut self, output: &mut W, event: &str, body: Value) -> std::io::Result<()> {
+        self.send(output, json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn respond<W: Write>(&mut self, output: &mut W, request: &Value, result: Result<Value, String>) -> std::io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(output, response)
+    }
+
+    fn handle<W: Write>(&mut self, request: &Value, output: &mut W) -> std::io::Result<Session> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsStepBack": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args, output),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                self.respond(output, request, Ok(json!({})))?;
+                let stop = match self.stop_on_entry {
+                    true => None,
+                    false => self.debugger.as_mut().map(|d| d.resume()),
+                };
+                match stop {
+                    Some(stop) => self.report(output, stop)?,
+                    None => self.event(output, "stopped", json!({ "reason": "entry", "threadId": THREAD_ID }))?,
+                }
+                return Ok(Session::Continue);
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "HI/LO", "variablesReference": HILO, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY, "expensive": false },
+            ]})),
+            "variables" => self.variables(args["variablesReference"].as_u64().unwrap_or_default()),
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                let Some(debugger) = self.debugger.as_mut() else {
+                    return self.respond(output, request, Err("no program is loaded".to_string())).map(|_| Session::Continue);
+                };
+                let stop = match command {
+                    "continue" => debugger.resume(),
+                    "next" => debugger.step_over(),
+                    "stepOut" => debugger.step_out(),
+                    "stepBack" => debugger.step_back_instruction(),
+                    "reverseContinue" => debugger.reverse(),
+                    _ => debugger.step_instruction(),
+                };
+                let body = match command {
+                    "continue" => json!({ "allThreadsContinued": true }),
+                    _ => json!({}),
+                };
+                self.respond(output, request, Ok(body))?;
+                self.report(output, stop)?;
+                return Ok(Session::Continue);
+            }
+            "pause" => Ok(json!({})),
+            "disconnect" | "terminate" => {
+                self.respond(output, request, Ok(json!({})))?;
+                self.event(output, "terminated", json!({}))?;
+                return Ok(Session::End);
+            }
+            _ => Err(format!("unsupported request: {command}")),
+        };
+        self.respond(output, request, result)?;
+        Ok(Session::Continue)
+    }
+
+    fn launch<W: Write>(&mut self, args: &Value, output: &mut W) -> Result<Value, String> {
+        let path = args["program"].as_str().ok_or("launch requires a program path")?.to_string();
+        let src = std::fs::read_to_string(&path).map_err(|e| format!("unable to read {path}: {e}"))?;
+        let program = match parse_source(&path, &src) {
+            Ok(program) => program,
+            Err(errors) => {
+                let renderer = Renderer::new(&path, &src, false);
+                let text: String = errors.iter().map(|e| renderer.render_error(e) + "\n").collect();
+                // the error is reported in the response, failing to show the details is not fatal
+                let _ = self.event(output, "output", json!({ "category": "stderr", "output": text }));
+                return Err(format!("could not assemble {path}"));
+            }
+        };
+
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_history_budget(args["history"].as_u64().unwrap_or(100_000) as usize);
+        vm.set_convention_check(args["checkCalls"].as_bool().unwrap_or(false));
+        // stdout carries the protocol, so the program gets a console of its own
+        vm.set_console(Box::new(Buffer::new(args["input"].as_str().unwrap_or_default())));
+        self.output_sent = 0;
+        let sandbox = match args["sandbox"].as_str() {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => std::path::Path::new(&path).parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map_or(std::path::PathBuf::from("."), |dir| dir.to_path_buf()),
+        };
+        vm.set_sandbox(Some(sandbox));
+        if let Some(seed) = args["seed"].as_u64() {
+            vm.set_random_seed(seed);
+        }
+        if let Some(millis) = args["virtualClock"].as_u64() {
+            vm.set_clock(Clock::Virtual(millis));
+        }
+        if let Some(check) = args["uninit"].as_str() {
+            vm.set_uninit_check(Some(check.parse()?));
+        }
+        if let Some(seed) = args["randomize"].as_u64() {
+            vm.randomize(seed);
+        }
+        self.debugger = Some(Debugger::new(vm, program.symbols));
+        self.source_path = path;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        let lines = std::mem::take(&mut self.breakpoint_lines);
+        self.apply_breakpoints(&lines);
+        Ok(json!({}))
+    }
+
+    /// breakpoints on lines without an instruction are unverified
+    fn apply_breakpoints(&mut self, lines: &[u32]) -> Vec<Value> {
+        self.breakpoint_lines = lines.to_vec();
+        let Some(debugger) = self.debugger.as_mut() else {
+            return lines.iter().map(|line| json!({ "verified": false, "line": line })).collect();
+        };
+        debugger.clear_breakpoints();
+        lines.iter().map(|line| {
+            match debugger.vm().runtime_dbg.compile_debug_info.line(*line) {
+                Some(pc) => {
+                    debugger.add_breakpoint(pc);
+                    json!({ "verified": true, "line": line })
+                }
+                None => json!({ "verified": false, "line": line, "message": "no instruction on this line" }),
+            }
+        }).collect()
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let lines: Vec<u32> = args["breakpoints"].as_array().map(|breakpoints| {
+            breakpoints.iter().filter_map(|b| b["line"].as_u64()).map(|line| line as u32).collect()
+        }).unwrap_or_default();
+        json!({ "breakpoints": self.apply_breakpoints(&lines) })
+    }
+
+    fn stack_trace(&self) -> Result<Value, String> {
+        let debugger = self.debugger.as_ref().ok_or("no program is loaded")?;
+        let runtime_dbg = &debugger.vm().runtime_dbg;
+        let calls = runtime_dbg.call_stack();
+        // innermost first, each caller is shown at its call site
+        let mut pc = debugger.vm().pc();
+        let mut frames = Vec::new();
+        for depth in (0..=calls.len()).rev() {
+            let entry = depth.checked_sub(1).map_or(0, |n| calls[n].entry);
+            let (line, column) = runtime_dbg.compile_debug_info.span(pc).map_or((0, 0), |span| (span.line, span.column));
+            frames.push(json!({
+                "id": frames.len() + 1,
+                "name": runtime_dbg.frame_name(entry),
+                "source": { "path": self.source_path },
+                "line": line,
+                "column": column,
+            }));
+            if let Some(n) = depth.checked_sub(1) {
+                pc = calls[n].call_site;
+            }
+        }
+        Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+    }
+
+    fn variables(&self, reference: u64) -> Result<Value, String> {
+        let vm = self.debugger.as_ref().ok_or("no program is loaded")?.vm();
+        let variables: Vec<Value> = match reference {
+            REGISTERS => (0..32).map(|reg| {
+                let name = addr_to_register(reg).map(|r| r.name).unwrap_or_default();
+                variable(&name, format_value(vm.reg_get(reg)))
+            }).collect(),
+            HILO => vec![
+                variable("hi", format_value(vm.reg_get(32))),
+                variable("lo", format_value(vm.reg_get(33))),
+            ],
+            MEMORY => {
+                let mut variables: Vec<Value> = vm.memory().labels().into_iter().map(|(name, range)| {
+                    let words: Vec<String> = range.clone().step_by(4)
+                        .filter_map(|addr| vm.memory().read_word(addr).ok())
+                        .map(|word| format!("0x{word:08x}"))
+                        .collect();
+                    variable(name, format!("@0x{:08x} [{}]", range.start, words.join(", ")))
+                }).collect();
+                let sp = vm.reg_get(29) & !3;
+                let stack = (0..MAX_STACK_WORDS)
+                    .map(|n| sp.wrapping_add(4 * n))
+                    .take_while(|addr| *addr <= STACK_POINTER)
+                    .filter_map(|addr| vm.memory().read_word(addr).ok().map(|word| variable(&format!("0x{addr:08x}"), format_value(word))));
+                variables.extend(stack);
+                variables
+            }
+            _ => return Err(format!("unknown variables reference: {reference}")),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// tells the client why execution stopped, calling convention
+    /// violations and uninitialized reads found on the way are sent
+    /// as console output
+    fn report<W: Write>(&mut self, output: &mut W, stop: Stop) -> std::io::Result<()> {
+        let printed = match self.debugger.as_ref() {
+            Some(debugger) => {
+                let printed = debugger.vm().console().output();
+                // output that was stepped back over is not taken back
+                let sent = self.output_sent.min(printed.len());
+                self.output_sent = printed.len();
+                String::from_utf8_lossy(&printed[sent..]).to_string()
+            }
+            None => String::new(),
+        };
+        if !printed.is_empty() {
+            self.event(output, "output", json!({ "category": "stdout", "output": printed }))?;
+        }
+        let warnings: Vec<String> = match self.debugger.as_mut() {
+            Some(debugger) => {
+                let violations = debugger.vm_mut().take_violations();
+                let reads = debugger.vm_mut().take_uninit_reads();
+                let dbg = &debugger.vm().runtime_dbg;
+                violations.iter().map(|v| format!("convention violation: {}", dbg.describe_violation(v)))
+                    .chain(reads.iter().map(|read| format!("warning: {}", dbg.describe_uninit_read(read))))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        for warning in warnings {
+            self.event(output, "output", json!({ "category": "console", "output": format!("{warning}\n") }))?;
+        }
+        let (reason, text) = match stop {
+            Stop::Paused | Stop::HistoryStart => ("step", None),
+            Stop::Breakpoint => ("breakpoint", None),
+            Stop::Watch(_) => ("data breakpoint", None),
+            Stop::Exception(e) => ("exception", Some(self.describe(&e))),
+            Stop::Halted => {
+                self.event(output, "exited", json!({ "exitCode": 0 }))?;
+                return self.event(output, "terminated", json!({}));
+            }
+        };
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event(output, "stopped", body)
+    }
+
+    fn describe(&self, exception: &MachineException) -> String {
+        match &self.debugger {
+            Some(debugger) => debugger.vm().describe_exception(exception),
+            None => exception.to_string(),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{frame_all, read_all};
+
+    /// sends the requests and returns every message sent back
+    fn session(requests: Vec<Value>) -> Vec<Value> {
+        let input = frame_all(requests.into_iter().enumerate().map(|(seq, mut r)| {
+            r["seq"] = json!(seq + 1);
+            r["type"] = json!("request");
+            r
+        }));
+        let mut output = Vec::new();
+        DapServer::new().serve(input.as_slice(), &mut output).unwrap();
+        read_all(&output)
+    }
+
+    fn write_source(name: &str, src: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mipstenite-dap-{}-{name}.s", std::process::id()));
+        std::fs::write(&path, src).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_breakpoint_and_variables() {
+        let path = write_source("breakpoint", ".text\nmain:   li $t0, 5\n        sw $t0, arr\n\nloop:   li $v0, 10\n        syscall\n.data\narr:    .word 1, 2\n");
+        let messages = session(vec![
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "setBreakpoints", "arguments": { "source": { "path": path }, "breakpoints": [{ "line": 4 }, { "line": 5 }] } }),
+            json!({ "command": "launch", "arguments": { "program": path } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": REGISTERS } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": MEMORY } }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        let find = |command: &str| messages.iter().find(|m| m["command"] == command).unwrap();
+        assert_eq!(find("initialize")["body"]["supportsStepBack"], true);
+        assert_eq!(find("setBreakpoints")["body"]["breakpoints"][0]["verified"], false);
+        assert_eq!(find("setBreakpoints")["body"]["breakpoints"][1]["verified"], false);
+        assert_eq!(find("launch")["success"], true);
+
+        let events: Vec<&Value> = messages.iter().filter(|m| m["type"] == "event").collect();
+        assert_eq!(events[0]["body"]["reason"], "breakpoint");
+        assert_eq!(events[1]["body"]["reason"], "step");
+        assert_eq!(events[2]["event"], "exited");
+        assert_eq!(events[3]["event"], "terminated");
+
+        let frame = &find("stackTrace")["body"]["stackFrames"][0];
+        assert_eq!(frame["name"], "main");
+        assert_eq!(frame["line"], 5);
+        assert_eq!(find("variables")["body"]["variables"][8], json!({ "name": "$t0", "value": "0x00000005 (5)", "variablesReference": 0 }));
+        let memory = messages.iter().filter(|m| m["command"] == "variables").nth(1).unwrap();
+        assert_eq!(memory["body"]["variables"][0]["value"], "@0x10010000 [0x00000005, 0x00000002]");
+    }
+
+    #[test]
+    fn test_stop_on_entry_and_errors() {
+        let path = write_source("entry", "main: li $t0, 1\n");
+        let bad = write_source("bad", "main: addd $t0, $t0, $t0\n");
+        let messages = session(vec![
+            json!({ "command": "launch", "arguments": { "program": bad } }),
+            json!({ "command": "launch", "arguments": { "program": path, "stopOnEntry": true } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "next" }),
+            json!({ "command": "stepBack" }),
+            json!({ "command": "disconnect" }),
+            json!({ "command": "threads" }),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&bad).unwrap();
+
+        assert!(messages[0]["body"]["output"].as_str().unwrap().contains("error[E0002]: invalid instruction: addd"));
+        assert_eq!(messages[1]["success"], false);
+        assert_eq!(messages[2]["success"], true);
+        assert_eq!(messages[4]["body"]["reason"], "entry");
+        assert_eq!(messages[6]["body"]["reason"], "step");
+        assert_eq!(messages[7]["command"], "stepBack");
+        // nothing is handled after disconnect
+        assert_eq!(messages.last().unwrap()["event"], "terminated");
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let path = write_source("calls", "main:   jal f\n        li $v0, 10\n        syscall\nf:      li $t0, 1\n        jr $ra\n");
+        let messages = session(vec![
+            json!({ "command": "setBreakpoints", "arguments": { "breakpoints": [{ "line": 4 }] } }),
+            json!({ "command": "launch", "arguments": { "program": path } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace" }),
+            json!({ "command": "stepOut" }),
+            json!({ "command": "stackTrace" }),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        let traces: Vec<Vec<(String, u64)>> = messages.iter().filter(|m| m["command"] == "stackTrace").map(|m| {
+            m["body"]["stackFrames"].as_array().unwrap().iter()
+                .map(|f| (f["name"].as_str().unwrap().to_string(), f["line"].as_u64().unwrap()))
+                .collect()
+        }).collect();
+        assert_eq!(traces[0], vec![("f".to_string(), 4), ("main".to_string(), 1)]);
+        assert_eq!(traces[1], vec![("main".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_console() {
+        let path = write_source("console", "main:   li $v0, 5\n        syscall\n        add $a0, $v0, $v0\n        li $v0, 1\n        syscall\n        li $v0, 10\n        syscall\n");
+        let messages = session(vec![
+            json!({ "command": "launch", "arguments": { "program": path, "input": "21\n" } }),
+            json!({ "command": "configurationDone" }),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        let output = messages.iter().find(|m| m["event"] == "output").unwrap();
+        assert_eq!(output["body"], json!({ "category": "stdout", "output": "42" }));
+    }
+}
@@ -4,9 +4,28 @@ use std::ops::Range;
 use serde::{Serialize, Deserialize};
 
 use crate::bytecode::{Bytecode, AsmInstruction};
+use crate::parser::ParsedProgram;
+use crate::registers::addr_to_register;
 
 
-#[derive(Debug, Eq, PartialEq)]
+/// where an instruction or data item was written, the text
+/// is the statement as written without labels or comments
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SourceSpan {
+    pub file: String,
+    pub line: u32,
+    pub column: usize,
+    pub text: String,
+}
+
+impl std::fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{} {}", self.file, self.line, self.column, self.text)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 #[derive(Serialize, Deserialize)]
 struct LineRange {
     range: Range<usize>,
@@ -28,14 +47,27 @@ impl PartialOrd for LineRange {
 /// - assembly instruction
 /// - current label
 /// - line number
+///
 /// So, the error message can look like:
+///
+/// ```text
 /// [ERROR] 13: li $t9, 123 in foo
 ///     VM failed to set register
+/// ```
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct CompileDebugInfo {
     debug_map: BTreeMap<LineRange, (AsmInstruction, Vec<Bytecode>)>,
+    span_map: BTreeMap<LineRange, SourceSpan>,
     label_map: HashMap<String, usize>,
+    // bytecode index each instruction starts at, in order
+    starts: Vec<usize>,
+}
+
+/// the entry of the instruction that contains pc, which is the
+/// last one starting at or before it
+fn lookup<V>(map: &BTreeMap<LineRange, V>, pc: usize) -> Option<(&LineRange, &V)> {
+    map.range(..=LineRange { range: pc..pc }).next_back().filter(|(key, _)| key.range.contains(&pc))
 }
 
 impl CompileDebugInfo {
@@ -43,10 +75,12 @@ impl CompileDebugInfo {
     pub fn new(asm_instructions: Vec<AsmInstruction>) -> CompileDebugInfo {
 
         let mut debug_map: BTreeMap<LineRange, (AsmInstruction, Vec<Bytecode>)> = BTreeMap::new();
+        let mut starts = Vec::with_capacity(asm_instructions.len());
         let mut index = 0;
 
         for i in asm_instructions {
             let bytecode = i.to_bytecode();
+            starts.push(index);
             let range: LineRange = LineRange { range: index..index + bytecode.len() };
             debug_map.insert(range, (i, bytecode.clone()));
             index += bytecode.len();
@@ -54,13 +88,76 @@ impl CompileDebugInfo {
 
         CompileDebugInfo { 
             debug_map,
+            span_map: BTreeMap::new(),
             label_map: HashMap::new(),
+            starts,
+        }
+    }
+
+    /// same as new but also records the source span of every
+    /// instruction and the bytecode index each text label points at
+    pub fn from_program(program: &ParsedProgram) -> CompileDebugInfo {
+        let mut debug_info = CompileDebugInfo::new(program.asm_instructions());
+
+        let mut offsets = Vec::with_capacity(program.instructions.len() + 1);
+        for (range, instruction) in debug_info.debug_map.keys().zip(program.instructions.iter()) {
+            offsets.push(range.range.start);
+            debug_info.span_map.insert(range.clone(), instruction.span.clone());
+        }
+        offsets.push(debug_info.debug_map.keys().last().map(|r| r.range.end).unwrap_or(0));
+
+        for (label, index) in &program.text_labels {
+            debug_info.label_map.insert(label.clone(), offsets[*index]);
         }
+
+        debug_info
     }
 
     pub fn get(&self, bytecode_number: usize) -> Option<(AsmInstruction, Vec<Bytecode>)> {
-        let lookup_key = self.debug_map.keys().find(|key| key.range.contains(&bytecode_number));
-        lookup_key.and_then(|key| self.debug_map.get(key).cloned())
+        lookup(&self.debug_map, bytecode_number).map(|(_, entry)| entry.clone())
+    }
+
+    /// source span of the instruction that generated the bytecode
+    pub fn span(&self, bytecode_number: usize) -> Option<&SourceSpan> {
+        lookup(&self.span_map, bytecode_number).map(|(_, span)| span)
+    }
+
+    /// bytecode index of the instruction a text label points at
+    pub fn label(&self, label: &str) -> Option<usize> {
+        self.label_map.get(label).copied()
+    }
+
+    /// bytecode index of the first instruction written on a source line
+    pub fn line(&self, line: u32) -> Option<usize> {
+        self.span_map.iter().find(|(_, span)| span.line == line).map(|(key, _)| key.range.start)
+    }
+
+    /// the text label closest before pc, the block of code pc is in
+    pub fn enclosing_label(&self, bytecode_number: usize) -> Option<&str> {
+        self.label_map.iter()
+            .filter(|(_, pc)| **pc <= bytecode_number)
+            .max_by_key(|(label, pc)| (**pc, std::cmp::Reverse(label.as_str())))
+            .map(|(label, _)| label.as_str())
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.debug_map.len()
+    }
+
+    /// position of the instruction that contains pc in the program
+    pub fn instruction_index(&self, bytecode_number: usize) -> Option<usize> {
+        let (key, _) = lookup(&self.debug_map, bytecode_number)?;
+        Some(self.starts.partition_point(|start| *start < key.range.start))
+    }
+
+    /// bytecode index the nth instruction of the program starts at
+    pub fn instruction_start(&self, index: usize) -> Option<usize> {
+        self.starts.get(index).copied()
+    }
+
+    /// bytecodes generated by the instruction that contains pc
+    pub fn instruction_range(&self, bytecode_number: usize) -> Option<Range<usize>> {
+        lookup(&self.debug_map, bytecode_number).map(|(key, _)| key.range.clone())
     }
 
 }
@@ -72,6 +169,157 @@ pub enum MachineException {
     Overflow,
     Bus,
     DivideByZero,
+    StackUnderflow,
+    InvalidRegister(u32),
+    InvalidSyscall(u32),
+    PcOutOfBounds(usize),
+    UninitializedRead(WatchTarget),
+    // a jump back to itself, nothing else can ever run and no
+    // interrupt can arrive
+    InfiniteLoop,
+    // console input or output failed, or the input was malformed
+    Io(String),
+}
+
+impl std::fmt::Display for MachineException {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MachineException::AddressError => write!(f, "address error"),
+            MachineException::Overflow => write!(f, "arithmetic overflow"),
+            MachineException::Bus => write!(f, "bus error"),
+            MachineException::DivideByZero => write!(f, "division by zero"),
+            MachineException::StackUnderflow => write!(f, "stack underflow"),
+            MachineException::InvalidRegister(reg) => write!(f, "invalid register: {reg}"),
+            MachineException::InvalidSyscall(code) => write!(f, "invalid syscall: {code}"),
+            MachineException::PcOutOfBounds(pc) => write!(f, "program counter out of bounds: {pc}"),
+            MachineException::UninitializedRead(target) => write!(f, "read of uninitialized {target}"),
+            MachineException::InfiniteLoop => write!(f, "infinite loop, the instruction jumps to itself"),
+            MachineException::Io(message) => write!(f, "I/O error: {message}"),
+        }
+    }
+}
+
+/// what a watchpoint watches, memory ranges are end exclusive
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum WatchTarget {
+    Register(u32),
+    Memory(Range<u32>),
+}
+
+impl WatchTarget {
+
+    pub fn overlaps(&self, other: &WatchTarget) -> bool {
+        match (self, other) {
+            (WatchTarget::Register(a), WatchTarget::Register(b)) => a == b,
+            (WatchTarget::Memory(a), WatchTarget::Memory(b)) => a.start < b.end && b.start < a.end,
+            _ => false,
+        }
+    }
+
+}
+
+impl std::fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchTarget::Register(reg) => match addr_to_register(*reg) {
+                Some(register) => write!(f, "{}", register.name),
+                None => write!(f, "${reg}"),
+            },
+            WatchTarget::Memory(range) => write!(f, "0x{:08x}..0x{:08x}", range.start, range.end),
+        }
+    }
+}
+
+/// change only triggers on writes that alter the value
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Change,
+}
+
+impl std::fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Change => write!(f, "change"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub kind: WatchKind,
+}
+
+/// a watchpoint triggered by the bytecode at pc, for reads
+/// old and new are both the value that was read
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct WatchHit {
+    pub id: usize,
+    pub pc: usize,
+    pub target: WatchTarget,
+    pub kind: WatchKind,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// a register or memory word read before anything was written to it
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct UninitRead {
+    pub pc: usize,
+    pub target: WatchTarget,
+}
+
+/// the backward jump taken most often, from the bytecode at end
+/// to the one at start
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotLoop {
+    pub start: usize,
+    pub end: usize,
+    pub iterations: u64,
+}
+
+/// a call entered by jal or jalr, left by `jr $ra`
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct CallFrame {
+    // bytecode index of the call and of the code called
+    pub call_site: usize,
+    pub entry: usize,
+    // $sp when the call was made
+    pub sp: u32,
+    // registers the callee must preserve and their values at the
+    // call, only recorded while calls are checked
+    pub saved: Vec<(u32, u32)>,
+}
+
+/// a break of the O32 calling convention, pc is the instruction
+/// that clobbered or read the register
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ConventionViolation {
+    // bytecode index of the function called
+    pub entry: usize,
+    pub register: u32,
+    pub pc: usize,
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum ViolationKind {
+    // a callee saved register differs on return
+    NotPreserved { expected: u32, actual: u32 },
+    // a caller saved register is read after the call before being set
+    ReadAfterCall,
 }
 
 #[derive(Debug, Clone)]
@@ -92,7 +340,15 @@ pub struct RuntimeDebugInfo {
     pub compile_debug_info: CompileDebugInfo,
     max_trace: usize,
     stack_trace: Vec<usize>,
-    exception: Option<MachineException>
+    exception: Option<MachineException>,
+    // innermost call last
+    call_stack: Vec<CallFrame>,
+}
+
+impl Default for RuntimeDebugInfo {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RuntimeDebugInfo {
@@ -103,6 +359,7 @@ impl RuntimeDebugInfo {
             max_trace: 20,
             stack_trace: Vec::new(),
             exception: None,
+            call_stack: Vec::new(),
         }
     }
 
@@ -114,6 +371,10 @@ impl RuntimeDebugInfo {
         self.exception = Some(exception);
     }
 
+    pub fn clear_exception(&mut self) {
+        self.exception = None;
+    }
+
     pub fn get_exception(&self) -> Option<MachineException> {
         self.exception.clone()
     }
@@ -125,26 +386,135 @@ impl RuntimeDebugInfo {
         self.stack_trace.push(line_number);
     }
 
+    pub fn pop_stack_trace(&mut self) {
+        self.stack_trace.pop();
+    }
+
+    /// indices of the most recently executed bytecodes, oldest first
+    pub fn stack_trace(&self) -> &[usize] {
+        &self.stack_trace
+    }
+
+    pub fn push_call(&mut self, frame: CallFrame) {
+        self.call_stack.push(frame);
+    }
+
+    /// leaves the innermost call, a return without a call is ignored
+    pub fn pop_call(&mut self) -> Option<CallFrame> {
+        self.call_stack.pop()
+    }
+
+    /// calls that have not returned, outermost first
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    /// name of the code a call entered, the label it jumped to
+    /// or, for a computed jump, the label enclosing it
+    pub fn frame_name(&self, entry: usize) -> &str {
+        self.compile_debug_info.enclosing_label(entry).unwrap_or("<program>")
+    }
+
+    /// the calls that led to pc, outermost first, e.g.
+    ///
+    /// ```text
+    /// backtrace: main → fib → fib
+    ///   #0 fib called at fib.s:12:9 jal fib, $sp = 0x7fffefe4
+    ///   #1 fib called at fib.s:4:9 jal fib, $sp = 0x7fffeff0
+    ///   #2 main
+    /// ```
+    pub fn backtrace(&self) -> String {
+        let names: Vec<&str> = std::iter::once(self.frame_name(0))
+            .chain(self.call_stack.iter().map(|frame| self.frame_name(frame.entry)))
+            .collect();
+        let mut lines = vec![format!("backtrace: {}", names.join(" → "))];
+        for (n, frame) in self.call_stack.iter().rev().enumerate() {
+            let call_site = match self.compile_debug_info.span(frame.call_site) {
+                Some(span) => span.to_string(),
+                None => format!("bytecode {}", frame.call_site),
+            };
+            lines.push(format!("  #{n} {} called at {call_site}, $sp = 0x{:08x}", self.frame_name(frame.entry), frame.sp));
+        }
+        lines.push(format!("  #{} {}", self.call_stack.len(), names[0]));
+        lines.join("\n")
+    }
+
+    /// explains a violation, citing the instruction responsible
+    pub fn describe_violation(&self, violation: &ConventionViolation) -> String {
+        let function = self.frame_name(violation.entry);
+        let register = addr_to_register(violation.register).map(|r| r.name).unwrap_or_default();
+        let message = match violation.kind {
+            ViolationKind::NotPreserved { expected, actual } => {
+                format!("{function} does not preserve {register}, 0x{expected:08x} became 0x{actual:08x}")
+            }
+            ViolationKind::ReadAfterCall => format!("{register} is read after a call to {function}, which need not preserve it"),
+        };
+        match self.compile_debug_info.span(violation.pc) {
+            Some(span) => format!("{message}\n  --> {span}"),
+            None => format!("{message}\n  --> bytecode {}", violation.pc),
+        }
+    }
+
+    /// names the code a loop runs and the lines it spans, e.g.
+    /// `loop (prog.s:3-5), 41 iterations`
+    pub fn describe_loop(&self, hot: &HotLoop) -> String {
+        let name = self.frame_name(hot.start);
+        let lines = match (self.compile_debug_info.span(hot.start), self.compile_debug_info.span(hot.end)) {
+            (Some(start), Some(end)) => format!("{}:{}-{}", start.file, start.line, end.line),
+            _ => format!("bytecode {}-{}", hot.start, hot.end),
+        };
+        let plural = if hot.iterations == 1 { "" } else { "s" };
+        format!("{name} ({lines}), {} iteration{plural}", hot.iterations)
+    }
+
+    pub fn describe_uninit_read(&self, read: &UninitRead) -> String {
+        match self.compile_debug_info.span(read.pc) {
+            Some(span) => format!("read of uninitialized {}\n  --> {span}", read.target),
+            None => format!("read of uninitialized {}\n  --> bytecode {}", read.target, read.pc),
+        }
+    }
+
+    /// error message for an exception raised while executing
+    /// the bytecode at pc, citing the source it came from and,
+    /// inside a call, how it was reached
+    pub fn describe_exception(&self, pc: usize, exception: &MachineException) -> String {
+        let message = match self.compile_debug_info.span(pc) {
+            Some(span) => format!("{exception}\n  --> {span}"),
+            None => format!("{exception}\n  --> bytecode {pc}"),
+        };
+        match self.call_stack.is_empty() {
+            true => message,
+            false => format!("{message}\n{}", self.backtrace()),
+        }
+    }
+
     pub fn print_debug_info(&self) {
-        let mut debug_stack_trace: Vec<(AsmInstruction, Vec<Bytecode>)> = Vec::new();
+        let mut debug_stack_trace: Vec<(usize, AsmInstruction, Vec<Bytecode>)> = Vec::new();
     
         for i in self.stack_trace.clone() {
-            let debug_info = self.compile_debug_info.get(i).unwrap();
-            debug_stack_trace.push(debug_info);
+            if let Some((asm_instruction, bytecode)) = self.compile_debug_info.get(i) {
+                debug_stack_trace.push((i, asm_instruction, bytecode));
+            }
         }
 
         debug_stack_trace.reverse();
 
         println!("[StackTrace]");
         // loop through debug_info only print AsmInstruction that is not similar to the previous one
+        let mut prev_span: Option<&SourceSpan> = None;
         let mut prev_asm_instruction: Option<AsmInstruction> = None;
-        for (asm_instruction, bytecode) in debug_stack_trace {
-            if prev_asm_instruction.is_none() || prev_asm_instruction.unwrap() != asm_instruction {
-                println!("{:?}", asm_instruction);
+        for (pc, asm_instruction, bytecode) in debug_stack_trace {
+            let span = self.compile_debug_info.span(pc);
+            if prev_asm_instruction.as_ref() != Some(&asm_instruction) || prev_span != span {
+                match span {
+                    Some(span) => println!("{span}"),
+                    None => println!("{:?}", asm_instruction),
+                }
                 for i in bytecode {
                     println!("\t{:?}", i);
                 }
             }
+            prev_span = span;
             prev_asm_instruction = Some(asm_instruction);
         }
     }
@@ -179,5 +549,11 @@ mod tests {
         assert_eq!(debug_info.get(7), Some((asm_instructions[2].clone(), asm_instructions[2].to_bytecode())));
 
         assert_eq!(debug_info.get(8), None);
+
+        assert_eq!(debug_info.instruction_range(3), Some(2..4));
+        assert_eq!(debug_info.instruction_index(6), Some(2));
+        assert_eq!(debug_info.instruction_index(8), None);
+        assert_eq!(debug_info.instruction_start(1), Some(2));
+        assert_eq!(debug_info.instruction_start(3), None);
     }
 }
@@ -0,0 +1,635 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::debug_table::{MachineException, MachineState, WatchHit, WatchKind, WatchTarget, Watchpoint};
+use crate::expr::{parse_expr, SymbolTable};
+use crate::memview::{self, ViewFormat};
+use crate::registers::{addr_to_register, register_to_addr};
+use crate::virtual_machine::VirtualMachine;
+
+const HELP: &str = "\
+commands:
+  s, step [n]          step n source instructions
+  n, next [n]          step n source instructions, running calls
+                       to completion
+  finish               run until the current call returns
+  si, stepi [n]        step n bytecodes
+  c, continue          run until a breakpoint or the program stops
+  sb, step-back [n]    undo n source instructions
+  rsi, reverse-stepi [n]
+                       undo n bytecodes
+  rc, reverse-continue run backwards to a breakpoint, or to where a
+                       write or change watchpoint last triggered
+  b, break <loc>       break at a label or line number
+  d, delete <loc>      remove a breakpoint
+  breakpoints          list breakpoints
+  w, watch <t> [kind]  watch $reg, a data label, *addr or *start..end
+                       on read, write (default) or change
+  unwatch <id>         remove a watchpoint
+  watchpoints          list watchpoints
+  p, print [$reg]      print a register, or all registers
+  x <addr> [n]         print n words of memory starting at addr
+  v, view[/fmt] [loc]  show a data label, *addr or *start..end, or the
+                       whole data section, as auto, hex, signed,
+                       unsigned, float or string
+  set <$reg|*addr> <v> set a register or a word of memory, which
+                       clears the history step-back goes through
+  bitmap <file>        save the bitmap display as a PNG or PPM image
+  snapshot <file>      save the machine to resume it later with --resume
+  l, list              show the current source line
+  bt, backtrace        show the calls that led to the current instruction
+  trace [n]            show the last n executed instructions
+  q, quit              stop debugging";
+
+/// why execution stopped, shown after every command that runs code
+pub enum Stop {
+    Paused,
+    Breakpoint,
+    Halted,
+    Exception(MachineException),
+    Watch(Vec<WatchHit>),
+    // nothing more is recorded to step back through
+    HistoryStart,
+}
+
+/// interactive debugger driving a loaded virtual machine, addresses
+/// and values may be written as constant expressions over the
+/// program's symbols, e.g. `x arr+4 2`
+pub struct Debugger {
+    vm: VirtualMachine,
+    symbols: SymbolTable,
+    // bytecode indices execution stops at
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+
+    pub fn new(vm: VirtualMachine, symbols: SymbolTable) -> Debugger {
+        Debugger {
+            vm,
+            symbols,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VirtualMachine {
+        &mut self.vm
+    }
+
+    /// stops execution when pc reaches the bytecode
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// reads commands until `quit` or the end of input
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> std::io::Result<()> {
+        writeln!(output, "{}", self.location())?;
+        write!(output, "(mdb) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if matches!(line.trim(), "q" | "quit") {
+                break;
+            }
+            match self.command(&line) {
+                Ok(out) if out.is_empty() => {}
+                Ok(out) => writeln!(output, "{out}")?,
+                Err(e) => writeln!(output, "error: {e}")?,
+            }
+            write!(output, "(mdb) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// runs a single command and returns what it prints
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(String::new()) };
+        let args: Vec<&str> = words.collect();
+
+        match command {
+            "s" | "step" => {
+                let stop = self.repeat(&args, Debugger::step_instruction)?;
+                Ok(self.report(stop))
+            }
+            "n" | "next" => {
+                let stop = self.repeat(&args, Debugger::step_over)?;
+                Ok(self.report(stop))
+            }
+            "finish" => {
+                let stop = self.step_out();
+                Ok(self.report(stop))
+            }
+            "si" | "stepi" => {
+                let stop = self.repeat(&args, Debugger::step_bytecode)?;
+                Ok(self.report(stop))
+            }
+            "c" | "continue" => {
+                let stop = self.resume();
+                Ok(self.report(stop))
+            }
+            "sb" | "step-back" => {
+                let stop = self.repeat(&args, Debugger::step_back_instruction)?;
+                Ok(self.report(stop))
+            }
+            "rsi" | "reverse-stepi" => {
+                let stop = self.repeat(&args, Debugger::step_back_bytecode)?;
+                Ok(self.report(stop))
+            }
+            "rc" | "reverse-continue" => {
+                let stop = self.reverse();
+                Ok(self.report(stop))
+            }
+            "b" | "break" => {
+                let pc = self.resolve_location(args.first().ok_or("expected a label or line number")?)?;
+                self.breakpoints.insert(pc);
+                Ok(format!("breakpoint at {}", self.describe_pc(pc)))
+            }
+            "d" | "delete" => {
+                let pc = self.resolve_location(args.first().ok_or("expected a label or line number")?)?;
+                match self.breakpoints.remove(&pc) {
+                    true => Ok(format!("deleted breakpoint at {}", self.describe_pc(pc))),
+                    false => Err(format!("no breakpoint at {}", self.describe_pc(pc))),
+                }
+            }
+            "breakpoints" => Ok(self.breakpoints.iter().map(|pc| self.describe_pc(*pc)).collect::<Vec<_>>().join("\n")),
+            "w" | "watch" => {
+                let target = self.resolve_watch_target(args.first().ok_or("expected a register, label or address")?)?;
+                let kind = match args.get(1).copied() {
+                    None | Some("w" | "write") => WatchKind::Write,
+                    Some("r" | "read") => WatchKind::Read,
+                    Some("c" | "change") => WatchKind::Change,
+                    Some(kind) => return Err(format!("unknown watch kind: {kind}, expected read, write or change")),
+                };
+                let id = self.vm.add_watchpoint(Watchpoint { target: target.clone(), kind });
+                Ok(format!("watchpoint {id}: {target} on {kind}"))
+            }
+            "unwatch" => {
+                let id = args.first().ok_or("expected a watchpoint id")?.parse::<usize>().map_err(|e| e.to_string())?;
+                match self.vm.remove_watchpoint(id) {
+                    Some(_) => Ok(format!("deleted watchpoint {id}")),
+                    None => Err(format!("no watchpoint {id}")),
+                }
+            }
+            "watchpoints" => Ok(self.vm.watchpoints().map(|(id, w)| format!("watchpoint {id}: {} on {}", w.target, w.kind)).collect::<Vec<_>>().join("\n")),
+            "p" | "print" => match args.first() {
+                Some(reg) => {
+                    let addr = register_to_addr(reg.to_string()).ok_or(format!("{reg} is not a valid register"))?;
+                    Ok(self.format_register(addr))
+                }
+                None => Ok((0..34).map(|addr| self.format_register(addr)).collect::<Vec<_>>().join("\n")),
+            },
+            "x" => {
+                let addr = self.evaluate(args.first().ok_or("expected an address")?)?;
+                let count = args.get(1).map(|n| self.evaluate(n)).transpose()?.unwrap_or(1);
+                (0..count)
+                    .map(|n| {
+                        let addr = addr.wrapping_add(4 * n);
+                        let value = self.vm.memory().read_word(addr).map_err(|e| format!("{e} at 0x{addr:08x}"))?;
+                        Ok(format!("0x{addr:08x}: {} (0x{value:08x})", value as i32))
+                    })
+                    .collect::<Result<Vec<_>, String>>()
+                    .map(|lines| lines.join("\n"))
+            }
+            "set" => {
+                let [target, value] = args[..] else { return Err("expected set <$reg|*addr> <value>".to_string()) };
+                let value = self.evaluate(value)?;
+                // steps recorded before the write would restore a mix of old and new state
+                self.vm.clear_history();
+                if let Some(addr) = target.strip_prefix('*') {
+                    let addr = self.evaluate(addr)?;
+                    self.vm.memory_mut().write_word(addr, value).map_err(|e| format!("{e} at 0x{addr:08x}"))?;
+                    Ok(format!("0x{addr:08x}: {}", value as i32))
+                } else {
+                    let reg = register_to_addr(target.to_string()).ok_or(format!("{target} is not a valid register"))?;
+                    self.vm.reg_set(reg, value);
+                    Ok(self.format_register(reg))
+                }
+            }
+            view if matches!(view.split_once('/').map_or(view, |(name, _)| name), "v" | "view") => {
+                let format = view.split_once('/').map(|(_, format)| format.parse::<ViewFormat>()).transpose()?.unwrap_or(ViewFormat::Auto);
+                let range = memview::resolve_range(self.vm.memory(), &self.symbols, &args.join(" "))?;
+                memview::view(self.vm.memory(), range, format)
+            }
+            "bitmap" => {
+                let path = args.first().ok_or("usage: bitmap <file>")?;
+                self.vm.save_bitmap(std::path::Path::new(path)).map_err(|e| format!("unable to write {path}: {e}"))?;
+                Ok(format!("saved {path}"))
+            }
+            "snapshot" => {
+                let path = args.first().ok_or("usage: snapshot <file>")?;
+                self.vm.save_snapshot(std::path::Path::new(path)).map_err(|e| format!("unable to write {path}: {e}"))?;
+                Ok(format!("saved {path}"))
+            }
+            "l" | "list" => Ok(self.location()),
+            "bt" | "backtrace" => Ok(self.vm.runtime_dbg.backtrace()),
+            "trace" => {
+                let count = args.first().map(|n| n.parse::<usize>().map_err(|e| e.to_string())).transpose()?.unwrap_or(10);
+                Ok(self.trace(count))
+            }
+            "h" | "help" => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command: {command}, try `help`")),
+        }
+    }
+
+    fn evaluate(&self, src: &str) -> Result<u32, String> {
+        parse_expr(src).and_then(|e| e.eval(&self.symbols)).map(|v| v as u32)
+    }
+
+    /// a label or a source line number to the bytecode index it starts at
+    fn resolve_location(&self, location: &str) -> Result<usize, String> {
+        let debug_info = &self.vm.runtime_dbg.compile_debug_info;
+        match location.parse::<u32>() {
+            Ok(line) => debug_info.line(line).ok_or(format!("no instruction on line {line}")),
+            Err(_) => debug_info.label(location).ok_or(format!("no label named {location}")),
+        }
+    }
+
+    /// `$reg`, `*addr`, `*start..end` or the name of a data label
+    fn resolve_watch_target(&self, target: &str) -> Result<WatchTarget, String> {
+        if target.starts_with('$') {
+            let reg = register_to_addr(target.to_string()).ok_or(format!("{target} is not a valid register"))?;
+            return Ok(WatchTarget::Register(reg));
+        }
+        if let Some(addr) = target.strip_prefix('*') {
+            let range = match addr.split_once("..") {
+                Some((start, end)) => self.evaluate(start)?..self.evaluate(end)?,
+                None => self.evaluate(addr).map(|start| start..start.wrapping_add(4))?,
+            };
+            if range.is_empty() {
+                return Err(format!("empty address range: {addr}"));
+            }
+            return Ok(WatchTarget::Memory(range));
+        }
+        self.vm.memory().label(target).map(WatchTarget::Memory).ok_or(format!("no data label named {target}"))
+    }
+
+    fn describe_pc(&self, pc: usize) -> String {
+        match self.vm.runtime_dbg.compile_debug_info.span(pc) {
+            Some(span) => span.to_string(),
+            None => format!("bytecode {pc}"),
+        }
+    }
+
+    fn format_register(&self, addr: u32) -> String {
+        let value = self.vm.reg_get(addr);
+        let name = addr_to_register(addr).map(|r| r.name).unwrap_or_default();
+        format!("{name} = {} (0x{value:08x})", value as i32)
+    }
+
+    /// the instruction about to be executed
+    fn location(&self) -> String {
+        let pc = self.vm.pc();
+        let bytecode = self.vm.runtime_dbg.compile_debug_info.get(pc)
+            .and_then(|(_, bytecode)| {
+                let start = self.vm.runtime_dbg.compile_debug_info.instruction_range(pc)?.start;
+                bytecode.get(pc - start).cloned()
+            });
+        match bytecode {
+            Some(bytecode) => format!("=> {} [{pc}: {bytecode:?}]", self.describe_pc(pc)),
+            None => format!("=> bytecode {pc}"),
+        }
+    }
+
+    /// source of the last executed instructions, oldest first
+    fn trace(&self, count: usize) -> String {
+        let debug_info = &self.vm.runtime_dbg.compile_debug_info;
+        let mut lines: Vec<String> = Vec::new();
+        let mut previous = None;
+        for pc in self.vm.runtime_dbg.stack_trace() {
+            // bytecodes of the same instruction are shown once
+            let range = debug_info.instruction_range(*pc);
+            if range.is_some() && range == previous {
+                continue;
+            }
+            lines.push(self.describe_pc(*pc));
+            previous = range;
+        }
+        let skip = lines.len().saturating_sub(count);
+        lines.split_off(skip).join("\n")
+    }
+
+    /// why execution stopped, after any calling convention
+    /// violations or uninitialized reads found on the way
+    fn report(&mut self, stop: Stop) -> String {
+        let mut lines: Vec<String> = self.vm.take_violations().iter()
+            .map(|v| format!("convention violation: {}", self.vm.runtime_dbg.describe_violation(v)))
+            .collect();
+        lines.extend(self.vm.take_uninit_reads().iter()
+            .map(|read| format!("warning: {}", self.vm.runtime_dbg.describe_uninit_read(read))));
+        lines.push(self.describe_stop(stop));
+        lines.join("\n")
+    }
+
+    fn describe_stop(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Paused => self.location(),
+            Stop::Breakpoint => format!("breakpoint hit\n{}", self.location()),
+            Stop::Halted => "program halted".to_string(),
+            Stop::HistoryStart => format!("reached the start of the recorded history\n{}", self.location()),
+            Stop::Exception(e) => format!("exception: {}", self.vm.describe_exception(&e)),
+            Stop::Watch(hits) => {
+                let mut out: Vec<String> = hits.iter().map(|hit| {
+                    let values = match hit.kind {
+                        WatchKind::Read => format!("read {}", hit.new as i32),
+                        _ => format!("{} -> {}", hit.old as i32, hit.new as i32),
+                    };
+                    format!("watchpoint {} hit: {} {values}\n  at {}", hit.id, hit.target, self.describe_pc(hit.pc))
+                }).collect();
+                out.push(self.location());
+                out.join("\n")
+            }
+        }
+    }
+
+    fn repeat(&mut self, args: &[&str], step: fn(&mut Debugger) -> Stop) -> Result<Stop, String> {
+        let count = args.first().map(|n| n.parse::<usize>().map_err(|e| e.to_string())).transpose()?.unwrap_or(1);
+        let mut stop = Stop::Paused;
+        for _ in 0..count {
+            stop = step(self);
+            if !matches!(stop, Stop::Paused) {
+                break;
+            }
+        }
+        Ok(stop)
+    }
+
+    pub fn step_bytecode(&mut self) -> Stop {
+        let stop = match self.vm.execute() {
+            Ok(MachineState::Running) => Stop::Paused,
+            Ok(MachineState::Halted) => Stop::Halted,
+            Err(e) => Stop::Exception(e),
+        };
+        let hits = self.vm.take_watch_hits();
+        match stop {
+            Stop::Paused if !hits.is_empty() => Stop::Watch(hits),
+            stop => stop,
+        }
+    }
+
+    /// executes the remaining bytecodes of the current instruction,
+    /// a jump is always the last bytecode of its instruction, so
+    /// watchpoints are only reported once the instruction is done
+    pub fn step_instruction(&mut self) -> Stop {
+        let pc = self.vm.pc();
+        let end = self.vm.runtime_dbg.compile_debug_info.instruction_range(pc).map_or(pc + 1, |r| r.end);
+        let mut hits = Vec::new();
+        for _ in pc..end {
+            match self.step_bytecode() {
+                Stop::Paused => {}
+                Stop::Watch(more) => hits.extend(more),
+                stop => return stop,
+            }
+        }
+        match hits.is_empty() {
+            true => Stop::Paused,
+            false => Stop::Watch(hits),
+        }
+    }
+
+    pub fn step_back_bytecode(&mut self) -> Stop {
+        if !self.vm.step_back() {
+            return Stop::HistoryStart;
+        }
+        let hits = self.vm.take_watch_hits();
+        match hits.is_empty() {
+            true => Stop::Paused,
+            false => Stop::Watch(hits),
+        }
+    }
+
+    /// undoes bytecodes until pc is at the start of an instruction
+    pub fn step_back_instruction(&mut self) -> Stop {
+        let mut hits = Vec::new();
+        loop {
+            match self.step_back_bytecode() {
+                Stop::Watch(more) => hits.extend(more),
+                Stop::HistoryStart if hits.is_empty() => return Stop::HistoryStart,
+                _ => {}
+            }
+            let pc = self.vm.pc();
+            let at_start = self.vm.runtime_dbg.compile_debug_info.instruction_range(pc).is_none_or(|r| r.start == pc);
+            if at_start || self.vm.history_len() == 0 {
+                break;
+            }
+        }
+        match hits.is_empty() {
+            true => Stop::Paused,
+            false => Stop::Watch(hits),
+        }
+    }
+
+    pub fn reverse(&mut self) -> Stop {
+        loop {
+            let stop = self.step_back_instruction();
+            if !matches!(stop, Stop::Paused) {
+                return stop;
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return Stop::Breakpoint;
+            }
+        }
+    }
+
+    /// steps an instruction, running a call it makes to completion
+    pub fn step_over(&mut self) -> Stop {
+        let depth = self.vm.runtime_dbg.call_stack().len();
+        match self.step_instruction() {
+            Stop::Paused if self.vm.runtime_dbg.call_stack().len() > depth => self.run_to_depth(depth),
+            stop => stop,
+        }
+    }
+
+    /// runs until the current call returns, or like continue
+    /// outside of a call
+    pub fn step_out(&mut self) -> Stop {
+        match self.vm.runtime_dbg.call_stack().len() {
+            0 => self.resume(),
+            depth => self.run_to_depth(depth - 1),
+        }
+    }
+
+    /// runs until no more than depth calls are active
+    fn run_to_depth(&mut self, depth: usize) -> Stop {
+        loop {
+            let stop = self.step_instruction();
+            if !matches!(stop, Stop::Paused) || self.vm.runtime_dbg.call_stack().len() <= depth {
+                return stop;
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return Stop::Breakpoint;
+            }
+        }
+    }
+
+    pub fn resume(&mut self) -> Stop {
+        loop {
+            let stop = self.step_instruction();
+            if !matches!(stop, Stop::Paused) {
+                return stop;
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return Stop::Breakpoint;
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_source;
+
+    fn debugger(src: &str) -> Debugger {
+        let program = parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        Debugger::new(vm, program.symbols)
+    }
+
+    const SRC: &str = "\
+.text
+main:   li $t0, 1
+loop:   add $t0, $t0, $t0
+        j loop
+.data
+arr:    .word 7, -2
+";
+
+    #[test]
+    fn test_step_and_print() {
+        let mut dbg = debugger(SRC);
+        assert!(dbg.command("list").unwrap().starts_with("=> prog.s:2:9 li $t0, 1 [0: PUSH"));
+        assert!(dbg.command("si").unwrap().contains("[1: SETO"));
+        dbg.command("step").unwrap();
+        assert_eq!(dbg.command("p $t0").unwrap(), "$t0 = 1 (0x00000001)");
+        dbg.command("step 2").unwrap();
+        assert_eq!(dbg.command("print $8").unwrap(), "$t0 = 2 (0x00000002)");
+
+        assert_eq!(dbg.command("set $t0 0x10").unwrap(), "$t0 = 16 (0x00000010)");
+        assert_eq!(dbg.command("x arr 2").unwrap(), "0x10010000: 7 (0x00000007)\n0x10010004: -2 (0xfffffffe)");
+        dbg.command("set *arr+4 5").unwrap();
+        assert_eq!(dbg.vm().memory().read_word(0x1001_0004).unwrap(), 5);
+        assert_eq!(dbg.command("view arr").unwrap(), "arr:\n0x10010000  .word    7, 5");
+        assert_eq!(dbg.command("v/x *arr+4").unwrap(), "0x10010004  .word    0x00000005");
+        assert!(dbg.command("view/bits arr").is_err());
+        assert!(dbg.command("x arr+1").is_err());
+        assert!(dbg.command("frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_breakpoints_and_trace() {
+        let mut dbg = debugger(SRC);
+        assert_eq!(dbg.command("break 4").unwrap(), "breakpoint at prog.s:4:9 j loop");
+        assert!(dbg.command("b nowhere").is_err());
+        assert!(dbg.command("c").unwrap().starts_with("breakpoint hit\n=> prog.s:4:9 j loop"));
+        assert_eq!(dbg.command("p $t0").unwrap(), "$t0 = 2 (0x00000002)");
+        assert_eq!(dbg.command("trace 2").unwrap(), "prog.s:2:9 li $t0, 1\nprog.s:3:9 add $t0, $t0, $t0");
+
+        dbg.command("delete 4").unwrap();
+        assert!(dbg.command("continue").unwrap().starts_with("exception: arithmetic overflow"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut dbg = debugger("\
+.text
+main:   li $s0, 5
+        sw $s0, arr+4
+        lw $t0, arr
+        li $s0, 5
+        li $v0, 10
+        syscall
+.data
+arr:    .word 7, -2
+");
+        assert_eq!(dbg.command("watch $s0 change").unwrap(), "watchpoint 1: $s0 on change");
+        assert_eq!(dbg.command("watch arr").unwrap(), "watchpoint 2: 0x10010000..0x10010008 on write");
+        assert_eq!(dbg.command("w *arr..arr+4 read").unwrap(), "watchpoint 3: 0x10010000..0x10010004 on read");
+        assert!(dbg.command("watch nowhere").is_err());
+        assert!(dbg.command("watch $s0 sometimes").is_err());
+
+        assert!(dbg.command("c").unwrap().starts_with("watchpoint 1 hit: $s0 0 -> 5\n  at prog.s:2:9 li $s0, 5\n=> prog.s:3:9"));
+        assert!(dbg.command("c").unwrap().starts_with("watchpoint 2 hit: 0x10010004..0x10010008 -2 -> 5\n  at prog.s:3:9 sw $s0, arr+4"));
+        assert!(dbg.command("c").unwrap().starts_with("watchpoint 3 hit: 0x10010000..0x10010004 read 7"));
+        assert_eq!(dbg.command("unwatch 3").unwrap(), "deleted watchpoint 3");
+        assert_eq!(dbg.command("watchpoints").unwrap().lines().count(), 2);
+        // setting $s0 to the value it already has is not a change
+        assert_eq!(dbg.command("c").unwrap(), "program halted");
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut dbg = debugger(SRC);
+        dbg.vm.set_history_budget(1000);
+        assert!(dbg.command("rc").unwrap().starts_with("reached the start of the recorded history\n=> prog.s:2:9"));
+
+        dbg.command("step 4").unwrap();
+        assert_eq!(dbg.command("p $t0").unwrap(), "$t0 = 4 (0x00000004)");
+        assert!(dbg.command("sb").unwrap().starts_with("=> prog.s:3:9 add"));
+        assert_eq!(dbg.command("p $t0").unwrap(), "$t0 = 2 (0x00000002)");
+        assert!(dbg.command("rsi").unwrap().starts_with("=> prog.s:4:9 j loop [6: JUMP"));
+
+        // run until $t0 overflows, then rewind to its last change
+        dbg.command("c").unwrap();
+        dbg.command("watch $t0 change").unwrap();
+        let out = dbg.command("rc").unwrap();
+        assert!(out.starts_with("watchpoint 1 hit: $t0 536870912 -> 1073741824\n  at prog.s:3:9 add"), "{out}");
+        assert_eq!(dbg.command("p $t0").unwrap(), "$t0 = 536870912 (0x20000000)");
+
+        dbg.command("unwatch 1").unwrap();
+        dbg.command("break 4").unwrap();
+        assert!(dbg.command("reverse-continue").unwrap().starts_with("breakpoint hit\n=> prog.s:4:9 j loop"));
+        assert_eq!(dbg.command("p $t0").unwrap(), "$t0 = 536870912 (0x20000000)");
+
+        // a value set by hand cannot be stepped back through
+        dbg.command("set $t0 3").unwrap();
+        assert!(dbg.command("sb").unwrap().starts_with("reached the start of the recorded history"));
+        assert_eq!(dbg.command("p $t0").unwrap(), "$t0 = 3 (0x00000003)");
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut dbg = debugger(SRC);
+        let mut output = Vec::new();
+        dbg.run("step\np $t0\nquit\nstep\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("(mdb) $t0 = 1"));
+        assert_eq!(dbg.vm().pc(), 2);
+    }
+
+    #[test]
+    fn test_calls() {
+        let mut dbg = debugger("\
+main:   jal twice
+        jal twice
+        li $v0, 10
+        syscall
+twice:  add $t0, $t0, $t0
+        jr $ra
+");
+        dbg.command("set $t0 1").unwrap();
+        assert!(dbg.command("next").unwrap().starts_with("=> prog.s:2:9 jal twice"));
+        assert_eq!(dbg.vm().reg_get(8), 2);
+
+        assert!(dbg.command("step").unwrap().starts_with("=> prog.s:5:9 add"));
+        assert_eq!(dbg.command("bt").unwrap(), "backtrace: main → twice
+  #0 twice called at prog.s:2:9 jal twice, $sp = 0x7fffeffc
+  #1 main");
+        assert!(dbg.command("finish").unwrap().starts_with("=> prog.s:3:9 li $v0, 10"));
+        assert_eq!(dbg.vm().reg_get(8), 4);
+        assert_eq!(dbg.command("bt").unwrap(), "backtrace: main
+  #0 main");
+        assert_eq!(dbg.command("finish").unwrap(), "program halted");
+    }
+}
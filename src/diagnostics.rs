@@ -0,0 +1,297 @@
+use std::collections::HashSet;
+
+use crate::parser::{AsmWarning, ErrorCode, ParserVerboseError, WarningKind, DIRECTIVES, INSTRUCTIONS};
+use crate::registers::addr_to_register;
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn color(&self) -> &'static str {
+        match self {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        }
+    }
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// a message pointing at a stretch of one source line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: String,
+    pub msg: String,
+    pub line: u32,
+    pub column: usize,
+    pub len: usize,
+    pub help: Vec<String>,
+}
+
+impl From<&ParserVerboseError> for Diagnostic {
+    fn from(err: &ParserVerboseError) -> Self {
+        let input = err.input.lines().next().unwrap_or_default();
+        Diagnostic {
+            severity: Severity::Error,
+            code: err.code.to_string(),
+            msg: err.msg.clone(),
+            line: err.line,
+            column: err.column,
+            len: input.chars().count().max(1),
+            help: suggestions(err),
+        }
+    }
+}
+
+impl From<&AsmWarning> for Diagnostic {
+    fn from(warning: &AsmWarning) -> Self {
+        let input = warning.input.lines().next().unwrap_or_default();
+        Diagnostic {
+            severity: Severity::Warning,
+            code: warning.kind.to_string(),
+            msg: warning.msg.clone(),
+            line: warning.line,
+            column: warning.column,
+            len: input.chars().count().max(1),
+            help: Vec::new(),
+        }
+    }
+}
+
+/// which warnings are reported and which are promoted to errors,
+/// a warning that is both allowed and denied is denied
+#[derive(Debug, Clone, Default)]
+pub struct WarningLevels {
+    allowed: HashSet<WarningKind>,
+    denied: HashSet<WarningKind>,
+}
+
+impl WarningLevels {
+
+    pub fn new(allowed: &[WarningKind], denied: &[WarningKind]) -> WarningLevels {
+        WarningLevels {
+            allowed: allowed.iter().copied().collect(),
+            denied: denied.iter().copied().collect(),
+        }
+    }
+
+    /// severity the warning is reported with, None if it is allowed
+    pub fn severity(&self, kind: WarningKind) -> Option<Severity> {
+        if self.denied.contains(&kind) {
+            Some(Severity::Error)
+        } else if self.allowed.contains(&kind) {
+            None
+        } else {
+            Some(Severity::Warning)
+        }
+    }
+
+    /// diagnostics for the warnings that are not allowed
+    pub fn apply(&self, warnings: &[AsmWarning]) -> Vec<Diagnostic> {
+        warnings.iter().filter_map(|w| {
+            let severity = self.severity(w.kind)?;
+            Some(Diagnostic { severity, ..w.into() })
+        }).collect()
+    }
+
+}
+
+/// levenshtein distance between two short strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + (ca != *cb) as usize).min(row[j] + 1).min(above + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// the closest candidate within a third of the word's length,
+/// ties go to the candidate sharing the longest prefix
+pub fn closest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let threshold = (word.chars().count() / 3).max(1);
+    let common_prefix = |c: &str| word.chars().zip(c.chars()).take_while(|(a, b)| a == b).count();
+    candidates.into_iter()
+        .filter(|c| *c != word)
+        .map(|c| (edit_distance(word, c), c))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by(|(da, a), (db, b)| da.cmp(db).then(common_prefix(b).cmp(&common_prefix(a))))
+        .map(|(_, c)| c)
+}
+
+pub fn register_names() -> Vec<String> {
+    (0..34).filter_map(addr_to_register).map(|r| r.name).collect()
+}
+
+fn usage(mnemonic: &str) -> Option<&'static str> {
+    INSTRUCTIONS.iter().find(|(name, _)| *name == mnemonic).map(|(_, usage)| *usage)
+}
+
+fn suggestions(err: &ParserVerboseError) -> Vec<String> {
+    let word = err.input.split_whitespace().next().unwrap_or_default();
+    let suggestion = match err.code {
+        ErrorCode::InvalidRegister => {
+            let names = register_names();
+            closest(word, names.iter().map(|n| n.as_str())).map(|s| s.to_string())
+        }
+        ErrorCode::UnknownInstruction => closest(word, INSTRUCTIONS.iter().map(|(name, _)| *name)).map(|s| s.to_string()),
+        ErrorCode::UnknownDirective => closest(word, DIRECTIVES.iter().copied()).map(|s| s.to_string()),
+        ErrorCode::OperandCount => return usage(word).map(|u| format!("usage: `{u}`")).into_iter().collect(),
+        ErrorCode::UndefinedSymbol => err.suggestion.clone(),
+        _ => None,
+    };
+    suggestion.map(|s| format!("did you mean `{s}`?")).into_iter().collect()
+}
+
+/// renders diagnostics in the style of rustc:
+///
+/// ```text
+/// error[E0003]: $t10 is not a valid register
+///  --> main.s:2:8
+///   |
+/// 2 |     li $t10, 1
+///   |        ^^^^
+///   = help: did you mean `$t1`?
+/// ```
+pub struct Renderer<'a> {
+    file_name: &'a str,
+    src: &'a str,
+    color: bool,
+}
+
+impl<'a> Renderer<'a> {
+
+    pub fn new(file_name: &'a str, src: &'a str, color: bool) -> Renderer<'a> {
+        Renderer { file_name, src, color }
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.color {
+            true => format!("{color}{text}{RESET}"),
+            false => text.to_string(),
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let line_number = diagnostic.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let bar = self.paint(BLUE, "|");
+        let source_line = self.src.lines().nth(diagnostic.line.saturating_sub(1) as usize).unwrap_or_default();
+
+        // keep tabs so the caret lines up with the source
+        let prefix: String = source_line.chars().take(diagnostic.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let remaining = source_line.chars().count().saturating_sub(diagnostic.column.saturating_sub(1));
+        let carets = "^".repeat(diagnostic.len.min(remaining).max(1));
+
+        let severity = diagnostic.severity;
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(severity.color(), &format!("{severity}[{}]", diagnostic.code)),
+            self.paint(BOLD, &format!(": {}", diagnostic.msg)),
+        );
+        out += &format!("{gutter}{} {}:{}:{}\n", self.paint(BLUE, "-->"), self.file_name, diagnostic.line, diagnostic.column);
+        out += &format!("{gutter} {bar}\n");
+        out += &format!("{} {bar} {source_line}\n", self.paint(BLUE, &line_number));
+        out += &format!("{gutter} {bar} {prefix}{}\n", self.paint(severity.color(), &carets));
+        for help in &diagnostic.help {
+            out += &format!("{gutter} {} help: {help}\n", self.paint(BLUE, "="));
+        }
+        out
+    }
+
+    pub fn render_error(&self, err: &ParserVerboseError) -> String {
+        self.render(&err.into())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::mock_parser;
+
+    fn render(src: &str) -> Vec<String> {
+        let renderer = Renderer::new("main.s", src, false);
+        mock_parser(src).unwrap_err().iter().map(|e| renderer.render_error(e)).collect()
+    }
+
+    #[test]
+    fn test_suggestions() {
+        assert_eq!(closest("$t10", register_names().iter().map(|n| n.as_str())), Some("$t1"));
+        assert_eq!(closest("addd", INSTRUCTIONS.iter().map(|(name, _)| *name)), Some("add"));
+        assert_eq!(closest("frobnicate", INSTRUCTIONS.iter().map(|(name, _)| *name)), None);
+    }
+
+    #[test]
+    fn test_render_snippet() {
+        let rendered = render(".text\n    li $t10, 1\n");
+        assert_eq!(rendered, vec![concat!(
+            "error[E0003]: $t10 is not a valid register\n",
+            " --> main.s:2:8\n",
+            "  |\n",
+            "2 |     li $t10, 1\n",
+            "  |        ^^^^\n",
+            "  = help: did you mean `$t1`?\n",
+        )]);
+
+        let rendered = render(".text\n\taddd $t0, $t0, $t1\n\tadd $t0\n");
+        assert!(rendered[0].starts_with("error[E0002]: invalid instruction: addd"));
+        assert!(rendered[0].contains("\n  | \t^^^^\n"));
+        assert!(rendered[0].contains("did you mean `add`?"));
+        assert!(rendered[1].contains("usage: `add $rd, $rs, $rt`"));
+
+        let rendered = render(".text\nmain: j man\n");
+        assert!(rendered[0].starts_with("error[E0006]: undefined label: man"));
+        assert!(rendered[0].contains("did you mean `main`?"));
+    }
+
+    #[test]
+    fn test_warning_levels() {
+        let src = ".text\nmain: li $zero, 1\nunused: li $k0, 2\n";
+        let warnings = mock_parser(src).unwrap().warnings;
+        let kinds: Vec<WarningKind> = warnings.iter().map(|w| w.kind).collect();
+        assert_eq!(kinds, vec![WarningKind::ZeroWrite, WarningKind::UnusedLabel, WarningKind::KernelRegister]);
+
+        let levels = WarningLevels::new(&[WarningKind::UnusedLabel], &[WarningKind::ZeroWrite]);
+        let diagnostics = levels.apply(&warnings);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+
+        let rendered = Renderer::new("main.s", src, false).render(&diagnostics[1]);
+        assert!(rendered.starts_with("warning[kernel-register]: $k0 is reserved for the kernel\n --> main.s:3:12"));
+    }
+
+    #[test]
+    fn test_render_color() {
+        let err = &mock_parser(".text\nfoo\n").unwrap_err()[0];
+        let rendered = Renderer::new("main.s", ".text\nfoo\n", true).render_error(err);
+        assert!(rendered.starts_with(RED));
+        assert!(rendered.contains(RESET));
+    }
+}
@@ -1,10 +1,11 @@
 use nom_locate::LocatedSpan;
 
-use crate::parser::ParserVerboseError;
+use crate::parser::{ErrorCode, ParserVerboseError};
 
 /// helper function to avoid .map_err everywhere
 pub fn map_parse_error<'a, T, E, F>(
     i: LocatedSpan<&'a str>,
+    code: ErrorCode,
     result_fn: F,
     err_msg: Option<&'a str>
 ) -> Result<T, nom::Err<ParserVerboseError>>
@@ -18,6 +19,8 @@ where
             column: i.get_column(),
             input: i.fragment().to_string(),
             msg: err_msg.map(|s| s.to_string()).unwrap_or(e.to_string()),
+            code,
+            suggestion: None,
         })
     })
 }
@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+
+use nom::{
+    IResult,
+    branch::alt,
+    bytes::complete::{tag, tag_no_case, take_while, take_while1},
+    character::complete::{anychar, char, multispace0, none_of},
+    combinator::{all_consuming, map, map_opt, map_res, recognize, value},
+    multi::fold_many0,
+    sequence::{delimited, pair, preceded},
+};
+
+/// an assembler-time constant expression such as
+/// `BUF_SIZE*4`, `msg+8` or `%hi(msg)`, it is
+/// evaluated against a symbol table once the
+/// symbols it refers to are known
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    // upper 16 bits, adjusted so that (%hi << 16) + %lo == value
+    Hi(Box<Expr>),
+    // lower 16 bits, sign extended
+    Lo(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+/// how a symbol came to be defined, `.eqv` constants
+/// and labels are fixed while `.set` may be redefined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Equate,
+    Set,
+    Label,
+}
+
+/// the width of the field an evaluated expression
+/// ends up in, used for range checking
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    // two's complement, e.g. the immediate of li
+    Signed(u32),
+    // e.g. the size of .space
+    Unsigned(u32),
+    // accepts both signed and unsigned values, e.g. .word -1 and .word 0xffffffff
+    Either(u32),
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Field::Signed(bits) => write!(f, "{bits}-bit signed"),
+            Field::Unsigned(bits) => write!(f, "{bits}-bit unsigned"),
+            Field::Either(bits) => write!(f, "{bits}-bit"),
+        }
+    }
+}
+
+impl Field {
+
+    /// inclusive range of values that fit in the field
+    pub fn range(&self) -> (i64, i64) {
+        match *self {
+            Field::Signed(bits) => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+            Field::Unsigned(bits) => (0, (1 << bits) - 1),
+            Field::Either(bits) => (-(1 << (bits - 1)), (1 << bits) - 1),
+        }
+    }
+
+    /// ensures value fits in the field, reporting an overflow otherwise
+    pub fn fit(&self, value: i64) -> Result<i64, String> {
+        let (min, max) = self.range();
+        if value < min || value > max {
+            return Err(format!("value {value} does not fit in a {self} field ({min}..={max})"));
+        }
+        Ok(value)
+    }
+}
+
+/// symbols known at assembly time, these are
+/// `.eqv`/`.set` constants and label addresses
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: HashMap<String, (i64, SymbolKind)>,
+}
+
+impl SymbolTable {
+
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// only `.set` symbols may be defined more than once
+    pub fn define(&mut self, name: &str, value: i64, kind: SymbolKind) -> Result<(), String> {
+        if let Some((_, existing)) = self.symbols.get(name) {
+            if !(*existing == SymbolKind::Set && kind == SymbolKind::Set) {
+                return Err(format!("symbol {name} is already defined"));
+            }
+        }
+        self.symbols.insert(name.to_string(), (value, kind));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<i64> {
+        self.symbols.get(name).map(|(value, _)| *value)
+    }
+
+    pub fn kind(&self, name: &str) -> Option<SymbolKind> {
+        self.symbols.get(name).map(|(_, kind)| *kind)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.symbols.keys().map(|name| name.as_str())
+    }
+
+}
+
+impl Expr {
+
+    /// the first symbol the expression refers to that is not defined
+    pub fn undefined<'a>(&'a self, symbols: &SymbolTable) -> Option<&'a str> {
+        match self {
+            Expr::Number(_) => None,
+            Expr::Symbol(name) => symbols.get(name).is_none().then_some(name.as_str()),
+            Expr::Negate(e) | Expr::Not(e) | Expr::Hi(e) | Expr::Lo(e) => e.undefined(symbols),
+            Expr::Binary(_, lhs, rhs) => lhs.undefined(symbols).or_else(|| rhs.undefined(symbols)),
+        }
+    }
+
+    pub fn eval(&self, symbols: &SymbolTable) -> Result<i64, String> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Symbol(name) => symbols.get(name).ok_or(format!("undefined symbol: {name}")),
+            Expr::Negate(e) => Ok(e.eval(symbols)?.wrapping_neg()),
+            Expr::Not(e) => Ok(!e.eval(symbols)?),
+            Expr::Hi(e) => Ok(((e.eval(symbols)? + 0x8000) >> 16) & 0xffff),
+            Expr::Lo(e) => Ok(e.eval(symbols)? as i16 as i64),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(symbols)?;
+                let rhs = rhs.eval(symbols)?;
+                let result = match op {
+                    BinOp::Add => lhs.checked_add(rhs),
+                    BinOp::Sub => lhs.checked_sub(rhs),
+                    BinOp::Mul => lhs.checked_mul(rhs),
+                    BinOp::Div if rhs == 0 => return Err("division by zero in constant expression".to_string()),
+                    BinOp::Div => lhs.checked_div(rhs),
+                    BinOp::Rem if rhs == 0 => return Err("division by zero in constant expression".to_string()),
+                    BinOp::Rem => lhs.checked_rem(rhs),
+                    BinOp::Shl => u32::try_from(rhs).ok().and_then(|r| lhs.checked_shl(r)),
+                    BinOp::Shr => u32::try_from(rhs).ok().and_then(|r| lhs.checked_shr(r)),
+                    BinOp::And => Some(lhs & rhs),
+                    BinOp::Or => Some(lhs | rhs),
+                    BinOp::Xor => Some(lhs ^ rhs),
+                };
+                result.ok_or(format!("overflow evaluating {lhs} {op:?} {rhs}"))
+            }
+        }
+    }
+}
+
+/// maps the character following a backslash
+/// in a character or string literal
+pub fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' => Some('\\'),
+        '\'' => Some('\''),
+        '"' => Some('"'),
+        _ => None,
+    }
+}
+
+fn ws<'a, O, F>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    delimited(multispace0, inner, multispace0)
+}
+
+fn number(i: &str) -> IResult<&str, Expr> {
+    map(
+        alt((
+            map_res(preceded(tag_no_case("0x"), take_while1(|c: char| c.is_ascii_hexdigit())), |s| i64::from_str_radix(s, 16)),
+            map_res(preceded(tag_no_case("0b"), take_while1(|c: char| c == '0' || c == '1')), |s| i64::from_str_radix(s, 2)),
+            map_res(take_while1(|c: char| c.is_ascii_digit()), |s: &str| s.parse::<i64>()),
+        )),
+        Expr::Number,
+    )(i)
+}
+
+fn char_literal(i: &str) -> IResult<&str, Expr> {
+    map(
+        delimited(
+            char('\''),
+            alt((preceded(char('\\'), map_opt(anychar, unescape)), none_of("\\'"))),
+            char('\''),
+        ),
+        |c| Expr::Number(c as i64),
+    )(i)
+}
+
+pub fn identifier(i: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        take_while1(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.'),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+    ))(i)
+}
+
+fn relocation(i: &str) -> IResult<&str, Expr> {
+    alt((
+        map(preceded(tag_no_case("%hi"), delimited(ws(char('(')), expr, char(')'))), |e| Expr::Hi(Box::new(e))),
+        map(preceded(tag_no_case("%lo"), delimited(ws(char('(')), expr, char(')'))), |e| Expr::Lo(Box::new(e))),
+    ))(i)
+}
+
+fn primary(i: &str) -> IResult<&str, Expr> {
+    ws(alt((
+        number,
+        char_literal,
+        relocation,
+        map(identifier, |s| Expr::Symbol(s.to_string())),
+        delimited(char('('), expr, char(')')),
+    )))(i)
+}
+
+fn unary(i: &str) -> IResult<&str, Expr> {
+    alt((
+        map(preceded(ws(char('-')), unary), |e| Expr::Negate(Box::new(e))),
+        map(preceded(ws(char('~')), unary), |e| Expr::Not(Box::new(e))),
+        preceded(ws(char('+')), unary),
+        primary,
+    ))(i)
+}
+
+/// parses a left associative chain of `operand (operator operand)*`
+fn binary_chain<'a>(
+    operand: fn(&'a str) -> IResult<&'a str, Expr>,
+    operator: fn(&'a str) -> IResult<&'a str, BinOp>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Expr> {
+    move |i| {
+        let (i, first) = operand(i)?;
+        fold_many0(
+            pair(ws(operator), operand),
+            move || first.clone(),
+            |lhs, (op, rhs)| Expr::Binary(op, Box::new(lhs), Box::new(rhs)),
+        )(i)
+    }
+}
+
+fn multiplicative(i: &str) -> IResult<&str, Expr> {
+    binary_chain(unary, |i| alt((
+        value(BinOp::Mul, char('*')),
+        value(BinOp::Div, char('/')),
+        value(BinOp::Rem, char('%')),
+    ))(i))(i)
+}
+
+fn additive(i: &str) -> IResult<&str, Expr> {
+    binary_chain(multiplicative, |i| alt((
+        value(BinOp::Add, char('+')),
+        value(BinOp::Sub, char('-')),
+    ))(i))(i)
+}
+
+fn shift(i: &str) -> IResult<&str, Expr> {
+    binary_chain(additive, |i| alt((
+        value(BinOp::Shl, tag("<<")),
+        value(BinOp::Shr, tag(">>")),
+    ))(i))(i)
+}
+
+fn bitand(i: &str) -> IResult<&str, Expr> {
+    binary_chain(shift, |i| value(BinOp::And, char('&'))(i))(i)
+}
+
+fn bitxor(i: &str) -> IResult<&str, Expr> {
+    binary_chain(bitand, |i| value(BinOp::Xor, char('^'))(i))(i)
+}
+
+fn expr(i: &str) -> IResult<&str, Expr> {
+    binary_chain(bitxor, |i| value(BinOp::Or, char('|'))(i))(i)
+}
+
+/// parses the whole of src as a constant expression
+pub fn parse_expr(src: &str) -> Result<Expr, String> {
+    all_consuming(expr)(src)
+        .map(|(_, e)| e)
+        .map_err(|_| format!("invalid constant expression: {}", src.trim()))
+}
+
+/// parses and evaluates src, then checks the result fits in field,
+/// `%hi` and `%lo` give 16 bit patterns so they are checked as such
+pub fn evaluate(src: &str, symbols: &SymbolTable, field: Field) -> Result<i64, String> {
+    let expr = parse_expr(src)?;
+    let field = match (&expr, field) {
+        (Expr::Hi(_) | Expr::Lo(_), Field::Signed(16)) => Field::Either(16),
+        _ => field,
+    };
+    field.fit(expr.eval(symbols)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str) -> Result<i64, String> {
+        let mut symbols = SymbolTable::new();
+        symbols.define("BUF_SIZE", 16, SymbolKind::Equate).unwrap();
+        symbols.define("msg", 0x1001_8004, SymbolKind::Label).unwrap();
+        parse_expr(src)?.eval(&symbols)
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(eval("42"), Ok(42));
+        assert_eq!(eval("0x1F"), Ok(31));
+        assert_eq!(eval("0b1010"), Ok(10));
+        assert_eq!(eval("'A'"), Ok(65));
+        assert_eq!(eval("'\\n'"), Ok(10));
+        assert_eq!(eval("-6"), Ok(-6));
+        assert!(eval("0b102").is_err());
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(eval("BUF_SIZE*4"), Ok(64));
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("1 << 4 | 1"), Ok(17));
+        assert_eq!(eval("~0 & 0xff"), Ok(255));
+        assert_eq!(eval("10 - 2 - 3"), Ok(5));
+        assert!(eval("1 / 0").is_err());
+    }
+
+    #[test]
+    fn test_symbols_and_relocations() {
+        assert_eq!(eval("msg+8"), Ok(0x1001_800c));
+        assert_eq!(eval("%hi(msg)"), Ok(0x1002));
+        assert_eq!(eval("%lo(msg)"), Ok(-0x7ffc));
+        assert_eq!((eval("%hi(msg)").unwrap() << 16) + eval("%lo(msg)").unwrap(), 0x1001_8004);
+        assert_eq!(eval("undefined"), Err("undefined symbol: undefined".to_string()));
+        let mut symbols = SymbolTable::new();
+        symbols.define("msg", 0, SymbolKind::Label).unwrap();
+        assert_eq!(parse_expr("%hi(msg + 4 * nosuch)").unwrap().undefined(&symbols), Some("nosuch"));
+        assert_eq!(parse_expr("%hi(msg)").unwrap().undefined(&symbols), None);
+    }
+
+    #[test]
+    fn test_field_range() {
+        let symbols = SymbolTable::new();
+        assert_eq!(evaluate("32767", &symbols, Field::Signed(16)), Ok(32767));
+        assert!(evaluate("32768", &symbols, Field::Signed(16)).is_err());
+        assert!(evaluate("-1", &symbols, Field::Unsigned(32)).is_err());
+        assert_eq!(evaluate("0xffffffff", &symbols, Field::Either(32)), Ok(0xffff_ffff));
+        assert_eq!(evaluate("-1", &symbols, Field::Either(32)), Ok(-1));
+        assert_eq!(evaluate("%hi(0xffff0000)", &symbols, Field::Signed(16)), Ok(0xffff));
+        assert!(evaluate("0xffff", &symbols, Field::Signed(16)).is_err());
+    }
+
+    #[test]
+    fn test_redefinition() {
+        let mut symbols = SymbolTable::new();
+        symbols.define("N", 1, SymbolKind::Set).unwrap();
+        assert!(symbols.define("N", 2, SymbolKind::Set).is_ok());
+        assert_eq!(symbols.get("N"), Some(2));
+        symbols.define("M", 1, SymbolKind::Equate).unwrap();
+        assert!(symbols.define("M", 2, SymbolKind::Equate).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// first descriptor handed out, 0 to 2 are the console
+const FIRST_FD: u32 = 3;
+
+struct OpenFile {
+    file: File,
+    writable: bool,
+}
+
+/// files opened by the file syscalls, which can only reach files
+/// inside the sandbox directory, without one nothing can be opened
+#[derive(Default)]
+pub struct FileTable {
+    sandbox: Option<PathBuf>,
+    files: BTreeMap<u32, OpenFile>,
+}
+
+impl std::fmt::Debug for FileTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileTable")
+            .field("sandbox", &self.sandbox)
+            .field("open", &self.files.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl FileTable {
+
+    pub fn new() -> FileTable {
+        FileTable::default()
+    }
+
+    /// relative paths are resolved against the sandbox
+    pub fn set_sandbox(&mut self, dir: Option<PathBuf>) {
+        self.sandbox = dir;
+    }
+
+    /// path of a file inside the sandbox, the file itself need not
+    /// exist but its directory must, symlinks are followed so they
+    /// cannot lead outside and dangling ones are refused since
+    /// creating the file would follow them
+    fn resolve(&self, name: &str) -> std::io::Result<PathBuf> {
+        let denied = || std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{name} is outside the sandbox"));
+        let sandbox = self.sandbox.as_ref().ok_or_else(denied)?.canonicalize()?;
+        let path = sandbox.join(name);
+        let file_name = path.file_name().ok_or_else(denied)?;
+        let dir = path.parent().map_or(Ok(sandbox.clone()), Path::canonicalize)?;
+        let path = dir.join(file_name);
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(_) if std::fs::symlink_metadata(&path).is_ok() => return Err(denied()),
+            Err(_) => path,
+        };
+        match path.starts_with(&sandbox) {
+            true => Ok(path),
+            false => Err(denied()),
+        }
+    }
+
+    /// opens a file with MARS's flags, 0 reads, 1 writes after
+    /// truncating and 9 appends, files being written are created
+    pub fn open(&mut self, name: &str, flags: u32) -> std::io::Result<u32> {
+        let path = self.resolve(name)?;
+        let file = match flags {
+            0 => File::open(path)?,
+            1 => File::create(path)?,
+            9 => OpenOptions::new().append(true).create(true).open(path)?,
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unsupported flags: {flags}"))),
+        };
+        let fd = (FIRST_FD..).find(|fd| !self.files.contains_key(fd)).unwrap_or(FIRST_FD);
+        self.files.insert(fd, OpenFile { file, writable: flags != 0 });
+        Ok(fd)
+    }
+
+    pub fn is_readable(&self, fd: u32) -> bool {
+        self.files.get(&fd).is_some_and(|f| !f.writable)
+    }
+
+    pub fn is_writable(&self, fd: u32) -> bool {
+        self.files.get(&fd).is_some_and(|f| f.writable)
+    }
+
+    /// up to max bytes, none at the end of the file
+    pub fn read(&mut self, fd: u32, max: usize) -> std::io::Result<Vec<u8>> {
+        let file = self.files.get_mut(&fd).ok_or(std::io::ErrorKind::NotFound)?;
+        let mut bytes = Vec::new();
+        (&mut file.file).take(max as u64).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn write(&mut self, fd: u32, bytes: &[u8]) -> std::io::Result<usize> {
+        let file = self.files.get_mut(&fd).ok_or(std::io::ErrorKind::NotFound)?;
+        file.file.write_all(bytes)?;
+        Ok(bytes.len())
+    }
+
+    /// false if the descriptor was not open
+    pub fn close(&mut self, fd: u32) -> bool {
+        self.files.remove(&fd).is_some()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_sandbox() {
+        let dir = std::env::temp_dir().join(format!("mipstenite-files-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let mut files = FileTable::new();
+        assert!(files.open("out.txt", 1).is_err());
+
+        files.set_sandbox(Some(dir.clone()));
+        let fd = files.open("sub/out.txt", 1).unwrap();
+        assert_eq!(fd, 3);
+        assert!(!files.is_readable(fd));
+        files.write(fd, b"hello").unwrap();
+        assert!(files.close(fd));
+        assert!(!files.close(fd));
+
+        let fd = files.open("sub/../sub/out.txt", 0).unwrap();
+        assert_eq!(files.read(fd, 3).unwrap(), b"hel");
+        assert_eq!(files.read(fd, 9).unwrap(), b"lo");
+        assert!(files.read(fd, 9).unwrap().is_empty());
+
+        assert!(files.open("../escape.txt", 1).is_err());
+        assert!(files.open("/etc/passwd", 0).is_err());
+        assert!(files.open("missing.txt", 0).is_err());
+        assert!(files.open("x", 2).is_err());
+
+        // a dangling link would create its target outside the sandbox
+        #[cfg(unix)]
+        {
+            let outside = std::env::temp_dir().join(format!("mipstenite-files-{}-outside", std::process::id()));
+            std::os::unix::fs::symlink(&outside, dir.join("link.txt")).unwrap();
+            assert_eq!(files.open("link.txt", 1).unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+            assert!(files.open("link.txt", 9).is_err());
+            assert!(!outside.exists());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::{BufRead, Write};
+
+use serde_json::Value;
+
+/// the next `Content-Length` framed message, None at end of input,
+/// this is how both the debug adapter and the language server talk
+pub(crate) fn read_message<R: BufRead>(input: &mut R) -> std::io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// writes a message framed the way `read_message` expects
+pub(crate) fn write_message<W: Write>(output: &mut W, message: &Value) -> std::io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// frames the messages of a session for the tests of the servers
+#[cfg(test)]
+pub(crate) fn frame_all(messages: impl IntoIterator<Item = Value>) -> Vec<u8> {
+    let mut input = Vec::new();
+    for message in messages {
+        write_message(&mut input, &message).unwrap();
+    }
+    input
+}
+
+/// every message a server sent back in a test session
+#[cfg(test)]
+pub(crate) fn read_all(mut output: &[u8]) -> Vec<Value> {
+    let mut messages = Vec::new();
+    while let Some(message) = read_message(&mut output).unwrap() {
+        messages.push(message);
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_framing() {
+        let messages = vec![json!({ "seq": 1 }), json!({ "text": "ü\r\n" })];
+        let input = frame_all(messages.clone());
+        assert!(input.starts_with(b"Content-Length: 9\r\n\r\n{\"seq\":1}"));
+        assert_eq!(read_all(&input), messages);
+
+        let err = read_message(&mut "Content-Type: x\r\n\r\n{}".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
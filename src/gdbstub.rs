@@ -0,0 +1,459 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+
+use crate::debug_table::{MachineException, MachineState, WatchHit, WatchKind, WatchTarget, Watchpoint};
+use crate::memory::TEXT_SEGMENT_BASE;
+use crate::virtual_machine::{self, VirtualMachine};
+
+// gdb's mips register layout: 32 general purpose registers, then
+// sr, lo, hi, badvaddr, cause, pc, 32 floating point registers,
+// fsr and fir, all 32 bits wide
+const SR: usize = 32;
+const LO: usize = 33;
+const HI: usize = 34;
+const CAUSE: usize = 36;
+const PC: usize = 37;
+const REGISTER_COUNT: usize = 72;
+
+/// why the target stopped, as reported to gdb
+enum Stop {
+    Trap,
+    Watch(WatchHit),
+    Exited,
+    Exception(MachineException),
+}
+
+/// what to do after answering a packet
+enum Session {
+    Continue,
+    End,
+}
+
+/// serves the gdb remote serial protocol for a loaded virtual machine,
+/// text addresses are TEXT_SEGMENT_BASE + 4 * instruction index and
+/// values are little endian, so gdb needs `set endian little`
+///
+/// continuing runs until a breakpoint, watchpoint or the end of the
+/// program, an interrupt from gdb is not noticed while running
+pub struct GdbStub {
+    vm: VirtualMachine,
+    // bytecode indices of the instructions with a breakpoint
+    breakpoints: BTreeSet<usize>,
+    // gdb's (type, address, length) to the watchpoints set for it
+    watchpoints: HashMap<(u8, u32, u32), Vec<usize>>,
+    no_ack: bool,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn hex_u32(src: &str) -> Option<u32> {
+    u32::from_str_radix(src, 16).ok()
+}
+
+fn hex_bytes(src: &str) -> Option<Vec<u8>> {
+    if !src.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..src.len()).step_by(2).map(|i| u8::from_str_radix(src.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// a 32 bit register in target byte order
+fn encode_register(value: u32) -> String {
+    value.to_le_bytes().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_register(src: &str) -> Option<u32> {
+    let bytes: [u8; 4] = hex_bytes(src)?.try_into().ok()?;
+    Some(u32::from_le_bytes(bytes))
+}
+
+impl GdbStub {
+
+    pub fn new(vm: VirtualMachine) -> GdbStub {
+        GdbStub {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: HashMap::new(),
+            no_ack: false,
+        }
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    /// waits for gdb to connect to addr, e.g. `127.0.0.1:1234`
+    pub fn serve_tcp(&mut self, addr: &str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        self.serve(stream.try_clone()?, stream)
+    }
+
+    /// answers packets until gdb detaches, kills the program or
+    /// closes the connection
+    pub fn serve<R: Read, W: Write>(&mut self, input: R, mut output: W) -> std::io::Result<()> {
+        let mut input = BufReader::new(input);
+        while let Some(packet) = self.read_packet(&mut input, &mut output)? {
+            let (reply, session) = self.handle(&packet);
+            self.write_packet(&mut output, &reply)?;
+            if let Session::End = session {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// the next `$data#checksum` packet, acknowledging it unless
+    /// gdb asked for no-ack mode, None once the input is closed
+    fn read_packet<R: BufRead, W: Write>(&self, input: &mut R, output: &mut W) -> std::io::Result<Option<String>> {
+        loop {
+            let mut skipped = Vec::new();
+            // acks and interrupts outside a packet are ignored
+            if input.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+                return Ok(None);
+            }
+            let mut data = Vec::new();
+            input.read_until(b'#', &mut data)?;
+            if data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut sum = [0u8; 2];
+            input.read_exact(&mut sum)?;
+
+            let data = String::from_utf8_lossy(&data).to_string();
+            let valid = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok()) == Some(checksum(&data));
+            if !self.no_ack {
+                output.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn write_packet<W: Write>(&self, output: &mut W, data: &str) -> std::io::Result<()> {
+        write!(output, "${data}#{:02x}", checksum(data))?;
+        output.flush()
+    }
+
+    /// the reply to a single packet, empty for unsupported packets
+    fn handle(&mut self, packet: &str) -> (String, Session) {
+        let reply = match packet.split_at(packet.len().min(1)) {
+            ("?", _) => "S05".to_string(),
+            ("g", _) => (0..REGISTER_COUNT).map(|n| encode_register(self.register(n))).collect(),
+            ("G", values) => {
+                for n in 0..REGISTER_COUNT {
+                    if let Some(value) = values.get(8 * n..8 * n + 8).and_then(decode_register) {
+                        self.set_register(n, value);
+                    }
+                }
+                "OK".to_string()
+            }
+            ("p", n) => match usize::from_str_radix(n, 16) {
+                Ok(n) if n < REGISTER_COUNT => encode_register(self.register(n)),
+                _ => "E01".to_string(),
+            },
+            ("P", assignment) => {
+                let parsed = assignment.split_once('=')
+                    .and_then(|(n, value)| Some((usize::from_str_radix(n, 16).ok()?, decode_register(value)?)));
+                match parsed {
+                    Some((n, value)) if n < REGISTER_COUNT => {
+                        self.set_register(n, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            ("m", args) => self.read_memory(args).unwrap_or("E01".to_string()),
+            ("M", args) => self.write_memory(args).unwrap_or("E01".to_string()),
+            ("Z", args) => self.insert_point(args).unwrap_or("E01".to_string()),
+            ("z", args) => self.remove_point(args).unwrap_or("E01".to_string()),
+            ("s", _) => {
+                let stop = self.step();
+                self.stop_reply(stop)
+            }
+            ("c", _) => {
+                let stop = self.resume();
+                self.stop_reply(stop)
+            }
+            ("H", _) => "OK".to_string(),
+            ("D", _) => return ("OK".to_string(), Session::End),
+            ("k", _) => return (String::new(), Session::End),
+            _ if packet.starts_with("qSupported") => "PacketSize=4000;QStartNoAckMode+".to_string(),
+            _ if packet == "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet == "qC" => "QC1".to_string(),
+            _ if packet == "qfThreadInfo" => "m1".to_string(),
+            _ if packet == "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        };
+        (reply, Session::Continue)
+    }
+
+    fn pc_address(&self) -> u32 {
+        let debug_info = &self.vm.runtime_dbg.compile_debug_info;
+        let index = debug_info.instruction_index(self.vm.pc()).unwrap_or(debug_info.instruction_count());
+        TEXT_SEGMENT_BASE + 4 * index as u32
+    }
+
+    /// bytecode index of the instruction at a text address
+    fn address_pc(&self, addr: u32) -> Option<usize> {
+        let offset = addr.checked_sub(TEXT_SEGMENT_BASE)?;
+        if !offset.is_multiple_of(4) {
+            return None;
+        }
+        self.vm.runtime_dbg.compile_debug_info.instruction_start(offset as usize / 4)
+    }
+
+    fn register(&self, n: usize) -> u32 {
+        match n {
+            0..=31 => self.vm.reg_get(n as u32),
+            SR => self.vm.cp0(virtual_machine::STATUS),
+            CAUSE => self.vm.cp0(virtual_machine::CAUSE),
+            LO => self.vm.reg_get(33),
+            HI => self.vm.reg_get(32),
+            PC => self.pc_address(),
+            _ => 0,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, value: u32) {
+        match n {
+            0..=31 => self.vm.reg_set(n as u32, value),
+            SR => self.vm.set_cp0(virtual_machine::STATUS, value),
+            CAUSE => self.vm.set_cp0(virtual_machine::CAUSE, value),
+            LO => self.vm.reg_set(33, value),
+            HI => self.vm.reg_set(32, value),
+            PC => {
+                if let Some(pc) = self.address_pc(value) {
+                    self.vm.set_pc(pc);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn parse_range(args: &str) -> Option<(u32, u32)> {
+        let (addr, len) = args.split_once(',')?;
+        Some((hex_u32(addr)?, hex_u32(len)?))
+    }
+
+    /// text has no machine encoding so it reads as zeros (nop)
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = GdbStub::parse_range(args)?;
+        (0..len).map(|i| {
+            let addr = addr.checked_add(i)?;
+            if self.address_pc(addr & !3).is_some() {
+                return Some("00".to_string());
+            }
+            self.vm.memory().read_byte(addr).ok().map(|b| format!("{b:02x}"))
+        }).collect()
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = GdbStub::parse_range(range)?;
+        let bytes = hex_bytes(data)?;
+        if bytes.len() != len as usize {
+            return None;
+        }
+        for (i, byte) in bytes.into_iter().enumerate() {
+            self.vm.memory_mut().write_byte(addr.checked_add(i as u32)?, byte).ok()?;
+        }
+        Some("OK".to_string())
+    }
+
+    fn parse_point(args: &str) -> Option<(u8, u32, u32)> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?.parse().ok()?;
+        let addr = hex_u32(fields.next()?)?;
+        let len = hex_u32(fields.next()?.split(';').next()?)?;
+        Some((kind, addr, len))
+    }
+
+    /// software breakpoints (0) and write (2), read (3) and access (4)
+    /// watchpoints, hardware breakpoints are treated as software ones
+    fn insert_point(&mut self, args: &str) -> Option<String> {
+        let (kind, addr, len) = GdbStub::parse_point(args)?;
+        let kinds = match kind {
+            0 | 1 => {
+                let pc = self.address_pc(addr)?;
+                self.breakpoints.insert(pc);
+                return Some("OK".to_string());
+            }
+            2 => vec![WatchKind::Write],
+            3 => vec![WatchKind::Read],
+            4 => vec![WatchKind::Read, WatchKind::Write],
+            _ => return Some(String::new()),
+        };
+        let target = WatchTarget::Memory(addr..addr.checked_add(len.max(1))?);
+        let ids = kinds.into_iter().map(|kind| self.vm.add_watchpoint(Watchpoint { target: target.clone(), kind })).collect();
+        self.watchpoints.insert((kind, addr, len), ids);
+        Some("OK".to_string())
+    }
+
+    fn remove_point(&mut self, args: &str) -> Option<String> {
+        let (kind, addr, len) = GdbStub::parse_point(args)?;
+        match kind {
+            0 | 1 => {
+                let pc = self.address_pc(addr)?;
+                self.breakpoints.remove(&pc);
+            }
+            2..=4 => {
+                for id in self.watchpoints.remove(&(kind, addr, len)).unwrap_or_default() {
+                    self.vm.remove_watchpoint(id);
+                }
+            }
+            _ => return Some(String::new()),
+        }
+        Some("OK".to_string())
+    }
+
+    /// executes the rest of the current instruction
+    fn step(&mut self) -> Stop {
+        let pc = self.vm.pc();
+        let end = self.vm.runtime_dbg.compile_debug_info.instruction_range(pc).map_or(pc + 1, |r| r.end);
+        for _ in pc..end {
+            match self.vm.execute() {
+                Ok(MachineState::Running) => {}
+                Ok(MachineState::Halted) => return Stop::Exited,
+                Err(e) => return Stop::Exception(e),
+            }
+        }
+        match self.vm.take_watch_hits().into_iter().next() {
+            Some(hit) => Stop::Watch(hit),
+            None => Stop::Trap,
+        }
+    }
+
+    fn resume(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Trap if !self.breakpoints.contains(&self.vm.pc()) => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Trap => "S05".to_string(),
+            Stop::Watch(hit) => {
+                let reason = match hit.kind {
+                    WatchKind::Read => "rwatch",
+                    _ => "watch",
+                };
+                let addr = match hit.target {
+                    WatchTarget::Memory(range) => range.start,
+                    WatchTarget::Register(_) => 0,
+                };
+                format!("T05{reason}:{addr:08x};")
+            }
+            Stop::Exited => "W00".to_string(),
+            Stop::Exception(e) => {
+                let signal = match e {
+                    MachineException::Overflow | MachineException::DivideByZero => 8,
+                    MachineException::AddressError | MachineException::Bus | MachineException::PcOutOfBounds(_) | MachineException::UninitializedRead(_) => 11,
+                    MachineException::InvalidSyscall(_) | MachineException::InvalidRegister(_) => 4,
+                    MachineException::StackUnderflow => 6,
+                    MachineException::InfiniteLoop | MachineException::Io(_) => 5,
+                };
+                format!("S{signal:02x}")
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::DATA_SEGMENT_BASE;
+    use crate::parser::parse_source;
+
+    fn stub(src: &str) -> GdbStub {
+        let program = parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        GdbStub::new(vm)
+    }
+
+    /// sends the packets and returns the replies without acks
+    fn session(stub: &mut GdbStub, packets: &[&str]) -> Vec<String> {
+        let input: String = packets.iter().map(|p| format!("+${p}#{:02x}", checksum(p))).collect();
+        let mut output = Vec::new();
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+            .split('$').skip(1)
+            .map(|reply| reply.split_once('#').unwrap().0.to_string())
+            .collect()
+    }
+
+    const SRC: &str = "\
+.text
+main:   li $t0, 5
+        sw $t0, arr
+        add $t0, $t0, $t0
+        li $v0, 10
+        syscall
+.data
+arr:    .word 7
+";
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = stub(SRC);
+        let replies = session(&mut stub, &["qSupported:multiprocess+", "?", "g", "p25", "P8=2a000000", "m10010000,4", "M10010000,2:0102", "m400000,4"]);
+        assert_eq!(replies[0], "PacketSize=4000;QStartNoAckMode+");
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2].len(), REGISTER_COUNT * 8);
+        // $sp and pc
+        assert_eq!(&replies[2][29 * 8..30 * 8], "fcefff7f");
+        assert_eq!(&replies[2][PC * 8..PC * 8 + 8], "00004000");
+        assert_eq!(replies[3], "00004000");
+        assert_eq!(replies[4], "OK");
+        assert_eq!(stub.vm().reg_get(8), 42);
+        assert_eq!(replies[5], "07000000");
+        assert_eq!(replies[6], "OK");
+        assert_eq!(stub.vm().memory().read_word(DATA_SEGMENT_BASE).unwrap(), 0x0201);
+        assert_eq!(replies[7], "00000000");
+    }
+
+    #[test]
+    fn test_cp0_registers() {
+        let mut stub = stub(SRC);
+        let replies = session(&mut stub, &["p20", "p24", "P20=01ff0000", "P24=00010000", "p24"]);
+        assert_eq!(replies, vec!["11ff0000", "00000000", "OK", "OK", "00010000"]);
+        assert_eq!(stub.vm().cp0(virtual_machine::STATUS), 0xff01);
+        assert_eq!(stub.vm().cp0(virtual_machine::CAUSE), 0x100);
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let mut stub = stub(SRC);
+        let replies = session(&mut stub, &["Z0,400008,4", "c", "p25", "s", "p8", "z0,400008,4", "c"]);
+        assert_eq!(replies, vec!["OK", "S05", "08004000", "S05", "0a000000", "OK", "W00"]);
+    }
+
+    #[test]
+    fn test_watchpoints_and_detach() {
+        let mut stub = stub(SRC);
+        let replies = session(&mut stub, &["QStartNoAckMode", "Z2,10010000,4", "c", "p25", "D", "c"]);
+        assert_eq!(replies, vec!["OK", "OK", "T05watch:10010000;", "08004000", "OK"]);
+        assert_eq!(stub.vm().memory().read_word(DATA_SEGMENT_BASE).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut stub = stub(SRC);
+        let mut output = Vec::new();
+        stub.serve("$?#00$?#3f".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "-+$S05#b8");
+    }
+}
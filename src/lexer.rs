@@ -1,69 +1,261 @@
-use std::collections::HashSet;
+use nom::{
+    IResult,
+    bytes::complete::{take, take_while, take_while1},
+    character::complete::{char, line_ending},
+    combinator::recognize,
+    sequence::{pair, terminated},
+};
+use nom_locate::LocatedSpan;
 
-use crate::{bytecode::AsmInstruction, debug_table::CompileDebugInfo};
+use crate::{parser::{ErrorCode, ParserVerboseError}, expr::unescape};
 
-enum Token {
+type Span<'a> = LocatedSpan<&'a str>;
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Token {
     COMMA,
 
+    // `name:` label definition
     LABEL(String),
+    // `.text`, `.word` etc.
+    DIRECTIVE(String),
     INSTRUCTION(String),
+    // `$t0` or `$8`, validated by the parser
     REGISTER(String),
+    // constant expression text, evaluated by the parser
     IMMEDIATE(String),
+    // string literal with escapes already applied
+    STRING(String),
 
+    NEWLINE,
     EOF,
 }
 
-pub struct ParsedInstruction {
-    asm_ins: AsmInstruction,
-    line_num: usize,
-    label: String,
+/// a token along with where it was found in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme<'a> {
+    pub token: Token,
+    pub span: Span<'a>,
+}
+
+/// splits the source into tokens, one line at a time, every
+/// line ends with a NEWLINE token and the source with EOF
+pub struct Lexer<'a> {
+    remaining: Span<'a>,
+}
+
+fn lexer_error(i: Span, msg: String) -> nom::Err<ParserVerboseError> {
+    nom::Err::Failure(ParserVerboseError {
+        line: i.location_line(),
+        column: i.get_column(),
+        input: i.fragment().to_string(),
+        msg,
+        code: ErrorCode::Syntax,
+        suggestion: None,
+    })
+}
+
+/// mips supports # comments only
+fn eol_comment<'a>(i: Span<'a>) -> IResult<Span<'a>, String, ParserVerboseError> {
+    let (i, (_, comment)) = pair(char('#'), take_while(|c| c != '\r' && c != '\n'))(i).map_err(|_: nom::Err<nom::error::Error<Span<'a>>>| {
+        lexer_error(i, "failed to parse comment".to_string())
+    })?;
+    Ok((i, comment.fragment().to_string()))
+}
+
+fn identifier(i: Span) -> IResult<Span, Span, ParserVerboseError> {
+    recognize(pair(
+        take_while1(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.'),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+    ))(i)
+}
+
+/// whitespace inside a line, anything `char::is_whitespace`
+/// accepts other than a line ending
+fn is_blank(c: char) -> bool {
+    c.is_whitespace() && c != '\r' && c != '\n'
+}
+
+fn blank(i: Span) -> IResult<Span, Span, ParserVerboseError> {
+    take_while(is_blank)(i)
+}
+
+fn at_end_of_line(i: Span) -> bool {
+    matches!(i.fragment().chars().next(), None | Some('\n') | Some('\r') | Some('#'))
+}
+
+/// a double quoted string, # inside the quotes is not a comment
+fn string_literal(i: Span) -> IResult<Span, String, ParserVerboseError> {
+    let (rest, _) = char('"')(i)?;
+    let mut literal = String::new();
+    let mut chars = rest.fragment().char_indices();
+    loop {
+        match chars.next() {
+            Some((idx, '"')) => {
+                let (rest, _) = take(idx + 1)(rest)?;
+                return Ok((rest, literal));
+            }
+            Some((_, '\\')) => {
+                let c = chars.next().and_then(|(_, c)| unescape(c)).ok_or(lexer_error(i, "invalid escape sequence in string literal".to_string()))?;
+                literal.push(c);
+            }
+            Some((_, '\n')) | None => return Err(lexer_error(i, "unterminated string literal".to_string())),
+            Some((_, c)) => literal.push(c),
+        }
+    }
 }
 
-pub struct Lexer {
-    source: String,
-    lines: Vec<String>,
-    line_number: usize,
-    labels: HashSet<String>,
-    compile_dbg: CompileDebugInfo,
+/// an operand runs up to the next comma, comment or end of line
+/// that is not inside a character literal or parentheses
+fn operand(i: Span) -> IResult<Span, Span, ParserVerboseError> {
+    let mut in_char = false;
+    let mut escaped = false;
+    let mut depth = 0;
+    let mut end = i.fragment().len();
+    for (idx, c) in i.fragment().char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_char => escaped = true,
+            '\'' => in_char = !in_char,
+            _ if in_char => {}
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => { end = idx; break; }
+            '#' | '\r' | '\n' => { end = idx; break; }
+            _ => {}
+        }
+    }
+    let trimmed = i.fragment()[..end].trim_end_matches(is_blank).len();
+    if trimmed == 0 {
+        return Err(lexer_error(i, format!("unexpected character: {}", i.fragment().chars().next().unwrap_or_default())));
+    }
+    take(trimmed)(i)
 }
 
-impl Lexer {
+/// lexes a single line, `label: instr operands # comment`,
+/// any part of which may be missing
+fn lex_line(i: Span) -> IResult<Span, Vec<Lexeme>, ParserVerboseError> {
+    let mut tokens = Vec::new();
+    let (mut i, _) = blank(i)?;
+
+    // any number of labels may precede the statement
+    while let Ok((rest, name)) = terminated(identifier, pair(blank, char(':')))(i) {
+        tokens.push(Lexeme { token: Token::LABEL(name.fragment().to_string()), span: name });
+        i = blank(rest)?.0;
+    }
+
+    if let Ok((rest, name)) = identifier(i) {
+        let token = match name.fragment().starts_with('.') {
+            true => Token::DIRECTIVE(name.fragment().to_string()),
+            false => Token::INSTRUCTION(name.fragment().to_string()),
+        };
+        tokens.push(Lexeme { token, span: name });
+        i = blank(rest)?.0;
+
+        while !at_end_of_line(i) {
+            if let Ok((rest, comma)) = recognize(char::<Span, ParserVerboseError>(','))(i) {
+                tokens.push(Lexeme { token: Token::COMMA, span: comma });
+                i = rest;
+            } else if i.fragment().starts_with('"') {
+                let start = i;
+                let (rest, literal) = string_literal(i)?;
+                let (_, span) = take(start.fragment().len() - rest.fragment().len())(start)?;
+                tokens.push(Lexeme { token: Token::STRING(literal), span });
+                i = rest;
+            } else {
+                let (rest, text) = operand(i)?;
+                let token = match text.fragment().starts_with('$') {
+                    true => Token::REGISTER(text.fragment().to_string()),
+                    false => Token::IMMEDIATE(text.fragment().to_string()),
+                };
+                tokens.push(Lexeme { token, span: text });
+                i = rest;
+            }
+            i = blank(i)?.0;
+        }
+    }
+
+    if i.fragment().starts_with('#') {
+        i = eol_comment(i)?.0;
+    }
 
-    pub fn new(source: String) -> Lexer {
-        let lines = source.split("\n").map(|s| s.to_string()).collect();
+    if i.fragment().is_empty() {
+        return Ok((i, tokens));
+    }
+    match line_ending::<Span, ParserVerboseError>(i) {
+        Ok((rest, newline)) => {
+            tokens.push(Lexeme { token: Token::NEWLINE, span: newline });
+            Ok((rest, tokens))
+        }
+        Err(_) => Err(lexer_error(i, format!("unexpected character: {}", i.fragment().chars().next().unwrap_or_default()))),
+    }
+}
+
+impl<'a> Lexer<'a> {
+
+    pub fn new(source: &'a str) -> Lexer<'a> {
         Lexer {
-            source,
-            lines,
-            line_number: 1,
-            labels: HashSet::new(),
-            compile_dbg: CompileDebugInfo::new(),
+            remaining: Span::new(source),
         }
     }
 
-    pub fn next(&mut self) -> Option<String> {
-        match self.lines.get(self.line_number - 1) {
-            Some(line) => {
-                self.line_number += 1;
-                Some(line.clone())
+    /// tokens of the next line, None once the source is exhausted
+    pub fn next_line(&mut self) -> Option<Result<Vec<Lexeme<'a>>, ParserVerboseError>> {
+        if self.remaining.fragment().is_empty() {
+            return None;
+        }
+        match lex_line(self.remaining) {
+            Ok((rest, tokens)) => {
+                self.remaining = rest;
+                Some(Ok(tokens))
+            },
+            Err(err) => {
+                // skip the rest of the line so lexing can continue
+                let (rest, _) = take_while::<_, Span, ParserVerboseError>(|c| c != '\n')(self.remaining).unwrap();
+                self.remaining = line_ending::<Span, ParserVerboseError>(rest).map(|(rest, _)| rest).unwrap_or(rest);
+                Some(Err(err.into()))
             },
-            None => None,
         }
     }
 
-    // parses a line and then calls on specific functions to parse the line
-    pub fn parse(&mut self) {
-        let line = self.next();
-        match line {
-            Some(l) => {
-            },
-            None => (),
+    /// lexes the whole source, stopping at the first error
+    pub fn tokenize(mut self) -> Result<Vec<Lexeme<'a>>, ParserVerboseError> {
+        let mut tokens = Vec::new();
+        while let Some(line) = self.next_line() {
+            tokens.extend(line?);
         }
+        tokens.push(Lexeme { token: Token::EOF, span: self.remaining });
+        Ok(tokens)
     }
 
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn tokens(src: &str) -> Vec<Token> {
+        Lexer::new(src).tokenize().unwrap().into_iter().map(|l| l.token).collect()
+    }
+
+    #[test]
+    fn test_eol_comment() {
+        let input = "# This is a comment\n";
+        let result = eol_comment(Span::new(input));
+        assert!(result.is_ok());
+        let (i, comment) = result.unwrap();
+        assert_eq!(i.fragment(), &"\n");
+        assert_eq!(comment, " This is a comment");
+
+        let input = "// This is NOT a comment";
+        let result = eol_comment(Span::new(input));
+        assert!(result.is_err());
+
+        let err = result.unwrap_err();
+        let pve: ParserVerboseError = err.into();
+        assert!(pve.msg.contains("failed to parse comment"));
+    }
 
     #[test]
     fn test_hello_world() {
@@ -76,12 +268,86 @@ mod tests {
                 li $v0, 10
                 syscall
             .data
-            msg: .asciiz "Hello, world!"#;
-        let mut lexer = super::Lexer::new(src.to_string());
-        let line = lexer.next();
-        println!("{:?}", line);
-        assert!(false == true);
+            msg: .asciiz "Hello, world!""#;
+        let mut lexer = Lexer::new(src);
+        assert_eq!(lexer.next_line().unwrap().unwrap().len(), 1);
+        let line = lexer.next_line().unwrap().unwrap();
+        assert_eq!(line[0].token, Token::DIRECTIVE(".text".to_string()));
+        assert_eq!(line[0].span.location_line(), 2);
+        assert_eq!(line[0].span.get_column(), 13);
+
+        assert_eq!(tokens(src).iter().filter(|t| matches!(t, Token::INSTRUCTION(_))).count(), 5);
+        assert_eq!(tokens(src).last(), Some(&Token::EOF));
     }
 
-}
+    #[test]
+    fn test_label_and_comments() {
+        assert_eq!(tokens("loop: add $t0, $t0, $8 # increment\n"), vec![
+            Token::LABEL("loop".to_string()),
+            Token::INSTRUCTION("add".to_string()),
+            Token::REGISTER("$t0".to_string()),
+            Token::COMMA,
+            Token::REGISTER("$t0".to_string()),
+            Token::COMMA,
+            Token::REGISTER("$8".to_string()),
+            Token::NEWLINE,
+            Token::EOF,
+        ]);
+
+        assert_eq!(tokens("# only a comment\nend:"), vec![
+            Token::NEWLINE,
+            Token::LABEL("end".to_string()),
+            Token::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_strings_and_immediates() {
+        assert_eq!(tokens(r#"msg: .asciiz "a # b\n" # comment"#), vec![
+            Token::LABEL("msg".to_string()),
+            Token::DIRECTIVE(".asciiz".to_string()),
+            Token::STRING("a # b\n".to_string()),
+            Token::EOF,
+        ]);
+
+        assert_eq!(tokens("li $t0, ','#comma\nli $t1, (1 + 2) * 3"), vec![
+            Token::INSTRUCTION("li".to_string()),
+            Token::REGISTER("$t0".to_string()),
+            Token::COMMA,
+            Token::IMMEDIATE("','".to_string()),
+            Token::NEWLINE,
+            Token::INSTRUCTION("li".to_string()),
+            Token::REGISTER("$t1".to_string()),
+            Token::COMMA,
+            Token::IMMEDIATE("(1 + 2) * 3".to_string()),
+            Token::EOF,
+        ]);
+    }
 
+    #[test]
+    fn test_unusual_whitespace() {
+        for src in ["main: li $t0, 1 \x0c\n", "main: li $t0, 1\u{a0}\n", "main:\x0bli\u{a0}$t0,\x0c1\n"] {
+            assert_eq!(tokens(src), vec![
+                Token::LABEL("main".to_string()),
+                Token::INSTRUCTION("li".to_string()),
+                Token::REGISTER("$t0".to_string()),
+                Token::COMMA,
+                Token::IMMEDIATE("1".to_string()),
+                Token::NEWLINE,
+                Token::EOF,
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        let err = Lexer::new("\n.asciiz \"never closed").tokenize().unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.msg.contains("unterminated string literal"));
+
+        let mut lexer = Lexer::new("li $t0, 1\n@oops\nsyscall");
+        assert!(lexer.next_line().unwrap().is_ok());
+        assert!(lexer.next_line().unwrap().is_err());
+        assert!(lexer.next_line().unwrap().is_ok());
+    }
+}
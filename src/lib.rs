@@ -1,8 +1,26 @@
 pub mod parser;
+pub mod lexer;
+pub mod lsp;
 pub mod parser_utils;
+pub mod random;
+pub mod timer;
+pub mod bitmap;
 pub mod bytecode;
+pub mod checksum;
+pub mod console;
+pub mod dap;
 pub mod memory;
+pub mod memview;
+pub mod mmio;
 pub mod registers;
+pub mod replay;
+pub mod snapshot;
 pub mod virtual_machine;
 pub mod debug_table;
+pub mod debugger;
+pub mod diagnostics;
 pub mod err_util;
+pub mod expr;
+pub mod files;
+pub mod framing;
+pub mod gdbstub;
@@ -0,0 +1,497 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::diagnostics::{register_names, Diagnostic, Severity, WarningLevels};
+use crate::framing::{read_message, write_message};
+use crate::lexer::{Lexeme, Lexer, Token};
+use crate::parser::{parse_source, referenced_symbols, DIRECTIVES, INSTRUCTIONS};
+use crate::registers::register_to_addr;
+
+// json-rpc error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// lsp enumerations
+const SYNC_FULL: u32 = 1;
+const ERROR: u32 = 1;
+const WARNING: u32 = 2;
+const COMPLETION_KEYWORD: u32 = 14;
+const COMPLETION_VARIABLE: u32 = 6;
+const COMPLETION_REFERENCE: u32 = 18;
+const SYMBOL_FUNCTION: u32 = 12;
+const SYMBOL_VARIABLE: u32 = 13;
+const SYMBOL_CONSTANT: u32 = 14;
+
+/// what each mnemonic does, shown on hover along with its usage
+const INSTRUCTION_DOCS: &[(&str, &str)] = &[
+    ("li", "load immediate, sets `$rt` to a constant"),
+    ("la", "load address, sets `$rt` to the address of a label"),
+    ("add", "adds `$rs` and `$rt` and stores the sum in `$rd`"),
+    ("lw", "loads the word at `offset($base)` into `$rt`"),
+    ("sw", "stores `$rt` to the word at `offset($base)`"),
+    ("j", "jumps to a label"),
+    ("jal", "jumps to a label, saving the return address in `$ra`"),
+    ("jalr", "jumps to the address in `$rs`, saving the return address in `$rd`"),
+    ("jr", "jumps to the address in `$rs`, `jr $ra` returns from a call"),
+    ("syscall", "requests the service numbered by `$v0`"),
+    ("mfc0", "moves CP0 register `$rd` into `$rt`"),
+    ("mtc0", "moves `$rt` into CP0 register `$rd`"),
+    ("eret", "returns from the exception handler to the address in EPC"),
+];
+
+fn register_doc(number: u32) -> &'static str {
+    match number {
+        0 => "always zero, writes are ignored",
+        1 => "reserved for the assembler",
+        2 | 3 => "function results",
+        4..=7 => "function arguments",
+        8..=15 | 24 | 25 => "temporary, not preserved across calls",
+        16..=23 => "saved, preserved across calls",
+        26 | 27 => "reserved for the kernel",
+        28 => "global pointer",
+        29 => "stack pointer",
+        30 => "frame pointer",
+        31 => "return address",
+        32 => "high word of a product, remainder of a division",
+        _ => "low word of a product, quotient of a division",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DefinitionKind {
+    TextLabel,
+    DataLabel,
+    Constant,
+}
+
+/// a name at a place in the source, lines and characters count from 0
+#[derive(Debug, Clone, PartialEq)]
+struct Occurrence {
+    name: String,
+    line: u32,
+    start: u32,
+    end: u32,
+}
+
+impl Occurrence {
+    fn new(name: &str, line: u32, start: u32) -> Occurrence {
+        Occurrence {
+            name: name.to_string(),
+            line,
+            start,
+            end: start + name.chars().count() as u32,
+        }
+    }
+
+    fn from_lexeme(lexeme: &Lexeme, name: &str) -> Occurrence {
+        Occurrence::new(name, lexeme.span.location_line() - 1, lexeme.span.get_utf8_column() as u32 - 1)
+    }
+
+    fn contains(&self, line: u32, character: u32) -> bool {
+        self.line == line && (self.start..=self.end).contains(&character)
+    }
+
+    fn range(&self) -> Value {
+        json!({
+            "start": { "line": self.line, "character": self.start },
+            "end": { "line": self.line, "character": self.end },
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Definition {
+    at: Occurrence,
+    kind: DefinitionKind,
+    // the directive laying out a data label
+    detail: Option<String>,
+}
+
+/// labels and constants of a document, found by lexing alone so
+/// that navigation still works in a file that does not assemble
+#[derive(Debug, Default)]
+struct SymbolIndex {
+    definitions: Vec<Definition>,
+    references: Vec<Occurrence>,
+}
+
+/// names referred to by an operand, along with their offset in it
+fn symbol_words(operand: &str) -> impl Iterator<Item = (usize, &str)> {
+    referenced_symbols(operand)
+        .map(move |word| (word.as_ptr() as usize - operand.as_ptr() as usize, word))
+        // register names and relocations such as `%hi(x)`
+        .filter(move |(offset, _)| !matches!(operand[..*offset].chars().last(), Some('$' | '%')))
+}
+
+impl SymbolIndex {
+
+    fn new(src: &str) -> SymbolIndex {
+        let mut index = SymbolIndex::default();
+        let mut kind = DefinitionKind::TextLabel;
+        // data labels get the directive of the next data item
+        let mut pending = Vec::new();
+
+        let mut lexer = Lexer::new(src);
+        while let Some(line) = lexer.next_line() {
+            let Ok(tokens) = line else { continue };
+            let mut head = None;
+            for lexeme in &tokens {
+                match &lexeme.token {
+                    Token::LABEL(name) => {
+                        if kind == DefinitionKind::DataLabel {
+                            pending.push(index.definitions.len());
+                        }
+                        index.definitions.push(Definition { at: Occurrence::from_lexeme(lexeme, name), kind, detail: None });
+                    }
+                    Token::DIRECTIVE(directive) => {
+                        head = Some(directive.as_str());
+                        match directive.as_str() {
+                            ".text" => kind = DefinitionKind::TextLabel,
+                            ".data" => kind = DefinitionKind::DataLabel,
+                            ".globl" | ".global" | ".eqv" | ".set" => {}
+                            _ => for definition in pending.drain(..) {
+                                index.definitions[definition].detail = Some(directive.clone());
+                            },
+                        }
+                    }
+                    Token::INSTRUCTION(_) => head = Some(""),
+                    Token::IMMEDIATE(text) => {
+                        let mut words = symbol_words(text).peekable();
+                        let defines = matches!(head.take(), Some(".eqv" | ".set"));
+                        if let Some((offset, name)) = words.next_if(|(offset, _)| defines && *offset == 0) {
+                            let at = Occurrence::from_lexeme(lexeme, name);
+                            index.definitions.push(Definition { at: Occurrence { start: at.start + offset as u32, end: at.end + offset as u32, ..at }, kind: DefinitionKind::Constant, detail: None });
+                        }
+                        for (offset, name) in words {
+                            let at = Occurrence::from_lexeme(lexeme, name);
+                            index.references.push(Occurrence { start: at.start + offset as u32, end: at.end + offset as u32, ..at });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        index
+    }
+
+    /// the symbol named at a position, if any
+    fn name_at(&self, line: u32, character: u32) -> Option<&str> {
+        self.definitions.iter().map(|d| &d.at)
+            .chain(self.references.iter())
+            .find(|o| o.contains(line, character))
+            .map(|o| o.name.as_str())
+    }
+
+    fn definition(&self, name: &str) -> Option<&Definition> {
+        self.definitions.iter().find(|d| d.at.name == name)
+    }
+
+}
+
+/// the register, mnemonic, directive or name a position falls in
+fn word_at(src: &str, line: u32, character: u32) -> Option<String> {
+    let text: Vec<char> = src.lines().nth(line as usize)?.chars().collect();
+    let is_word = |c: &char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$');
+    let character = (character as usize).min(text.len());
+    let start = text[..character].iter().rposition(|c| !is_word(c)).map_or(0, |p| p + 1);
+    let end = text[character..].iter().position(|c| !is_word(c)).map_or(text.len(), |p| character + p);
+    let word: String = text[start..end].iter().collect();
+    (!word.is_empty()).then_some(word)
+}
+
+fn lsp_diagnostic(diagnostic: &Diagnostic) -> Value {
+    let line = diagnostic.line.saturating_sub(1);
+    let start = diagnostic.column.saturating_sub(1);
+    let mut message = diagnostic.msg.clone();
+    for help in &diagnostic.help {
+        message.push_str(&format!("\nhelp: {help}"));
+    }
+    json!({
+        "range": {
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": start + diagnostic.len },
+        },
+        "severity": match diagnostic.severity {
+            Severity::Error => ERROR,
+            Severity::Warning => WARNING,
+        },
+        "code": diagnostic.code,
+        "source": "mipstenite",
+        "message": message,
+    })
+}
+
+/// Language Server Protocol server, documents are synchronized
+/// in full and reassembled on every change
+#[derive(Default)]
+pub struct LanguageServer {
+    documents: HashMap<String, String>,
+}
+
+impl LanguageServer {
+
+    pub fn new() -> LanguageServer {
+        LanguageServer::default()
+    }
+
+    /// handles messages until the client sends `exit`
+    pub fn serve<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> std::io::Result<()> {
+        while let Some(message) = read_message(&mut input)? {
+            let method = message["method"].as_str().unwrap_or_default();
+            if method == "exit" {
+                break;
+            }
+            let params = &message["params"];
+            let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+            match method {
+                "textDocument/didOpen" => {
+                    self.documents.insert(uri.clone(), params["textDocument"]["text"].as_str().unwrap_or_default().to_string());
+                    self.publish_diagnostics(&uri, &mut output)?;
+                }
+                "textDocument/didChange" => {
+                    // with full synchronization the last change is the whole document
+                    if let Some(text) = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                        self.documents.insert(uri.clone(), text.to_string());
+                    }
+                    self.publish_diagnostics(&uri, &mut output)?;
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(&uri);
+                    write_message(&mut output, &json!({
+                        "jsonrpc": "2.0",
+                        "method": "textDocument/publishDiagnostics",
+                        "params": { "uri": uri, "diagnostics": [] },
+                    }))?;
+                }
+                _ => {}
+            }
+            // notifications have no id and get no response
+            let Some(id) = message.get("id") else { continue };
+            let mut response = json!({ "jsonrpc": "2.0", "id": id });
+            match self.request(method, &uri, params) {
+                Ok(result) => response["result"] = result,
+                Err((code, message)) => response["error"] = json!({ "code": code, "message": message }),
+            }
+            write_message(&mut output, &response)?;
+        }
+        Ok(())
+    }
+
+    fn request(&self, method: &str, uri: &str, params: &Value) -> Result<Value, (i64, String)> {
+        if method == "initialize" {
+            return Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": SYNC_FULL,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["$", "."] },
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "mipstenite" },
+            }));
+        }
+        if method == "shutdown" {
+            return Ok(Value::Null);
+        }
+
+        let src = self.documents.get(uri).ok_or((INVALID_PARAMS, format!("unknown document: {uri}")))?;
+        let line = params["position"]["line"].as_u64().unwrap_or_default() as u32;
+        let character = params["position"]["character"].as_u64().unwrap_or_default() as u32;
+        let index = SymbolIndex::new(src);
+        let location = |at: &Occurrence| json!({ "uri": uri, "range": at.range() });
+
+        match method {
+            "textDocument/definition" => Ok(index.name_at(line, character)
+                .and_then(|name| index.definition(name))
+                .map_or(Value::Null, |d| location(&d.at))),
+            "textDocument/references" => {
+                let Some(name) = index.name_at(line, character) else { return Ok(json!([])) };
+                let declarations = index.definitions.iter().map(|d| &d.at)
+                    .filter(|_| params["context"]["includeDeclaration"].as_bool().unwrap_or(true));
+                let locations: Vec<Value> = declarations.chain(index.references.iter())
+                    .filter(|o| o.name == name)
+                    .map(location)
+                    .collect();
+                Ok(json!(locations))
+            }
+            "textDocument/hover" => Ok(self.hover(src, &index, line, character).map_or(Value::Null, |markdown| json!({
+                "contents": { "kind": "markdown", "value": markdown },
+            }))),
+            "textDocument/completion" => Ok(json!(self.completion(src, &index, line, character))),
+            "textDocument/documentSymbol" => Ok(json!(index.definitions.iter().map(|d| {
+                let kind = match d.kind {
+                    DefinitionKind::TextLabel => SYMBOL_FUNCTION,
+                    DefinitionKind::DataLabel => SYMBOL_VARIABLE,
+                    DefinitionKind::Constant => SYMBOL_CONSTANT,
+                };
+                let mut symbol = json!({ "name": d.at.name, "kind": kind, "range": d.at.range(), "selectionRange": d.at.range() });
+                if let Some(detail) = &d.detail {
+                    symbol["detail"] = json!(detail);
+                }
+                symbol
+            }).collect::<Vec<Value>>())),
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method: {method}"))),
+        }
+    }
+
+    fn publish_diagnostics<W: Write>(&self, uri: &str, output: &mut W) -> std::io::Result<()> {
+        let Some(src) = self.documents.get(uri) else { return Ok(()) };
+        let diagnostics: Vec<Value> = match parse_source(uri, src) {
+            Ok(program) => WarningLevels::default().apply(&program.warnings).iter().map(lsp_diagnostic).collect(),
+            Err(errors) => errors.iter().map(|e| lsp_diagnostic(&Diagnostic::from(e))).collect(),
+        };
+        write_message(output, &json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    fn hover(&self, src: &str, index: &SymbolIndex, line: u32, character: u32) -> Option<String> {
+        if let Some(definition) = index.name_at(line, character).and_then(|name| index.definition(name)) {
+            let kind = match definition.kind {
+                DefinitionKind::TextLabel => "label",
+                DefinitionKind::DataLabel => "data label",
+                DefinitionKind::Constant => "constant",
+            };
+            let detail = definition.detail.as_ref().map(|d| format!(" `{d}`")).unwrap_or_default();
+            return Some(format!("{kind} `{}`{detail} defined on line {}", definition.at.name, definition.at.line + 1));
+        }
+        let word = word_at(src, line, character)?;
+        if word.starts_with('$') {
+            let number = register_to_addr(word.clone())?;
+            return Some(format!("`{word}` register {number}, {}", register_doc(number)));
+        }
+        let usage = INSTRUCTIONS.iter().find(|(name, _)| *name == word).map(|(_, usage)| *usage)?;
+        let doc = INSTRUCTION_DOCS.iter().find(|(name, _)| *name == word).map(|(_, doc)| *doc).unwrap_or_default();
+        Some(format!("```mips\n{usage}\n```\n{doc}"))
+    }
+
+    fn completion(&self, src: &str, index: &SymbolIndex, line: u32, character: u32) -> Vec<Value> {
+        let text: String = src.lines().nth(line as usize).unwrap_or_default().chars().take(character as usize).collect();
+        let item = |label: &str, kind: u32, detail: &str| json!({ "label": label, "kind": kind, "detail": detail });
+        let registers = || register_names().into_iter().map(|name| {
+            let doc = register_to_addr(name.clone()).map(register_doc).unwrap_or_default();
+            item(&name, COMPLETION_VARIABLE, doc)
+        });
+
+        let word = text.rsplit(|c: char| c.is_whitespace() || c == ',' || c == '(').next().unwrap_or_default();
+        if word.starts_with('$') {
+            return registers().collect();
+        }
+        // the mnemonic follows any labels on the line
+        let statement = text.rsplit(':').next().unwrap_or_default().trim_start();
+        if !statement.contains(char::is_whitespace) {
+            let instructions = INSTRUCTIONS.iter().map(|(name, usage)| item(name, COMPLETION_KEYWORD, usage));
+            let directives = DIRECTIVES.iter().map(|name| item(name, COMPLETION_KEYWORD, "directive"));
+            return match word.starts_with('.') {
+                true => directives.collect(),
+                false => instructions.chain(directives).collect(),
+            };
+        }
+        let symbols = index.definitions.iter().map(|d| item(&d.at.name, COMPLETION_REFERENCE, d.detail.as_deref().unwrap_or_default()));
+        registers().chain(symbols).collect()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{frame_all, read_all};
+
+    const URI: &str = "file:///main.s";
+    const SRC: &str = ".eqv N, 4\n.data\nlist:   .word 1, N\n.text\nmain:   la $t0, list\n        lw $t1, list\nloop:   j loop\n";
+
+    /// opens SRC, sends the requests and returns every message sent back
+    fn session(src: &str, requests: Vec<(&str, Value)>) -> Vec<Value> {
+        let open = json!({
+            "jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "mips", "version": 1, "text": src } },
+        });
+        let requests = requests.into_iter().enumerate().map(|(id, (method, mut params))| {
+            params["textDocument"] = json!({ "uri": URI });
+            json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+        });
+        let exit = json!({ "jsonrpc": "2.0", "method": "exit" });
+        let input = frame_all(std::iter::once(open).chain(requests).chain([exit]));
+
+        let mut output = Vec::new();
+        LanguageServer::new().serve(input.as_slice(), &mut output).unwrap();
+        read_all(&output)
+    }
+
+    fn position(line: u32, character: u32) -> Value {
+        json!({ "position": { "line": line, "character": character } })
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let messages = session("main: li $t10, 1\n", vec![]);
+        let diagnostic = &messages[0]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["code"], "E0003");
+        assert_eq!(diagnostic["severity"], ERROR);
+        assert_eq!(diagnostic["range"]["start"], json!({ "line": 0, "character": 9 }));
+        assert_eq!(diagnostic["message"], "$t10 is not a valid register\nhelp: did you mean `$t1`?");
+
+        let messages = session(SRC, vec![]);
+        assert_eq!(messages[0]["params"]["diagnostics"], json!([]));
+
+        let messages = session("main: li $t0, 1\nunused: li $t1, 2\n", vec![]);
+        let diagnostic = &messages[0]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["code"], "unused-label");
+        assert_eq!(diagnostic["severity"], WARNING);
+    }
+
+    #[test]
+    fn test_navigation() {
+        let messages = session(SRC, vec![
+            ("textDocument/definition", position(5, 17)),
+            ("textDocument/references", position(2, 1)),
+            ("textDocument/definition", position(2, 18)),
+            ("textDocument/definition", position(4, 12)),
+            ("textDocument/documentSymbol", json!({})),
+        ]);
+        assert_eq!(messages[1]["result"]["range"]["start"], json!({ "line": 2, "character": 0 }));
+        let references: Vec<u64> = messages[2]["result"].as_array().unwrap().iter()
+            .map(|l| l["range"]["start"]["line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(references, vec![2, 4, 5]);
+        assert_eq!(messages[3]["result"]["range"]["start"], json!({ "line": 0, "character": 5 }));
+        // `$t0` is not a symbol
+        assert_eq!(messages[4]["result"], Value::Null);
+
+        let symbols: Vec<(&str, u64)> = messages[5]["result"].as_array().unwrap().iter()
+            .map(|s| (s["name"].as_str().unwrap(), s["kind"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(symbols, vec![("N", 14), ("list", 13), ("main", 12), ("loop", 12)]);
+        assert_eq!(messages[5]["result"][1]["detail"], ".word");
+    }
+
+    #[test]
+    fn test_hover_and_completion() {
+        let messages = session(SRC, vec![
+            ("textDocument/hover", position(5, 9)),
+            ("textDocument/hover", position(5, 13)),
+            ("textDocument/hover", position(6, 11)),
+            ("textDocument/completion", json!({ "position": { "line": 5, "character": 12 } })),
+            ("textDocument/completion", position(6, 8)),
+            ("textDocument/completion", position(4, 17)),
+        ]);
+        assert_eq!(messages[1]["result"]["contents"]["value"], "```mips\nlw $rt, offset($base)\n```\nloads the word at `offset($base)` into `$rt`");
+        assert_eq!(messages[2]["result"]["contents"]["value"], "`$t1` register 9, temporary, not preserved across calls");
+        assert_eq!(messages[3]["result"]["contents"]["value"], "label `loop` defined on line 7");
+
+        let labels = |n: usize| -> Vec<String> {
+            messages[n]["result"].as_array().unwrap().iter().map(|i| i["label"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(labels(4).len(), 34);
+        assert!(labels(5).contains(&"syscall".to_string()));
+        assert!(!labels(5).contains(&"$t0".to_string()));
+        assert!(labels(6).contains(&"list".to_string()));
+        assert!(labels(6).contains(&"$a0".to_string()));
+    }
+}
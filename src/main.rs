@@ -1,19 +1,241 @@
-use clap::Parser;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand, ValueEnum};
 
 use log::error;
-use mipstenite::{parser::mock_parser, virtual_machine::VirtualMachine, bytecode::{Bytecode, AsmInstruction}, debug_table::{CompileDebugInfo, MachineState}, err_util::setup_logger};
+use mipstenite::{bitmap::{self, Bitmap}, console::{Buffer, FileConsole, SocketConsole}, replay::ReplayLog, parser::parse_source, virtual_machine::{Clock, UninitCheck, VirtualMachine}, debug_table::{MachineException, MachineState}, memview::{self, ViewFormat}, dap::DapServer, debugger::Debugger, gdbstub::GdbStub, lsp::LanguageServer, diagnostics::{Renderer, Severity, WarningLevels}, parser::WarningKind, err_util::setup_logger};
+
+/// exit status when the program runs into --max-steps
+const EXIT_MAX_STEPS: i32 = 3;
+/// exit status when the program runs past --timeout
+const EXIT_TIMEOUT: i32 = 4;
+/// exit status when the program jumps to itself forever
+const EXIT_INFINITE_LOOP: i32 = 5;
 
 #[derive(Debug, Parser)]
-#[clap(author, version, about)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-	#[clap(required = true)]
-	file_path: Option<String>,
+	#[clap(subcommand)]
+	mode: Option<Mode>,
 
-	#[clap(long, short, action)]
-	cleanup: bool,
+	#[clap(required_unless_present = "resume")]
+	file_path: Option<String>,
 
 	#[clap(long, short)]
 	debug: bool,
+
+	/// serve the gdb remote protocol on an address such as
+	/// `127.0.0.1:1234`, or on stdin and stdout with `stdio`, which
+	/// keeps the program's console off them unless it is redirected
+	#[clap(long, value_name = "ADDR")]
+	gdb: Option<String>,
+
+	/// report calls that break the O32 calling convention
+	#[clap(long)]
+	check_calls: bool,
+
+	/// report reads of registers and memory that were never
+	/// written, either as warnings or by stopping with an error
+	#[clap(long, value_name = "warn|trap")]
+	uninit: Option<UninitCheck>,
+
+	/// fill uninitialized registers and memory with noise, seeded
+	/// from the clock unless a seed is given
+	#[clap(long, value_name = "SEED", num_args = 0..=1)]
+	randomize: Option<Option<u64>>,
+
+	/// show memory once the program stops, a data label,
+	/// `start..end` or `data` for the whole data section
+	#[clap(long, value_name = "WHERE")]
+	view: Vec<String>,
+
+	/// format of `--view`: auto, hex, signed, unsigned, float or string
+	#[clap(long, value_name = "FORMAT", default_value = "auto")]
+	view_as: ViewFormat,
+
+	/// read the program's console input from a file
+	#[clap(long, value_name = "FILE")]
+	input: Option<String>,
+
+	/// write the program's console output to a file
+	#[clap(long, value_name = "FILE")]
+	output: Option<String>,
+
+	/// use a TCP connection accepted on an address such as
+	/// `127.0.0.1:4000` as the program's console
+	#[clap(long, value_name = "ADDR", conflicts_with_all = ["input", "output"])]
+	console_socket: Option<String>,
+
+	/// seed of the random syscalls, seeded from the clock by default
+	#[clap(long, value_name = "SEED")]
+	seed: Option<u64>,
+
+	/// give the time syscall a clock that starts at the given
+	/// milliseconds since the epoch, 0 by default, and only moves
+	/// when the program sleeps, which then returns immediately
+	#[clap(long, value_name = "MILLIS", num_args = 0..=1, default_missing_value = "0")]
+	virtual_clock: Option<u64>,
+
+	/// directory the program's file syscalls are confined to
+	#[clap(long, value_name = "DIR", default_value = ".")]
+	sandbox: String,
+
+	/// save every input the program takes, with the step it was
+	/// taken at, to a log that --replay can feed back
+	#[clap(long, value_name = "FILE")]
+	record: Option<String>,
+
+	/// take the program's input from a log saved by --record
+	#[clap(long, value_name = "FILE", conflicts_with = "record")]
+	replay: Option<String>,
+
+	/// attach a bitmap display and save it when the program stops,
+	/// as a PNG if FILE ends in .png and as a PPM otherwise
+	#[clap(long, value_name = "FILE")]
+	bitmap: Option<String>,
+
+	/// size of the bitmap display in pixels
+	#[clap(long, value_name = "WIDTHxHEIGHT", default_value = "512x256", value_parser = bitmap::parse_size)]
+	bitmap_size: (u32, u32),
+
+	/// pixels covered by each unit of the bitmap display
+	#[clap(long, value_name = "WIDTHxHEIGHT", default_value = "1", value_parser = bitmap::parse_size)]
+	bitmap_unit: (u32, u32),
+
+	/// address of the bitmap display's framebuffer
+	#[clap(long, value_name = "ADDR", default_value = "0x10010000", value_parser = parse_address)]
+	bitmap_base: u32,
+
+	/// also save every Nth frame, a frame ends whenever the program
+	/// writes to 0xffff0010, the 4th frame of out.png is out-0004.png
+	#[clap(long, value_name = "N", requires = "bitmap")]
+	bitmap_every: Option<u64>,
+
+	/// save a snapshot when the program has executed this many
+	/// bytecodes or first reaches a label, and keep running
+	#[clap(long, value_name = "STEP|LABEL")]
+	snapshot_at: Option<String>,
+
+	/// where --snapshot-at saves the snapshot
+	#[clap(long, value_name = "FILE", default_value = "vm.snapshot")]
+	snapshot: String,
+
+	/// carry on from a snapshot instead of assembling a program,
+	/// the console, files, display and limits are set up anew
+	#[clap(long, value_name = "FILE", conflicts_with_all = ["file_path", "randomize", "seed", "virtual_clock", "record", "replay"])]
+	resume: Option<String>,
+
+	/// stop after executing this many bytecodes
+	#[clap(long, value_name = "N")]
+	max_steps: Option<u64>,
+
+	/// stop after running for this many seconds
+	#[clap(long, value_name = "SECS")]
+	timeout: Option<f64>,
+
+	/// number of bytecodes the debugger can step back through
+	#[clap(long, default_value_t = 100_000)]
+	history: usize,
+
+	/// silence a warning, e.g. `--allow unused-label`
+	#[clap(long, short = 'A', value_name = "WARNING")]
+	allow: Vec<WarningKind>,
+
+	/// report a warning as an error
+	#[clap(long, short = 'D', value_name = "WARNING")]
+	deny: Vec<WarningKind>,
+
+	/// colorize diagnostics
+	#[clap(long, value_enum, default_value_t = ColorChoice::Auto)]
+	color: ColorChoice,
+}
+
+#[derive(Debug, Subcommand)]
+enum Mode {
+	/// serve the Debug Adapter Protocol on stdin and stdout
+	Dap,
+	/// serve the Language Server Protocol on stdin and stdout
+	Lsp,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ColorChoice {
+	Auto,
+	Always,
+	Never,
+}
+
+impl ColorChoice {
+	fn enabled(&self) -> bool {
+		match self {
+			ColorChoice::Auto => std::io::stderr().is_terminal(),
+			ColorChoice::Always => true,
+			ColorChoice::Never => false,
+		}
+	}
+}
+
+/// a decimal or 0x prefixed hexadecimal address
+fn parse_address(s: &str) -> Result<u32, String> {
+	match s.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(hex, 16),
+		None => s.parse::<u32>(),
+	}.map_err(|_| format!("invalid address: {s}"))
+}
+
+/// writes the bitmap display of a run started with --bitmap
+fn save_bitmap(args: &Args, vm: &VirtualMachine) {
+	let Some(path) = &args.bitmap else { return };
+	if let Err(e) = vm.save_bitmap(std::path::Path::new(path)) {
+		eprintln!("Unable to write {}: {}", path, e);
+	}
+}
+
+/// writes the log of a run started with --record
+fn save_record(args: &Args, vm: &VirtualMachine) {
+	let (Some(path), Some(log)) = (&args.record, vm.replay_log()) else { return };
+	if let Err(e) = std::fs::File::create(path).and_then(|file| log.write(std::io::BufWriter::new(file))) {
+		eprintln!("Unable to write {}: {}", path, e);
+	}
+}
+
+/// assembles the program, reporting errors and warnings, and
+/// loads it into a new machine
+fn assemble(args: &Args) -> VirtualMachine {
+	let file_path = args.file_path.clone().unwrap();
+	let src = std::fs::read_to_string(&file_path).unwrap_or_else(|e| {
+		eprintln!("Unable to read {}: {}", &file_path, e);
+		std::process::exit(1);
+	});
+	let result = parse_source(&file_path, &src);
+	let program = match result {
+		Ok(program) => program,
+		Err(errors) => {
+			let renderer = Renderer::new(&file_path, &src, args.color.enabled());
+			for e in &errors {
+				eprintln!("{}", renderer.render_error(e));
+			}
+			let plural = if errors.len() == 1 { "" } else { "s" };
+			eprintln!("error: could not assemble {} due to {} previous error{}", &file_path, errors.len(), plural);
+			std::process::exit(1);
+		}
+	};
+	let renderer = Renderer::new(&file_path, &src, args.color.enabled());
+	let diagnostics = WarningLevels::new(&args.allow, &args.deny).apply(&program.warnings);
+	for diagnostic in &diagnostics {
+		eprintln!("{}", renderer.render(diagnostic));
+	}
+	let denied = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+	if denied > 0 {
+		let plural = if denied == 1 { "" } else { "s" };
+		eprintln!("error: could not assemble {} due to {} previous error{}", &file_path, denied, plural);
+		std::process::exit(1);
+	}
+
+	let mut vm = VirtualMachine::new();
+	vm.load_program(&program);
+	vm
 }
 
 fn main() {
@@ -21,11 +243,21 @@ fn main() {
 	setup_logger();
 
 	let args = Args::parse();
+
+	if let Some(mode) = &args.mode {
+		let result = match mode {
+			Mode::Dap => DapServer::new().serve(std::io::stdin().lock(), std::io::stdout()),
+			Mode::Lsp => LanguageServer::new().serve(std::io::stdin().lock(), std::io::stdout()),
+		};
+		if let Err(e) = result {
+			eprintln!("Error: {}", e);
+			std::process::exit(1);
+		}
+		std::process::exit(0);
+	}
 	
 	// check if valid file path
-	let file_path = args.file_path.clone();
-	if file_path.is_some() {
-		let file_path = file_path.unwrap();
+	if let Some(file_path) = args.file_path.clone() {
 		if !std::path::Path::new(&file_path).exists() {
 			error!("Invalid file path");
 			eprintln!("Invalid file path: {}", &file_path);
@@ -33,110 +265,164 @@ fn main() {
 		}
 	}
 
-	if args.cleanup {
-			// remove all files with numbers in the name ending with .bin extension
-			let files = std::fs::read_dir(".").unwrap();
-			for file in files {
-				let file = file.unwrap();
-				let file_name = file.file_name();
-				let file_name = file_name.to_str().unwrap();
-				if file_name.ends_with(".bin") {
-					let file_name = file_name.split(".bin").collect::<Vec<&str>>()[0];
-					if file_name.parse::<u32>().is_ok() {
-						std::fs::remove_file(file.path()).unwrap();
-					}
+	let mut vm = match &args.resume {
+		Some(path) => VirtualMachine::load_snapshot(std::path::Path::new(path)).unwrap_or_else(|e| {
+			eprintln!("Unable to resume from {}: {}", path, e);
+			std::process::exit(1);
+		}),
+		None => assemble(&args),
+	};
+
+		vm.set_convention_check(args.check_calls);
+		vm.set_uninit_check(args.uninit);
+		if args.input.is_some() || args.output.is_some() {
+			let console = FileConsole::open(args.input.as_deref().map(std::path::Path::new), args.output.as_deref().map(std::path::Path::new));
+			match console {
+				Ok(console) => vm.set_console(Box::new(console)),
+				Err(e) => {
+					eprintln!("Unable to open console files: {}", e);
+					std::process::exit(1);
 				}
 			}
-		std::process::exit(0);
 		}
-	if args.debug {
-		error!("not implementd");
-	}
-
-    let _src = r#"
-        # ------------------------------------------------------------------
-        	
-        	.text
-        main:
-        	lw	$s0, x		# Reg $s0 = x
-        	lw	$s1, y		# Reg $s1 = y
-        
-        	# Call function
-        	move	$a0, $s0	# Argument 1: x ($s0)
-        	jal	fun		# Save current PC in $ra, and jump to fun
-        	move	$s1,$v0		# Return value saved in $v0. This is y ($s1)
-        
-        	# Print msg1
-        	li	$v0, 4		# print_string syscall code = 4
-        	la	$a0, msg1
-        	syscall
-        
-        fun:	# This function overwrites $s0 and $s1
-        	# We should save those on the stack
-        	# This is PUSH'ing onto the stack
-        	addi $sp,$sp,-4		# Adjust stack pointer
-        	sw $s0,0($sp)		# Save $s0
-        	addi $sp,$sp,-4		# Adjust stack pointer
-        	sw $s1,0($sp)		# Save $s1
-        
-        	# Save the return value in $v0
-        	move $v0,$s1
-        
-        	.data
-        x:	.word 5
-        y:	.word 0
-        msg1:	.asciiz	"y="
-        lf:     .asciiz	"\n"
-        "#;
-
-	let src = r#"
-	# Program File: Program2-1.asm 
-	# Author: Charles Kann
-	# Purpose: First program, Hello World
-	.text                   # Define the program instructions.
-	main:                   # Label to define the main program.
-	    li $v0,4            # Load 4 into $v0 to indicate a print string.
-	    la $a0, greeting    # Load the address of the greeting into $a0.
-	    syscall             # Print greeting. The print is indicated by
-	                        # $v0 having a value of 4, and the string to
-	                        # print is stored at the address in $a0.
-	    li $v0, 10          # Load a 10 (halt) into $v0.
-	    syscall             # The program ends.
-	.data                   # Define the program data.
-	greeting: .asciiz "Hello World" #The string to print.
-	"#;
-
-        let lxr_src = src;
-		let result = mock_parser(lxr_src);
-		let asm_instructions: Vec<AsmInstruction>;
-		let mut byc_translations: Vec<Vec<Bytecode>> = Vec::new();
-		if result.is_ok() {
-			asm_instructions = result.unwrap().1;
-		} else {
-			println!("{:#?}", result);
-			std::process::exit(1);
+		if let Some(addr) = &args.console_socket {
+			eprintln!("waiting for a console connection on {}", addr);
+			match SocketConsole::listen(addr) {
+				Ok(console) => vm.set_console(Box::new(console)),
+				Err(e) => {
+					eprintln!("Error: {}", e);
+					std::process::exit(1);
+				}
+			}
+		}
+		if let Some(seed) = args.randomize {
+			let seed = seed.unwrap_or_else(|| {
+				let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+				now.as_nanos() as u64
+			});
+			eprintln!("randomizing with seed {}", seed);
+			vm.randomize(seed);
 		}
 
-		// construct a HashMap that maps line_number or usize to a tupe of (instruction, Vec<Bytecode>)
-		// this allows for easy access of assembly instruction and generated bytecode for every line
-		let compile_debug_info = CompileDebugInfo::new(asm_instructions.clone());
+		vm.set_sandbox(Some(std::path::PathBuf::from(&args.sandbox)));
+		if let Some(seed) = args.seed {
+			vm.set_random_seed(seed);
+		}
+		if let Some(millis) = args.virtual_clock {
+			vm.set_clock(Clock::Virtual(millis));
+		}
+		if let Some(path) = &args.bitmap {
+			match Bitmap::new(args.bitmap_base, args.bitmap_size, args.bitmap_unit) {
+				Ok(bitmap) => vm.set_bitmap(Some(bitmap)),
+				Err(e) => {
+					eprintln!("Error: {}", e);
+					std::process::exit(1);
+				}
+			}
+			if let Some(every) = args.bitmap_every {
+				vm.save_frames(std::path::PathBuf::from(path), every);
+			}
+		}
+		if args.record.is_some() {
+			vm.record();
+		}
+		if let Some(path) = &args.replay {
+			let log = std::fs::File::open(path).and_then(|file| ReplayLog::read(std::io::BufReader::new(file)));
+			match log {
+				Ok(log) => vm.replay(log),
+				Err(e) => {
+					eprintln!("Unable to read {}: {}", path, e);
+					std::process::exit(1);
+				}
+			}
+		}
 
-		let mut byc_instructions = asm_instructions.into_iter().map(|i| {
-			let byc = i.to_bytecode();
-			byc_translations.push(byc.clone());
-			byc
-		}).flatten().collect::<Vec<Bytecode>>();
-		byc_instructions.push(Bytecode::TERMINATOR);
+		if let Some(addr) = &args.gdb {
+			// stdin and stdout carry the protocol in stdio mode, so the
+			// program gets a console of its own unless it is redirected
+			if addr == "stdio" && args.console_socket.is_none() {
+				match (&args.input, &args.output) {
+					(None, None) => vm.set_console(Box::new(Buffer::new(""))),
+					(Some(_), Some(_)) => {}
+					_ => {
+						eprintln!("--gdb stdio needs both --input and --output to redirect the console");
+						std::process::exit(1);
+					}
+				}
+			}
+			let mut stub = GdbStub::new(vm);
+			let result = match addr.as_str() {
+				"stdio" => stub.serve(std::io::stdin(), std::io::stdout()),
+				addr => {
+					eprintln!("waiting for gdb on {}", addr);
+					stub.serve_tcp(addr)
+				}
+			};
+			if let Err(e) = result {
+				eprintln!("Error: {}", e);
+				std::process::exit(1);
+			}
+			std::process::exit(0);
+		}
 
-		let mut vm = VirtualMachine::new();
-		vm.set_program(byc_instructions);
-		vm.setup_debug(compile_debug_info);
+		if args.debug {
+			vm.set_history_budget(args.history);
+			let symbols = vm.symbols().clone();
+			let mut debugger = Debugger::new(vm, symbols);
+			if let Err(e) = debugger.run(std::io::stdin().lock(), std::io::stdout()) {
+				eprintln!("Error: {}", e);
+				std::process::exit(1);
+			}
+			save_record(&args, debugger.vm());
+			save_bitmap(&args, debugger.vm());
+			std::process::exit(0);
+		}
 
-		// Serialize the VM to a file
-		vm.dump();
+		// the step, or the bytecode index of the label, to save a snapshot at
+		let mut snapshot_at = args.snapshot_at.as_ref().map(|when| match when.parse::<u64>() {
+			Ok(step) => (Some(step), None),
+			Err(_) => match vm.runtime_dbg.compile_debug_info.label(when) {
+				Some(pc) => (None, Some(pc)),
+				None => {
+					eprintln!("Error: no label named {}", when);
+					std::process::exit(1);
+				}
+			},
+		});
 
+		let deadline = args.timeout.map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+		let mut steps: u64 = 0;
+		let mut status = 0;
 		loop {
-			match vm.execute() {
+			if args.max_steps.is_some_and(|max| steps >= max) {
+				eprintln!("stopped after {} steps", steps);
+				status = EXIT_MAX_STEPS;
+				break;
+			}
+			// checking the clock every step would slow everything down
+			if steps.is_multiple_of(1024) && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+				eprintln!("stopped after running for {} seconds", args.timeout.unwrap_or_default());
+				status = EXIT_TIMEOUT;
+				break;
+			}
+			if let Some((step, pc)) = snapshot_at {
+				if step == Some(vm.steps()) || pc == Some(vm.pc()) {
+					match vm.save_snapshot(std::path::Path::new(&args.snapshot)) {
+						Ok(()) => eprintln!("saved a snapshot to {} at step {}", args.snapshot, vm.steps()),
+						Err(e) => eprintln!("Unable to write {}: {}", args.snapshot, e),
+					}
+					snapshot_at = None;
+				}
+			}
+			steps += 1;
+			let result = vm.execute();
+			for violation in vm.take_violations() {
+				eprintln!("warning: {}", vm.runtime_dbg.describe_violation(&violation));
+			}
+			for read in vm.take_uninit_reads() {
+				eprintln!("warning: {}", vm.runtime_dbg.describe_uninit_read(&read));
+			}
+			match result {
 				Ok(o) => {
 					match o {
 						MachineState::Running => {}
@@ -146,14 +432,38 @@ fn main() {
 					}	
 				},
 				Err(e) => {
-					eprintln!("Error: {:?}", e);
+					eprintln!("Error: {}", vm.describe_exception(&e));
+					if matches!(e, MachineException::InfiniteLoop) {
+						status = EXIT_INFINITE_LOOP;
+					}
 					break;
 				}
 			}
 		}
+		save_record(&args, &vm);
+		save_bitmap(&args, &vm);
+		if status == EXIT_MAX_STEPS || status == EXIT_TIMEOUT {
+			if let Some(hot) = vm.hottest_loop() {
+				eprintln!("  hottest loop: {}", vm.runtime_dbg.describe_loop(&hot));
+			}
+		}
 
 		vm.runtime_dbg.print_debug_info();
 
+		for target in &args.view {
+			let target = if target == "data" { "" } else { target.as_str() };
+			let view = memview::resolve_range(vm.memory(), vm.symbols(), target)
+				.and_then(|range| memview::view(vm.memory(), range, args.view_as));
+			match view {
+				Ok(view) => println!("{}", view),
+				Err(e) => eprintln!("Error: {}", e),
+			}
+		}
+
 		println!("{:#?}", vm);
 
+		if status != 0 {
+			std::process::exit(status);
+		}
+
 }
@@ -1,13 +1,33 @@
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
 
 use serde::{Serialize, Deserialize};
 
+use crate::debug_table::{MachineException, SourceSpan};
+use crate::snapshot::{put_bytes, Fields};
+
+/// address the first item of the .data section is
+/// placed at, same as MARS's default memory layout
+pub const DATA_SEGMENT_BASE: u32 = 0x1001_0000;
+
+/// address of the first instruction, text labels are
+/// given addresses as if every instruction took 4 bytes
+pub const TEXT_SEGMENT_BASE: u32 = 0x0040_0000;
+
+/// initial value of $sp, memory from the data segment up to
+/// here can be used even if it was not declared in .data
+pub const STACK_POINTER: u32 = 0x7fff_effc;
+
+/// initial value of $gp
+pub const GLOBAL_POINTER: u32 = 0x1000_8000;
+
 /// one word = 4 bytes
 /// a 32 bit word must be located
 /// and accessed using a word aligned
 /// address. This means that the address
 /// must be divisible by 4 or the last
 /// two bits must be 0.
+#[allow(dead_code)]
 struct Word {
     bytes: [u8; 4],
 }
@@ -17,6 +37,7 @@ struct Word {
 /// and accessed using a half word aligned 
 /// address. This means that the address
 /// the last lower order bit must be 0.
+#[allow(dead_code)]
 struct HalfWord {
     bytes: [u8; 2],
 }
@@ -31,6 +52,10 @@ pub enum DataDirective {
     HalfWord(u16),
     // stores n 32 bit values in successive words of memory
     Word(u32),
+    // single precision floating point values
+    Float(f32),
+    // double precision floating point values
+    Double(f64),
     // ascii string without null terminator
     Ascii(String),
     // ascii string with null terminator
@@ -41,23 +66,83 @@ pub enum DataDirective {
     Align(u32),
 }
 
-/// returned by parser
+impl DataDirective {
+
+    /// number of bytes the directive occupies in memory
+    pub fn size(&self) -> u32 {
+        match self {
+            DataDirective::Byte(_) => 1,
+            DataDirective::HalfWord(_) => 2,
+            DataDirective::Word(_) | DataDirective::Float(_) => 4,
+            DataDirective::Double(_) => 8,
+            DataDirective::Ascii(s) => s.len() as u32,
+            DataDirective::AsciiZero(s) => s.len() as u32 + 1,
+            DataDirective::Space(n) => *n,
+            DataDirective::Align(_) => 0,
+        }
+    }
+
+    /// contents of the directive in memory, words and half
+    /// words are stored little endian like MARS does
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            DataDirective::Byte(b) => vec![*b],
+            DataDirective::HalfWord(h) => h.to_le_bytes().to_vec(),
+            DataDirective::Word(w) => w.to_le_bytes().to_vec(),
+            DataDirective::Float(f) => f.to_le_bytes().to_vec(),
+            DataDirective::Double(d) => d.to_le_bytes().to_vec(),
+            DataDirective::Ascii(s) => s.as_bytes().to_vec(),
+            DataDirective::AsciiZero(s) => s.bytes().chain(std::iter::once(0)).collect(),
+            DataDirective::Space(n) => vec![0; *n as usize],
+            DataDirective::Align(_) => Vec::new(),
+        }
+    }
+
+    /// boundary the directive is placed on, .align n uses 2^n
+    pub fn alignment(&self) -> u32 {
+        match self {
+            DataDirective::HalfWord(_) => 2,
+            DataDirective::Word(_) | DataDirective::Float(_) => 4,
+            DataDirective::Double(_) => 8,
+            DataDirective::Align(n) => 1 << n,
+            _ => 1,
+        }
+    }
+
+}
+
+/// returned by parser, one per data directive line
+/// such as `arr: .word 1, 2, 3`, the label is optional
 #[derive(Debug, Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct DataMap {
-    name: String,
-    data: DataDirective,
+    name: Option<String>,
+    data: Vec<DataDirective>,
+    span: SourceSpan,
 }
 
 impl DataMap {
 
-    pub fn new(name: String, data: DataDirective) -> DataMap {
+    pub fn new(name: Option<String>, data: Vec<DataDirective>, span: SourceSpan) -> DataMap {
         DataMap {
             name,
             data,
+            span,
         }
     }
-        
+
+    pub fn span(&self) -> &SourceSpan {
+        &self.span
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn data(&self) -> &[DataDirective] {
+        &self.data
+    }
+
 }
 
 /// each memory write is tagged without 
@@ -65,10 +150,83 @@ impl DataMap {
 /// this tag is useful for debugging as the memory
 /// can be shown as strings, integers, floats, etc.
 /// and not just bytes.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub enum MemTag {
-    String,
+    Word,
+    HalfWord,
+    Byte,
+    Float,
+    Double,
+    Ascii,
+    // ascii string with null terminator
+    AsciiZero,
+    // reserved by .space, undefined until written
+    Space,
+}
+
+impl MemTag {
+
+    /// tag of the data a directive lays out, if it lays out any
+    pub fn of(directive: &DataDirective) -> Option<MemTag> {
+        match directive {
+            DataDirective::Word(_) => Some(MemTag::Word),
+            DataDirective::HalfWord(_) => Some(MemTag::HalfWord),
+            DataDirective::Byte(_) => Some(MemTag::Byte),
+            DataDirective::Float(_) => Some(MemTag::Float),
+            DataDirective::Double(_) => Some(MemTag::Double),
+            DataDirective::Ascii(_) => Some(MemTag::Ascii),
+            DataDirective::AsciiZero(_) => Some(MemTag::AsciiZero),
+            DataDirective::Space(_) => Some(MemTag::Space),
+            DataDirective::Align(_) => None,
+        }
+    }
+
+    /// the directive that lays out data with this tag
+    pub fn directive(&self) -> &'static str {
+        match self {
+            MemTag::Word => ".word",
+            MemTag::HalfWord => ".half",
+            MemTag::Byte => ".byte",
+            MemTag::Float => ".float",
+            MemTag::Double => ".double",
+            MemTag::Ascii => ".ascii",
+            MemTag::AsciiZero => ".asciiz",
+            MemTag::Space => ".space",
+        }
+    }
+
+    /// bytes taken up by a single value, strings and
+    /// `.space` are made of bytes
+    pub fn size(&self) -> u32 {
+        match self {
+            MemTag::Word | MemTag::Float => 4,
+            MemTag::HalfWord => 2,
+            MemTag::Double => 8,
+            MemTag::Byte | MemTag::Ascii | MemTag::AsciiZero | MemTag::Space => 1,
+        }
+    }
+
+}
+
+/// what a directive of the data section placed in memory
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct DataTag {
+    pub tag: MemTag,
+    // addresses the directive takes up
+    pub range: Range<u32>,
+    // label of the line the directive is on
+    pub label: Option<String>,
+}
+
+/// a value that looks random but only depends on the seed and x
+pub fn scramble(seed: u64, x: u64) -> u64 {
+    // splitmix64
+    let mut z = seed.wrapping_add(x.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
 /// represents the memory of the system
@@ -78,7 +236,24 @@ pub enum MemTag {
 #[derive(Serialize, Deserialize)]
 pub struct Memory {
     data: Vec<u8>,
-    tags: BTreeMap<usize, Option<MemTag>>,
+    // keyed by the address each directive starts at
+    tags: BTreeMap<u32, DataTag>,
+    // whether each byte of the data section was initialized or written,
+    // padding and .space are not
+    defined: Vec<bool>,
+    // bytes written outside the data section, such as the stack,
+    // only these are defined
+    sparse: BTreeMap<u32, u8>,
+    // undefined bytes read as noise derived from the seed, not zero
+    seed: Option<u64>,
+    // addresses taken up by each labelled line of the data section
+    labels: HashMap<String, Range<u32>>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
@@ -87,6 +262,10 @@ impl Memory {
         Memory {
             data: Vec::new(),
             tags: BTreeMap::new(),
+            defined: Vec::new(),
+            sparse: BTreeMap::new(),
+            seed: None,
+            labels: HashMap::new(),
         }
     }
 
@@ -94,20 +273,228 @@ impl Memory {
         self.data.len()
     }
 
-    pub fn tag(&mut self, addr: usize, tag: MemTag) {
-        self.tags.insert(addr, Some(tag));
+    pub fn tag(&mut self, tag: DataTag) {
+        self.tags.insert(tag.range.start, tag);
+    }
+
+    /// the directive that placed the byte at addr, if any
+    pub fn tag_at(&self, addr: u32) -> Option<&DataTag> {
+        self.tags.range(..=addr).next_back().map(|(_, tag)| tag).filter(|tag| tag.range.contains(&addr))
+    }
+
+    /// directives that placed data within the range, in address order
+    pub fn tags_in(&self, range: Range<u32>) -> impl Iterator<Item = &DataTag> {
+        let first = self.tag_at(range.start).map_or(range.start, |tag| tag.range.start);
+        self.tags.range(first..range.end).map(|(_, tag)| tag).filter(|tag| !tag.range.is_empty())
     }
 
     pub fn write(&mut self, addr: usize, data: &[u8]) {
-        let mut i = 0;
-        for byte in data {
+        for (i, byte) in data.iter().enumerate() {
             self.data[addr + i] = *byte;
-            i += 1;
         }
     }
 
+    /// byte at an offset into the data section along with
+    /// the tag of the directive that placed it
     pub fn read(&self, addr: usize) -> (u8, Option<MemTag>) {
-        (self.data[addr], self.tags.get(&addr).unwrap().clone())
+        let tag = self.tag_at(DATA_SEGMENT_BASE + addr as u32).map(|tag| tag.tag);
+        (self.data[addr], tag)
+    }
+
+    /// lays out the data section at the addresses the parser
+    /// gave its labels, each line aligned by its first directive
+    pub fn load_data(&mut self, data: &[DataMap]) {
+        for map in data {
+            let alignment = map.data().first().map_or(1, |d| d.alignment()) as usize;
+            self.data.resize(self.data.len().div_ceil(alignment) * alignment, 0);
+            let start = DATA_SEGMENT_BASE + self.data.len() as u32;
+            for directive in map.data() {
+                // alignment padding is undefined
+                self.defined.resize(self.data.len(), false);
+                let addr = DATA_SEGMENT_BASE + self.data.len() as u32;
+                if let Some(tag) = MemTag::of(directive) {
+                    let label = map.name().map(|name| name.to_string());
+                    self.tag(DataTag { tag, range: addr..addr + directive.size(), label });
+                }
+                self.data.extend(directive.to_bytes());
+                self.defined.resize(self.data.len(), !matches!(directive, DataDirective::Space(_)));
+            }
+            if let Some(name) = map.name() {
+                self.labels.insert(name.to_string(), start..DATA_SEGMENT_BASE + self.data.len() as u32);
+            }
+        }
+        self.defined.resize(self.data.len(), false);
+        if let Some(seed) = self.seed {
+            self.randomize(seed);
+        }
+    }
+
+    /// fills undefined bytes with noise derived from the seed,
+    /// bytes that are never written keep reading as the same noise
+    pub fn randomize(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        for (offset, byte) in self.data.iter_mut().enumerate() {
+            if !self.defined[offset] {
+                *byte = scramble(seed, DATA_SEGMENT_BASE as u64 + offset as u64) as u8;
+            }
+        }
+    }
+
+    /// whether all len bytes at addr were initialized or written
+    pub fn is_defined(&self, addr: u32, len: u32) -> bool {
+        (addr..addr.saturating_add(len)).all(|addr| match addr.checked_sub(DATA_SEGMENT_BASE).and_then(|offset| self.defined.get(offset as usize)) {
+            Some(defined) => *defined,
+            None => self.sparse.contains_key(&addr),
+        })
+    }
+
+    /// marks len bytes at addr as defined or not without changing
+    /// them, undefined bytes outside the data section are forgotten
+    pub fn set_defined(&mut self, addr: u32, len: u32, defined: bool) {
+        for addr in addr..addr.saturating_add(len) {
+            match addr.checked_sub(DATA_SEGMENT_BASE).and_then(|offset| self.defined.get_mut(offset as usize)) {
+                Some(bit) => *bit = defined,
+                None if defined => {
+                    let byte = self.get(addr);
+                    self.sparse.insert(addr, byte);
+                }
+                None => { self.sparse.remove(&addr); }
+            }
+        }
+    }
+
+    /// every data label with its addresses, in address order
+    pub fn labels(&self) -> Vec<(&str, Range<u32>)> {
+        let mut labels: Vec<(&str, Range<u32>)> = self.labels.iter().map(|(name, range)| (name.as_str(), range.clone())).collect();
+        labels.sort_by_key(|(_, range)| range.start);
+        labels
+    }
+
+    /// addresses of the data declared on the line of a data label
+    pub fn label(&self, name: &str) -> Option<Range<u32>> {
+        self.labels.get(name).cloned()
+    }
+
+    /// checks that len bytes at addr are aligned and addressable,
+    /// which is anything from the data segment up to the stack
+    fn check(addr: u32, len: u32) -> Result<(), MachineException> {
+        let end = addr.checked_add(len).ok_or(MachineException::AddressError)?;
+        if !addr.is_multiple_of(len) || addr < DATA_SEGMENT_BASE || end > STACK_POINTER + 4 {
+            return Err(MachineException::AddressError);
+        }
+        Ok(())
+    }
+
+    fn get(&self, addr: u32) -> u8 {
+        match self.data.get((addr - DATA_SEGMENT_BASE) as usize) {
+            Some(byte) => *byte,
+            None => match self.sparse.get(&addr) {
+                Some(byte) => *byte,
+                None => self.seed.map_or(0, |seed| scramble(seed, addr as u64) as u8),
+            },
+        }
+    }
+
+    fn set(&mut self, addr: u32, value: u8) {
+        let offset = (addr - DATA_SEGMENT_BASE) as usize;
+        match self.data.get_mut(offset) {
+            Some(byte) => {
+                *byte = value;
+                self.defined[offset] = true;
+            }
+            None => { self.sparse.insert(addr, value); }
+        }
+    }
+
+    pub fn read_byte(&self, addr: u32) -> Result<u8, MachineException> {
+        Memory::check(addr, 1)?;
+        Ok(self.get(addr))
+    }
+
+    pub fn write_byte(&mut self, addr: u32, value: u8) -> Result<(), MachineException> {
+        Memory::check(addr, 1)?;
+        self.set(addr, value);
+        Ok(())
+    }
+
+    pub fn read_word(&self, addr: u32) -> Result<u32, MachineException> {
+        Memory::check(addr, 4)?;
+        Ok(u32::from_le_bytes([0, 1, 2, 3].map(|i| self.get(addr + i))))
+    }
+
+    pub fn write_word(&mut self, addr: u32, value: u32) -> Result<(), MachineException> {
+        Memory::check(addr, 4)?;
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            self.set(addr + i as u32, byte);
+        }
+        Ok(())
+    }
+
+    /// the data section for a snapshot, the seed, the bytes and a bit
+    /// per byte set if it is defined, undefined bytes are kept too as
+    /// they may hold noise
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut section = vec![self.seed.is_some() as u8];
+        section.extend(self.seed.unwrap_or_default().to_le_bytes());
+        put_bytes(&mut section, &self.data);
+        let defined: Vec<u8> = self.defined.chunks(8)
+            .map(|bits| bits.iter().enumerate().fold(0, |byte, (i, bit)| byte | (*bit as u8) << i))
+            .collect();
+        put_bytes(&mut section, &defined);
+        section
+    }
+
+    /// restores the data section saved by `save_data`, the program
+    /// must have laid out a data section of the same size
+    pub fn restore_data(&mut self, fields: &mut Fields) -> std::io::Result<()> {
+        let seeded = fields.bool()?;
+        let seed = fields.u64()?;
+        let data = fields.bytes()?;
+        let defined = fields.bytes()?;
+        if data.len() != self.data.len() || defined.len() != data.len().div_ceil(8) {
+            return Err(fields.error("a data section of another size than the program's"));
+        }
+        self.seed = seeded.then_some(seed);
+        self.data.copy_from_slice(data);
+        for (offset, bit) in self.defined.iter_mut().enumerate() {
+            *bit = defined[offset / 8] & 1 << (offset % 8) != 0;
+        }
+        Ok(())
+    }
+
+    /// bytes written outside the data section for a snapshot, such
+    /// as the stack, as the number of runs of consecutive addresses
+    /// and then each run as its first address and its bytes
+    pub fn save_dynamic(&self) -> Vec<u8> {
+        let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (addr, byte) in &self.sparse {
+            match runs.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() as u32 == *addr => bytes.push(*byte),
+                _ => runs.push((*addr, vec![*byte])),
+            }
+        }
+        let mut section = (runs.len() as u32).to_le_bytes().to_vec();
+        for (start, bytes) in runs {
+            section.extend(start.to_le_bytes());
+            put_bytes(&mut section, &bytes);
+        }
+        section
+    }
+
+    /// restores the bytes saved by `save_dynamic`
+    pub fn restore_dynamic(&mut self, fields: &mut Fields) -> std::io::Result<()> {
+        let end = DATA_SEGMENT_BASE + self.data.len() as u32;
+        self.sparse.clear();
+        for _ in 0..fields.u32()? {
+            let start = fields.u32()?;
+            for (i, byte) in fields.bytes()?.iter().enumerate() {
+                let addr = start.checked_add(i as u32)
+                    .filter(|addr| *addr >= end && Memory::check(*addr, 1).is_ok())
+                    .ok_or_else(|| fields.error("a byte outside memory"))?;
+                self.sparse.insert(addr, *byte);
+            }
+        }
+        Ok(())
     }
 
 }
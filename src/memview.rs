@@ -0,0 +1,213 @@
+use std::ops::Range;
+
+use crate::expr::{parse_expr, SymbolTable};
+use crate::memory::{MemTag, Memory, DATA_SEGMENT_BASE};
+
+/// bytes shown on each row of the viewer
+const ROW_BYTES: u32 = 16;
+
+/// how the memory viewer renders values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViewFormat {
+    // by the directive that placed the data, hex if there is none
+    Auto,
+    Hex,
+    Signed,
+    Unsigned,
+    Float,
+    String,
+}
+
+impl std::str::FromStr for ViewFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ViewFormat::Auto),
+            "hex" | "x" => Ok(ViewFormat::Hex),
+            "signed" | "d" => Ok(ViewFormat::Signed),
+            "unsigned" | "u" => Ok(ViewFormat::Unsigned),
+            "float" | "f" => Ok(ViewFormat::Float),
+            "string" | "s" => Ok(ViewFormat::String),
+            _ => Err(format!("unknown format: {s}, expected auto, hex, signed, unsigned, float or string")),
+        }
+    }
+}
+
+/// the addresses `target` refers to: a data label, `start..end`,
+/// a single word at an address, or the whole data section if empty,
+/// addresses are constant expressions over the symbols
+pub fn resolve_range(memory: &Memory, symbols: &SymbolTable, target: &str) -> Result<Range<u32>, String> {
+    let evaluate = |src: &str| parse_expr(src).and_then(|e| e.eval(symbols)).map(|v| v as u32);
+    let target = target.trim();
+    if target.is_empty() {
+        return Ok(DATA_SEGMENT_BASE..DATA_SEGMENT_BASE + memory.last() as u32);
+    }
+    if let Some(range) = memory.label(target) {
+        return Ok(range);
+    }
+    let target = target.strip_prefix('*').unwrap_or(target);
+    let range = match target.split_once("..") {
+        Some((start, end)) => evaluate(start)?..evaluate(end)?,
+        None => evaluate(target).map(|start| start..start.wrapping_add(4))?,
+    };
+    if range.is_empty() {
+        return Err(format!("empty address range: {target}"));
+    }
+    Ok(range)
+}
+
+/// renders memory like the data segment window of MARS, each data
+/// label is shown above the row its data starts on and values are
+/// grouped by the directive that placed them, e.g.
+///
+/// ```text
+/// arr:
+/// 0x10010000  .word    1, 2, -3
+/// ```
+pub fn view(memory: &Memory, range: Range<u32>, format: ViewFormat) -> Result<String, String> {
+    let mut lines = Vec::new();
+    let mut tags = memory.tags_in(range.clone()).peekable();
+    let mut addr = range.start;
+    while addr < range.end {
+        let Some(first) = tags.next_if(|tag| tag.range.start <= addr) else {
+            // memory no directive placed, such as padding or the stack
+            let end = tags.peek().map_or(range.end, |tag| tag.range.start.min(range.end));
+            lines.extend(rows(memory, addr..end, None, format)?);
+            addr = end;
+            continue;
+        };
+        // values on the same line of source are shown together
+        let mut end = first.range.end;
+        if !matches!(first.tag, MemTag::Ascii | MemTag::AsciiZero) {
+            while let Some(next) = tags.next_if(|next| next.range.start == end && next.tag == first.tag && next.label == first.label) {
+                end = next.range.end;
+            }
+        }
+        if let Some(label) = &first.label {
+            if first.range.start >= range.start && memory.label(label).is_some_and(|r| r.start == first.range.start) {
+                lines.push(format!("{label}:"));
+            }
+        }
+        let end = end.min(range.end);
+        lines.extend(rows(memory, addr..end, Some(first.tag), format)?);
+        addr = end;
+    }
+    Ok(lines.join("\n"))
+}
+
+/// rows showing data placed by one kind of directive
+fn rows(memory: &Memory, range: Range<u32>, tag: Option<MemTag>, format: ViewFormat) -> Result<Vec<String>, String> {
+    let bytes = range.clone()
+        .map(|addr| memory.read_byte(addr).map_err(|e| format!("{e} at 0x{addr:08x}")))
+        .collect::<Result<Vec<u8>, String>>()?;
+    let directive = tag.map_or("", |tag| tag.directive());
+
+    let string = matches!(format, ViewFormat::String)
+        || matches!((format, tag), (ViewFormat::Auto, Some(MemTag::Ascii | MemTag::AsciiZero)));
+    if string {
+        let chunks = bytes.chunks(ROW_BYTES as usize * 4);
+        return Ok(chunks.zip((range.start..).step_by(ROW_BYTES as usize * 4))
+            .map(|(chunk, addr)| format!("0x{addr:08x}  {directive:<8} \"{}\"", escape(chunk)))
+            .collect());
+    }
+
+    let size = match (format, tag) {
+        (ViewFormat::Float, Some(MemTag::Double)) => 8,
+        (ViewFormat::Float, _) => 4,
+        (_, Some(tag @ (MemTag::Word | MemTag::HalfWord | MemTag::Byte | MemTag::Float | MemTag::Double))) => tag.size(),
+        _ if range.start.is_multiple_of(4) && bytes.len().is_multiple_of(4) => 4,
+        _ => 1,
+    };
+    let mut rows = Vec::new();
+    for (offset, row) in bytes.chunks(ROW_BYTES as usize).enumerate() {
+        let values: Vec<String> = row.chunks(size as usize)
+            .map(|value| match value.len() == size as usize {
+                true => render(value, tag, format),
+                // cut off by the end of the range
+                false => value.iter().map(|b| format!("0x{b:02x}")).collect::<Vec<_>>().join(", "),
+            })
+            .collect();
+        let addr = range.start + offset as u32 * ROW_BYTES;
+        rows.push(format!("0x{addr:08x}  {directive:<8} {}", values.join(", ")));
+    }
+    Ok(rows)
+}
+
+/// a little endian value of 1, 2, 4 or 8 bytes
+fn render(bytes: &[u8], tag: Option<MemTag>, format: ViewFormat) -> String {
+    let mut le = [0; 8];
+    le[..bytes.len()].copy_from_slice(bytes);
+    let raw = u64::from_le_bytes(le);
+    let bits = bytes.len() as u32 * 8;
+    let format = match (format, tag) {
+        (ViewFormat::Auto, Some(MemTag::Word | MemTag::HalfWord | MemTag::Byte)) => ViewFormat::Signed,
+        (ViewFormat::Auto, Some(MemTag::Float | MemTag::Double)) => ViewFormat::Float,
+        (ViewFormat::Auto, _) => ViewFormat::Hex,
+        (format, _) => format,
+    };
+    match format {
+        ViewFormat::Signed => (((raw << (64 - bits)) as i64) >> (64 - bits)).to_string(),
+        ViewFormat::Unsigned => raw.to_string(),
+        ViewFormat::Float if bits == 64 => format!("{:?}", f64::from_bits(raw)),
+        ViewFormat::Float => format!("{:?}", f32::from_bits(raw as u32)),
+        _ => format!("0x{raw:0width$x}", width = bytes.len() * 2),
+    }
+}
+
+fn escape(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| match b {
+        0 => "\\0".to_string(),
+        b => std::ascii::escape_default(*b).to_string(),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse_source;
+
+    fn memory(src: &str) -> (Memory, SymbolTable) {
+        let program = parse_source("prog.s", src).unwrap();
+        let mut memory = Memory::new();
+        memory.load_data(&program.data);
+        (memory, program.symbols)
+    }
+
+    #[test]
+    fn test_view() {
+        let (memory, symbols) = memory("\
+        .data
+msg:    .asciiz \"hi\\n\"
+arr:    .word 1, -2, 3
+        .half 7
+f:      .float 1.5, -2.0
+d:      .double 0.25
+buf:    .space 4
+");
+        let all = resolve_range(&memory, &symbols, "").unwrap();
+        assert_eq!(view(&memory, all, ViewFormat::Auto).unwrap(), "\
+msg:
+0x10010000  .asciiz  \"hi\\n\\0\"
+arr:
+0x10010004  .word    1, -2, 3
+0x10010010  .half    7
+0x10010012           0x00, 0x00
+f:
+0x10010014  .float   1.5, -2.0
+0x1001001c           0x00000000
+d:
+0x10010020  .double  0.25
+buf:
+0x10010028  .space   0x00000000");
+
+        let arr = resolve_range(&memory, &symbols, "arr").unwrap();
+        assert_eq!(view(&memory, arr.clone(), ViewFormat::Hex).unwrap(), "arr:\n0x10010004  .word    0x00000001, 0xfffffffe, 0x00000003");
+        assert_eq!(view(&memory, arr, ViewFormat::Unsigned).unwrap(), "arr:\n0x10010004  .word    1, 4294967294, 3");
+        let range = resolve_range(&memory, &symbols, "*arr+4..arr+8").unwrap();
+        assert_eq!(view(&memory, range, ViewFormat::Auto).unwrap(), "0x10010008  .word    -2");
+        assert_eq!(view(&memory, 0x10010000..0x10010002, ViewFormat::Auto).unwrap(), "msg:\n0x10010000  .asciiz  \"hi\"");
+        assert!(resolve_range(&memory, &symbols, "nope").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use serde::{Serialize, Deserialize};
+
+use crate::debug_table::MachineException;
+use crate::snapshot::Fields;
+
+/// registers of the keyboard and display, laid out like MARS's
+/// Keyboard and Display MMIO Simulator
+pub const RECEIVER_CONTROL: u32 = 0xffff0000;
+pub const RECEIVER_DATA: u32 = 0xffff0004;
+pub const TRANSMITTER_CONTROL: u32 = 0xffff0008;
+pub const TRANSMITTER_DATA: u32 = 0xffff000c;
+
+/// interrupt pending bits of the CP0 Cause register
+pub const KEYBOARD_INTERRUPT: u32 = 1 << 8;
+pub const DISPLAY_INTERRUPT: u32 = 1 << 9;
+
+const READY: u32 = 1;
+const INTERRUPT_ENABLE: u32 = 1 << 1;
+
+/// whether addr is in the memory mapped I/O region
+pub fn is_mmio(addr: u32) -> bool {
+    addr >= RECEIVER_CONTROL
+}
+
+/// a keyboard fed from the console input and a display writing to
+/// the console output, the display is ready again as soon as a
+/// character is written
+#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct KeyboardDisplay {
+    // byte typed that the data register was not read for yet
+    received: Option<u8>,
+    // the console has no more input
+    exhausted: bool,
+    keyboard_interrupts: bool,
+    display_interrupts: bool,
+    // a character was written while display interrupts were enabled
+    written: bool,
+}
+
+impl KeyboardDisplay {
+
+    pub fn new() -> KeyboardDisplay {
+        KeyboardDisplay::default()
+    }
+
+    /// whether the keyboard can take another byte
+    pub fn wants_input(&self) -> bool {
+        self.received.is_none() && !self.exhausted
+    }
+
+    /// whether a byte is only needed to raise an interrupt
+    pub fn keyboard_interrupts(&self) -> bool {
+        self.keyboard_interrupts
+    }
+
+    /// a byte typed, None at the end of input
+    pub fn receive(&mut self, byte: Option<u8>) {
+        match byte {
+            Some(byte) => self.received = Some(byte),
+            None => self.exhausted = true,
+        }
+    }
+
+    /// value of a register, None if addr is not one
+    pub fn read(&mut self, addr: u32) -> Option<u32> {
+        let enabled = |on: bool| if on { INTERRUPT_ENABLE } else { 0 };
+        match addr {
+            RECEIVER_CONTROL => Some(self.received.map_or(0, |_| READY) | enabled(self.keyboard_interrupts)),
+            // reading the data takes it
+            RECEIVER_DATA => Some(self.received.take().unwrap_or_default() as u32),
+            TRANSMITTER_CONTROL => Some(READY | enabled(self.display_interrupts)),
+            TRANSMITTER_DATA => Some(0),
+            _ => None,
+        }
+    }
+
+    /// writes a register, returns the character to display if any,
+    /// only the interrupt enable bits of the control registers can
+    /// be written
+    pub fn write(&mut self, addr: u32, value: u32) -> Result<Option<u8>, MachineException> {
+        match addr {
+            RECEIVER_CONTROL => self.keyboard_interrupts = value & INTERRUPT_ENABLE != 0,
+            TRANSMITTER_CONTROL => self.display_interrupts = value & INTERRUPT_ENABLE != 0,
+            TRANSMITTER_DATA => {
+                self.written = self.display_interrupts;
+                return Ok(Some(value as u8));
+            }
+            RECEIVER_DATA => {}
+            _ => return Err(MachineException::AddressError),
+        }
+        Ok(None)
+    }
+
+    /// interrupts the devices are raising, as Cause bits
+    pub fn pending(&self) -> u32 {
+        let keyboard = self.keyboard_interrupts && self.received.is_some();
+        (if keyboard { KEYBOARD_INTERRUPT } else { 0 }) | (if self.written { DISPLAY_INTERRUPT } else { 0 })
+    }
+
+    /// the devices for a snapshot, whether a byte was received and the
+    /// byte, then whether the input is exhausted, the interrupts that
+    /// are enabled and whether a character raised one
+    pub fn save(&self) -> Vec<u8> {
+        vec![
+            self.received.is_some() as u8,
+            self.received.unwrap_or_default(),
+            self.exhausted as u8,
+            self.keyboard_interrupts as u8,
+            self.display_interrupts as u8,
+            self.written as u8,
+        ]
+    }
+
+    pub fn restore(fields: &mut Fields) -> std::io::Result<KeyboardDisplay> {
+        let received = fields.bool()?;
+        let byte = fields.u8()?;
+        Ok(KeyboardDisplay {
+            received: received.then_some(byte),
+            exhausted: fields.bool()?,
+            keyboard_interrupts: fields.bool()?,
+            display_interrupts: fields.bool()?,
+            written: fields.bool()?,
+        })
+    }
+
+    /// the display raises one interrupt per character, the keyboard
+    /// keeps raising its own until the data register is read
+    pub fn acknowledge(&mut self) {
+        self.written = false;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_keyboard_display() {
+        let mut devices = KeyboardDisplay::new();
+        assert_eq!(devices.read(RECEIVER_CONTROL), Some(0));
+        assert!(devices.wants_input());
+        devices.receive(Some(b'a'));
+        assert!(!devices.wants_input());
+        assert_eq!(devices.read(RECEIVER_CONTROL), Some(1));
+        assert_eq!(devices.read(RECEIVER_DATA), Some(b'a' as u32));
+        assert_eq!(devices.read(RECEIVER_CONTROL), Some(0));
+        devices.receive(None);
+        assert!(!devices.wants_input());
+
+        assert_eq!(devices.read(TRANSMITTER_CONTROL), Some(1));
+        assert_eq!(devices.write(TRANSMITTER_DATA, 0x148).unwrap(), Some(b'H'));
+        assert_eq!(devices.pending(), 0);
+        assert!(devices.write(0xffff0010, 0).is_err());
+
+        devices.write(TRANSMITTER_CONTROL, 3).unwrap();
+        devices.write(RECEIVER_CONTROL, 2).unwrap();
+        assert_eq!(devices.read(TRANSMITTER_CONTROL), Some(3));
+        devices.write(TRANSMITTER_DATA, b'i' as u32).unwrap();
+        devices.receive(Some(b'b'));
+        assert_eq!(devices.pending(), KEYBOARD_INTERRUPT | DISPLAY_INTERRUPT);
+        devices.acknowledge();
+        assert_eq!(devices.pending(), KEYBOARD_INTERRUPT);
+        devices.read(RECEIVER_DATA);
+        assert_eq!(devices.pending(), 0);
+
+        let mut snapshot = crate::snapshot::Snapshot::new();
+        snapshot.add(b"DEVS", devices.save());
+        assert_eq!(KeyboardDisplay::restore(&mut snapshot.fields(b"DEVS").unwrap()).unwrap(), devices);
+    }
+}
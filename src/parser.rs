@@ -1,23 +1,112 @@
-use nom::{
-    IResult,
-    error::ParseError,
-    sequence::{pair, tuple},
-    bytes::complete::is_not, character::complete::char, combinator::{map, recognize}, multi::separated_list1,
-};
+use std::collections::{HashMap, HashSet};
+
+use nom::error::ParseError;
 use nom_locate::LocatedSpan;
 
-use crate::{parser_utils::{check_argument_counts, ensure_register}, memory::{DataMap, DataDirective}};
+use crate::{parser_utils::{check_argument_counts, check_symbols, cp0_register, ensure_register, parse_immediate, undefined_symbol}, memory::{DataMap, DataDirective, DATA_SEGMENT_BASE, TEXT_SEGMENT_BASE}};
+use crate::expr::{identifier, parse_expr, Field, SymbolKind, SymbolTable};
+use crate::lexer::{Lexer, Lexeme, Token};
+use crate::bytecode::WhereTo;
+use crate::debug_table::SourceSpan;
+use crate::registers::register_to_addr;
 
 use super::bytecode::AsmInstruction;
 use super::err_util::map_parse_error;
 
 type Span<'a> = LocatedSpan<&'a str>;
+
+/// identifies the kind of an assembler error, shown as `error[E0003]`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorCode {
+    #[default]
+    Syntax,
+    UnknownInstruction,
+    InvalidRegister,
+    OperandCount,
+    InvalidExpression,
+    UndefinedSymbol,
+    DuplicateSymbol,
+    UnknownDirective,
+    WrongSection,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "E{:04}", *self as u32 + 1)
+    }
+}
+
+/// suspicious but legal code, each kind can be allowed or
+/// denied on its own by the name it is displayed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    ZeroWrite,
+    // `$at` is used by the assembler when expanding pseudo-instructions,
+    // which are always enabled
+    AtRegister,
+    KernelRegister,
+    TruncatedImmediate,
+    UnreachableCode,
+    UnusedLabel,
+    DataInText,
+}
+
+impl WarningKind {
+    pub const ALL: [WarningKind; 7] = [
+        WarningKind::ZeroWrite,
+        WarningKind::AtRegister,
+        WarningKind::KernelRegister,
+        WarningKind::TruncatedImmediate,
+        WarningKind::UnreachableCode,
+        WarningKind::UnusedLabel,
+        WarningKind::DataInText,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::ZeroWrite => "zero-write",
+            WarningKind::AtRegister => "at-register",
+            WarningKind::KernelRegister => "kernel-register",
+            WarningKind::TruncatedImmediate => "truncated-immediate",
+            WarningKind::UnreachableCode => "unreachable-code",
+            WarningKind::UnusedLabel => "unused-label",
+            WarningKind::DataInText => "data-in-text",
+        }
+    }
+}
+
+impl std::fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for WarningKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        WarningKind::ALL.into_iter().find(|k| k.name() == s).ok_or(format!("unknown warning: {s}"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmWarning {
+    pub line: u32,
+    pub column: usize,
+    pub input: String,
+    pub msg: String,
+    pub kind: WarningKind,
+}
+
 #[derive(Debug, Clone)]
 pub struct ParserVerboseError {
     pub line: u32,
     pub column: usize,
     pub input: String,
     pub msg: String,
+    pub code: ErrorCode,
+    // a known name close to the one that is wrong, shown as help
+    pub suggestion: Option<String>,
 }
 
 impl<'a> ParseError<Span<'a>> for ParserVerboseError {
@@ -27,6 +116,8 @@ impl<'a> ParseError<Span<'a>> for ParserVerboseError {
             column: input.get_column(),
             input: input.fragment().to_string(),
             msg: kind.description().to_string(),
+            code: ErrorCode::Syntax,
+            suggestion: None,
         }
     }
 
@@ -36,6 +127,8 @@ impl<'a> ParseError<Span<'a>> for ParserVerboseError {
             column: input.get_column(),
             input: input.fragment().to_string(),
             msg: format!("{}: {}", kind.description(), other.msg),
+            code: other.code,
+            suggestion: None,
         }
     }
 }
@@ -49,184 +142,626 @@ impl From<nom::Err<ParserVerboseError>> for ParserVerboseError {
     }
 }
 
-/// mips supports # comments only
-fn eol_comment<'a>(i: Span<'a>) -> IResult<Span<'a>, String, ParserVerboseError> {
-    let (i, (_, comment)) = pair(char('#'), is_not("\r\n"))(i).map_err(|_: nom::Err<nom::error::Error<Span<'a>>>| {
-        nom::Err::Failure(ParserVerboseError {
-            line: i.location_line(),
-            column: i.get_column(),
-            input: i.fragment().to_string(),
-            msg: "failed to parse comment".to_string(),
-        })
-    })?;
-    Ok((i, comment.fragment().to_string()))
+/// an assembly instruction along with where it was written
+/// and the label of the block of code it belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedInstruction {
+    pub asm_ins: AsmInstruction,
+    pub span: SourceSpan,
+    pub label: String,
+}
+
+/// result of parsing a whole source file
+#[derive(Debug)]
+pub struct ParsedProgram {
+    pub instructions: Vec<ParsedInstruction>,
+    pub data: Vec<DataMap>,
+    pub symbols: SymbolTable,
+    // text label to the index of the instruction it points at
+    pub text_labels: HashMap<String, usize>,
+    // index of the first instruction in .ktext, where interrupts go
+    pub exception_handler: Option<usize>,
+    // ordered by position
+    pub warnings: Vec<AsmWarning>,
+    // what the program was assembled from
+    pub file_name: String,
+    pub source: String,
+}
+
+impl ParsedProgram {
+
+    pub fn asm_instructions(&self) -> Vec<AsmInstruction> {
+        self.instructions.iter().map(|i| i.asm_ins.clone()).collect()
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Text,
+    Data,
 }
 
-/// consumes all whitespace characters
-fn consume_whitespace<'a>(i: Span<'a>) -> IResult<Span<'a>, (), ParserVerboseError> {
-    let (i, _) = nom::character::complete::multispace0(i)?;
-    Ok((i, ()))
+/// one line of tokens in the form `labels: head operands`
+#[derive(Debug, Clone)]
+struct Statement<'a> {
+    labels: Vec<Lexeme<'a>>,
+    head: Option<Lexeme<'a>>,
+    operands: Vec<Lexeme<'a>>,
 }
 
-/// function to parse a line of data section
-fn parse_data<'a>(i: Span<'a>) -> IResult<Span<'a>, DataMap, ParserVerboseError> {
-    let stripped_src = consume_whitespace(i)?.0;
+impl<'a> Statement<'a> {
+
+    /// span of the directive or mnemonic, used to locate errors
+    fn span(&self) -> Span<'a> {
+        self.head.as_ref().map(|h| h.span).unwrap_or_else(|| self.labels[0].span)
+    }
+
+    fn arguments(&self) -> Vec<String> {
+        self.operands.iter().map(|o| o.span.fragment().to_string()).collect()
+    }
+
+    /// the statement from its head to its last operand
+    fn source_span(&self, file: &str, src: &str) -> SourceSpan {
+        let start = self.span();
+        let end = self.operands.last().map(|o| o.span).unwrap_or(start);
+        let text = &src[start.location_offset()..end.location_offset() + end.fragment().len()];
+        SourceSpan {
+            file: file.to_string(),
+            line: start.location_line(),
+            column: start.get_column(),
+            text: text.to_string(),
+        }
+    }
 
-    // consume `.data` directive
-    let (stripped_src, _) = recognize(tuple((char('.'), is_not("\r\n"), consume_whitespace)))(stripped_src)?;
+}
 
-    // all data is in the format
-    // name: .type value
-    // where type is .word, .asciiz, etc.
+fn statement_error(i: Span, code: ErrorCode, msg: String) -> nom::Err<ParserVerboseError> {
+    nom::Err::Failure(ParserVerboseError {
+        line: i.location_line(),
+        column: i.get_column(),
+        input: i.fragment().to_string(),
+        msg,
+        code,
+        suggestion: None,
+    })
+}
+
+fn statement_warning(i: Span, kind: WarningKind, msg: String) -> AsmWarning {
+    AsmWarning {
+        line: i.location_line(),
+        column: i.get_column(),
+        input: i.fragment().to_string(),
+        msg,
+        kind,
+    }
+}
 
-    // parse label name
-    let (remaining, name) = recognize(tuple((is_not(":"), consume_whitespace)))(stripped_src)?;
-    let (remaining, _) = char(':')(remaining)?;
-    let name = name.fragment().trim().to_string();
+/// groups the tokens of a line into a statement, checking
+/// that operands are separated by exactly one comma
+fn split_statement<'a>(line: &[Lexeme<'a>]) -> Result<Statement<'a>, nom::Err<ParserVerboseError>> {
+    let mut tokens = line.iter().peekable();
 
-    // parse data type
-    let (remaining, data_type) = recognize(tuple((is_not(" "), consume_whitespace)))(remaining)?;
-    let data_type = data_type.fragment().trim().to_string();
+    let mut labels = Vec::new();
+    while let Some(label) = tokens.next_if(|l| matches!(l.token, Token::LABEL(_))) {
+        labels.push(label.clone());
+    }
+    let head = tokens.next_if(|l| matches!(l.token, Token::DIRECTIVE(_) | Token::INSTRUCTION(_))).cloned();
 
-    // parse value
-    let (remaining, value) = recognize(tuple((is_not("\r\n"), consume_whitespace)))(remaining)?;
-    let value = value.fragment().trim().to_string();
+    let mut operands = Vec::new();
+    let mut expect_operand = true;
+    for lexeme in tokens {
+        match (expect_operand, &lexeme.token) {
+            (true, Token::COMMA) => return Err(statement_error(lexeme.span, ErrorCode::Syntax, "expected operand before ,".to_string())),
+            (false, Token::COMMA) => expect_operand = true,
+            (true, _) => {
+                operands.push(lexeme.clone());
+                expect_operand = false;
+            }
+            (false, _) => return Err(statement_error(lexeme.span, ErrorCode::Syntax, format!("expected , before {}", lexeme.span.fragment()))),
+        }
+    }
+    if expect_operand && !operands.is_empty() {
+        return Err(statement_error(operands.last().unwrap().span, ErrorCode::Syntax, "expected operand after ,".to_string()));
+    }
 
-    let remaining = consume_whitespace(remaining)?.0;
-    match data_type.as_str() {
-        ".asciiz" => {
-            Ok((remaining, DataMap::new(name, DataDirective::AsciiZero(value))))
-        },
-        // else return error
-        _ => Err(nom::Err::Failure(ParserVerboseError {
-            line: i.location_line(),
-            column: i.get_column(),
-            input: i.fragment().to_string(),
-            msg: format!("invalid data type: {data_type}"),
-        })),
+    Ok(Statement { labels, head, operands })
+}
+
+/// `.eqv NAME value` and `.set NAME, value` are evaluated in
+/// order, a `.set` symbol may be redefined and each use sees
+/// the value in force where it appears, returns the name and value
+fn define_constant<'a>(stmt: &Statement<'a>, kind: SymbolKind, symbols: &mut SymbolTable) -> Result<(&'a str, i64), nom::Err<ParserVerboseError>> {
+    let definition = stmt.operands.first().ok_or(statement_error(stmt.span(), ErrorCode::Syntax, "expected symbol name".to_string()))?;
+    let (rest, name) = identifier(definition.span.fragment())
+        .map_err(|_| statement_error(definition.span, ErrorCode::Syntax, format!("expected symbol name, got {}", definition.span.fragment())))?;
+
+    let (value, value_span) = match (rest.trim(), stmt.operands.get(1)) {
+        ("", Some(value)) => (value.span.fragment().to_string(), value.span),
+        (value, None) if !value.is_empty() => (value.to_string(), definition.span),
+        _ => return Err(statement_error(definition.span, ErrorCode::OperandCount, format!("expected a single value for {name}"))),
+    };
+
+    check_symbols(&value, symbols, value_span)?;
+    let value = map_parse_error(value_span, ErrorCode::InvalidExpression, || parse_expr(&value).and_then(|e| e.eval(symbols)), None)?;
+    map_parse_error(definition.span, ErrorCode::DuplicateSymbol, || symbols.define(name, value, kind), None)?;
+    Ok((name, value))
+}
+
+/// `.half` and `.byte` values that only fit in a word are
+/// truncated to the low bits with a warning
+fn parse_truncated(text: &str, symbols: &SymbolTable, bits: u32, i: Span, warnings: &mut Vec<AsmWarning>) -> Result<i64, nom::Err<ParserVerboseError>> {
+    let value = parse_immediate(text, symbols, Field::Either(32), i)?;
+    if Field::Either(bits).fit(value).is_err() {
+        let truncated = value & ((1 << bits) - 1);
+        warnings.push(statement_warning(i, WarningKind::TruncatedImmediate, format!("value {value} is truncated to {bits} bits ({truncated})")));
+    }
+    Ok(value)
+}
+
+/// `.float` and `.double` values are plain literals such as `-1.5e3`
+fn parse_float(text: &str, i: Span) -> Result<f64, nom::Err<ParserVerboseError>> {
+    text.trim().parse::<f64>().map_err(|_| statement_error(i, ErrorCode::InvalidExpression, format!("expected a floating point number, got {text}")))
+}
+
+/// function to parse the values of a data directive
+fn parse_data(stmt: &Statement, symbols: &SymbolTable, warnings: &mut Vec<AsmWarning>) -> Result<Vec<DataDirective>, nom::Err<ParserVerboseError>> {
+    let head = stmt.head.as_ref().unwrap();
+    let data_type = head.span.fragment().to_string();
+
+    if stmt.operands.is_empty() {
+        return Err(statement_error(head.span, ErrorCode::OperandCount, format!("expected value after {data_type}")));
+    }
+
+    let mut directives = Vec::new();
+    for value in &stmt.operands {
+        let i = value.span;
+        let text = i.fragment();
+        let directive = match (data_type.as_str(), &value.token) {
+            (".asciiz", Token::STRING(s)) => DataDirective::AsciiZero(s.clone()),
+            (".ascii", Token::STRING(s)) => DataDirective::Ascii(s.clone()),
+            (".asciiz", _) | (".ascii", _) => return Err(statement_error(i, ErrorCode::InvalidExpression, format!("expected string literal, got {text}"))),
+            (".word", _) => DataDirective::Word(parse_immediate(text, symbols, Field::Either(32), i)? as u32),
+            (".half", _) => DataDirective::HalfWord(parse_truncated(text, symbols, 16, i, warnings)? as u16),
+            (".byte", _) => DataDirective::Byte(parse_truncated(text, symbols, 8, i, warnings)? as u8),
+            (".float", _) => DataDirective::Float(parse_float(text, i)? as f32),
+            (".double", _) => DataDirective::Double(parse_float(text, i)?),
+            (".space", _) => DataDirective::Space(parse_immediate(text, symbols, Field::Unsigned(32), i)? as u32),
+            (".align", _) => DataDirective::Align(parse_immediate(text, symbols, Field::Unsigned(2), i)? as u32),
+            // else return error
+            _ => return Err(statement_error(head.span, ErrorCode::UnknownDirective, format!("invalid data type: {data_type}"))),
+        };
+        directives.push(directive);
     }
+
+    Ok(directives)
+}
+
+/// every supported mnemonic with its operand form, used for
+/// usage hints and suggestions
+pub const INSTRUCTIONS: &[(&str, &str)] = &[
+    ("li", "li $rt, imm"),
+    ("la", "la $rt, address"),
+    ("add", "add $rd, $rs, $rt"),
+    ("lw", "lw $rt, offset($base)"),
+    ("sw", "sw $rt, offset($base)"),
+    ("j", "j label"),
+    ("jal", "jal label"),
+    ("jalr", "jalr [$rd,] $rs"),
+    ("jr", "jr $rs"),
+    ("syscall", "syscall"),
+    ("mfc0", "mfc0 $rt, $rd"),
+    ("mtc0", "mtc0 $rt, $rd"),
+    ("eret", "eret"),
+];
+
+/// every directive the assembler understands
+pub const DIRECTIVES: &[&str] = &[
+    ".text", ".data", ".ktext", ".kdata", ".globl", ".global", ".eqv", ".set",
+    ".word", ".half", ".byte", ".float", ".double", ".ascii", ".asciiz", ".space", ".align",
+];
+
+/// directives that lay out data, these belong in `.data`
+const DATA_DIRECTIVES: &[&str] = &[".word", ".half", ".byte", ".float", ".double", ".ascii", ".asciiz", ".space", ".align"];
+
+/// warnings about the registers an already parsed instruction uses
+/// that are not about the kernel registers if it is kernel code
+fn check_registers(stmt: &Statement, asm_ins: &AsmInstruction, kernel: bool, warnings: &mut Vec<AsmWarning>) {
+    for (n, operand) in stmt.operands.iter().enumerate() {
+        let Token::REGISTER(reg) = &operand.token else { continue };
+        match register_to_addr(reg.clone()) {
+            Some(0) if n == 0 && asm_ins.destination().is_some() => warnings.push(statement_warning(
+                operand.span, WarningKind::ZeroWrite, format!("write to {reg} is discarded"),
+            )),
+            Some(1) => warnings.push(statement_warning(
+                operand.span, WarningKind::AtRegister, format!("{reg} is reserved for expanding pseudo-instructions"),
+            )),
+            Some(26 | 27) if !kernel => warnings.push(statement_warning(
+                operand.span, WarningKind::KernelRegister, format!("{reg} is reserved for the kernel"),
+            )),
+            _ => {}
+        }
+    }
+}
+
+/// names of the symbols an operand may refer to
+pub(crate) fn referenced_symbols(operand: &str) -> impl Iterator<Item = &str> {
+    operand.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        .filter(|word| word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+}
+
+/// a memory operand, either `offset($base)` with an optional
+/// 16 bit offset or an absolute address such as a data label
+fn parse_address(arg: &str, symbols: &SymbolTable, i: Span) -> Result<(u32, String), nom::Err<ParserVerboseError>> {
+    if let Some((offset, base)) = arg.strip_suffix(')').and_then(|a| a.rsplit_once('(')) {
+        let base = base.trim();
+        ensure_register(base, i)?;
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => parse_immediate(offset, symbols, Field::Signed(16), i)?,
+        };
+        return Ok((offset as u32, base.to_string()));
+    }
+    let addr = parse_immediate(arg, symbols, Field::Unsigned(32), i)?;
+    Ok((addr as u32, "$zero".to_string()))
 }
 
 /// function to parse a line of assembly instructions
-fn parse_instruction<'a>(i: Span<'a>) -> IResult<Span<'a>, AsmInstruction, ParserVerboseError> {
-    let stripped_src = consume_whitespace(i)?.0;
-    // parse instruction, parse alphabet until space and return the rest of the source
-    let (args, ins) = recognize(tuple((is_not(" "), consume_whitespace)))(stripped_src)?;
-    let instruction = ins.fragment().trim().to_string();
-
-    // parse arguments until newline, each argument is separated by a comma
-    // any amount of whitespace is allowed between arguments
-    let (i, arguments) = separated_list1(
-        char(','),
-        map(
-            recognize(tuple((consume_whitespace, is_not(",\r\n"), consume_whitespace))),
-            |s: Span| s.fragment().trim().to_string(),
-        ),
-    )(args)?;
-
-    let remaining = consume_whitespace(i)?.0;
+fn parse_instruction(stmt: &Statement, symbols: &SymbolTable, text_labels: &HashMap<String, usize>) -> Result<AsmInstruction, nom::Err<ParserVerboseError>> {
+    let i = stmt.span();
+    let instruction = i.fragment().to_string();
+    let arguments = stmt.arguments();
+    // operands are only indexed after their count has been checked
+    let operand = |n: usize| stmt.operands[n].span;
 
     match instruction.as_str() {
         "li" => {
             check_argument_counts(&arguments, 2, i)?;
-            let reg = arguments.get(0).unwrap();
-            ensure_register(reg, i)?;
-            let imm = map_parse_error(i, || arguments.get(1).unwrap().parse::<i16>(), Some("unable to parse immediate value"))?;
-            Ok((remaining, AsmInstruction::LI(reg.to_string(), imm)))
+            let reg = arguments.first().unwrap();
+            ensure_register(reg, operand(0))?;
+            let imm = parse_immediate(arguments.get(1).unwrap(), symbols, Field::Signed(16), operand(1))?;
+            Ok(AsmInstruction::LI(reg.to_string(), imm as i16))
+        }
+        "la" => {
+            check_argument_counts(&arguments, 2, i)?;
+            let reg = arguments.first().unwrap();
+            ensure_register(reg, operand(0))?;
+            let addr = parse_immediate(arguments.get(1).unwrap(), symbols, Field::Unsigned(32), operand(1))?;
+            Ok(AsmInstruction::LA(reg.to_string(), addr as u32))
         }
         "add" => {
             check_argument_counts(&arguments, 3, i)?;
-            let rd = arguments.get(0).unwrap();
-            ensure_register(rd, i)?;
+            let rd = arguments.first().unwrap();
+            ensure_register(rd, operand(0))?;
             let rs = arguments.get(1).unwrap();
-            ensure_register(rs, i)?;
+            ensure_register(rs, operand(1))?;
             let rt = arguments.get(2).unwrap();
-            ensure_register(rt, i)?;
-            Ok((remaining, AsmInstruction::ADD(rd.to_string(), rs.to_string(), rt.to_string())))
+            ensure_register(rt, operand(2))?;
+            Ok(AsmInstruction::ADD(rd.to_string(), rs.to_string(), rt.to_string()))
+        }
+        "lw" | "sw" => {
+            check_argument_counts(&arguments, 2, i)?;
+            let reg = arguments.first().unwrap();
+            ensure_register(reg, operand(0))?;
+            let (offset, base) = parse_address(arguments.get(1).unwrap(), symbols, operand(1))?;
+            match instruction.as_str() {
+                "lw" => Ok(AsmInstruction::LW(reg.to_string(), offset, base)),
+                _ => Ok(AsmInstruction::SW(reg.to_string(), offset, base)),
+            }
+        }
+        "j" | "jal" => {
+            check_argument_counts(&arguments, 1, i)?;
+            let label = arguments.first().unwrap();
+            if !text_labels.contains_key(label) {
+                return Err(undefined_symbol(operand(0), format!("undefined label: {label}"), label, text_labels.keys().map(|l| l.as_str())));
+            }
+            match instruction.as_str() {
+                "j" => Ok(AsmInstruction::JUMP(WhereTo::Label(label.to_string()))),
+                _ => Ok(AsmInstruction::JAL(WhereTo::Label(label.to_string()))),
+            }
+        }
+        "jr" => {
+            check_argument_counts(&arguments, 1, i)?;
+            let rs = arguments.first().unwrap();
+            ensure_register(rs, operand(0))?;
+            Ok(AsmInstruction::JR(rs.to_string()))
+        }
+        // the link register defaults to $ra
+        "jalr" => {
+            if arguments.len() != 1 {
+                check_argument_counts(&arguments, 2, i)?;
+            }
+            for (n, reg) in arguments.iter().enumerate() {
+                ensure_register(reg, operand(n))?;
+            }
+            let rs = arguments.last().unwrap();
+            let rd = if arguments.len() == 2 { arguments[0].as_str() } else { "$ra" };
+            Ok(AsmInstruction::JALR(rd.to_string(), rs.to_string()))
+        }
+        "syscall" => {
+            check_argument_counts(&arguments, 0, i)?;
+            Ok(AsmInstruction::SYSCALL)
+        }
+        "mfc0" | "mtc0" => {
+            check_argument_counts(&arguments, 2, i)?;
+            let rt = arguments.first().unwrap();
+            ensure_register(rt, operand(0))?;
+            let rd = cp0_register(arguments.get(1).unwrap(), operand(1))?;
+            match instruction.as_str() {
+                "mfc0" => Ok(AsmInstruction::MFC0(rt.to_string(), rd)),
+                _ => Ok(AsmInstruction::MTC0(rt.to_string(), rd)),
+            }
+        }
+        "eret" => {
+            check_argument_counts(&arguments, 0, i)?;
+            Ok(AsmInstruction::ERET)
         }
         // else return error
-        _ => Err(nom::Err::Failure(ParserVerboseError {
-            line: i.location_line(),
-            column: i.get_column(),
-            input: i.fragment().to_string(),
-            msg: format!("invalid instruction: {instruction}"),
-        })),
+        _ => Err(statement_error(stmt.head.as_ref().map_or(i, |h| h.span), ErrorCode::UnknownInstruction, format!("invalid instruction: {instruction}"))),
+    }
+
+}
+
+/// replaces jump targets given as labels with the index of the
+/// first bytecode generated for the labelled instruction
+fn resolve_labels(instructions: &mut [ParsedInstruction], text_labels: &HashMap<String, usize>) {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions.iter() {
+        offsets.push(offset);
+        offset += instruction.asm_ins.bytecode_len();
+    }
+    // a label after the last instruction points at the terminator
+    offsets.push(offset);
+
+    for instruction in instructions.iter_mut() {
+        match &instruction.asm_ins {
+            AsmInstruction::JUMP(WhereTo::Label(label)) => {
+                let target = offsets[text_labels[label]] as u32;
+                instruction.asm_ins = AsmInstruction::JUMP(WhereTo::Line(target));
+            }
+            AsmInstruction::JAL(WhereTo::Label(label)) => {
+                let target = offsets[text_labels[label]] as u32;
+                instruction.asm_ins = AsmInstruction::JAL(WhereTo::Line(target));
+            }
+            _ => {}
+        }
     }
+}
 
+/// state of the first pass, which defines labels and constants
+/// and lays out the data section one statement at a time
+struct FirstPass<'a> {
+    file_name: &'a str,
+    src: &'a str,
+    symbols: SymbolTable,
+    text_labels: HashMap<String, usize>,
+    text_statements: Vec<(Statement<'a>, String)>,
+    // every .set as the index of the next instruction, the name and the value
+    sets: Vec<(usize, String, i64)>,
+    datastore_source: Vec<DataMap>,
+    section: Section,
+    // index of the first .ktext instruction
+    kernel_text: Option<usize>,
+    current_label: String,
+    // data labels are placed at the next, possibly aligned, data item
+    pending_labels: Vec<Lexeme<'a>>,
+    data_offset: u32,
+    // every label in order of definition and every symbol an operand refers to
+    labels: Vec<Lexeme<'a>>,
+    references: HashSet<String>,
+    warnings: Vec<AsmWarning>,
 }
 
-pub fn mock_parser(src_in: &str) -> Result<(LocatedSpan<&str>, Vec<AsmInstruction>, Vec<DataMap>), nom::Err<ParserVerboseError>> {
+impl<'a> FirstPass<'a> {
+
+    fn new(file_name: &'a str, src: &'a str) -> FirstPass<'a> {
+        FirstPass {
+            file_name,
+            src,
+            symbols: SymbolTable::new(),
+            text_labels: HashMap::new(),
+            text_statements: Vec::new(),
+            sets: Vec::new(),
+            datastore_source: Vec::new(),
+            section: Section::Text,
+            kernel_text: None,
+            current_label: String::new(),
+            pending_labels: Vec::new(),
+            data_offset: 0,
+            labels: Vec::new(),
+            references: HashSet::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn define_label(&mut self, label: &Lexeme<'a>, value: u32) -> Result<(), nom::Err<ParserVerboseError>> {
+        map_parse_error(label.span, ErrorCode::DuplicateSymbol, || self.symbols.define(label.span.fragment(), value as i64, SymbolKind::Label), None)?;
+        self.labels.push(label.clone());
+        Ok(())
+    }
 
-    let (mut text_section, mut data_section) = match (src_in.find(".text"), src_in.find(".data")) {
-        (Some(text_index), Some(data_index)) if data_index > text_index => {
-            let text_section = Span::new(&src_in[text_index..data_index].trim()[..]);
-            let data_section = Span::new(&src_in[data_index..].trim()[..]);
-            (text_section, data_section)
+    /// places the pending data labels at the current data offset
+    fn flush_pending_labels(&mut self) -> Result<(), nom::Err<ParserVerboseError>> {
+        for label in std::mem::take(&mut self.pending_labels) {
+            self.define_label(&label, DATA_SEGMENT_BASE + self.data_offset)?;
         }
-        (Some(text_index), None) => {
-            let text_section = Span::new(&src_in[text_index..].trim()[..]);
-            (Span::new(""), text_section)
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: Statement<'a>) -> Result<(), nom::Err<ParserVerboseError>> {
+        for operand in &stmt.operands {
+            if let Token::IMMEDIATE(text) = &operand.token {
+                self.references.extend(referenced_symbols(text).map(|s| s.to_string()));
+            }
         }
-        (None, Some(data_index)) => {
-            let data_section = Span::new(&src_in[data_index..].trim()[..]);
-            (data_section, Span::new(""))
+
+        // data declared in .text is still placed in the data segment
+        let section = match &stmt.head {
+            Some(Lexeme { token: Token::DIRECTIVE(d), .. }) if DATA_DIRECTIVES.contains(&d.as_str()) => Section::Data,
+            _ => self.section,
+        };
+        for label in &stmt.labels {
+            match section {
+                Section::Text => {
+                    let index = self.text_statements.len();
+                    self.define_label(label, TEXT_SEGMENT_BASE + 4 * index as u32)?;
+                    self.text_labels.insert(label.span.fragment().to_string(), index);
+                    self.current_label = label.span.fragment().to_string();
+                }
+                Section::Data => self.pending_labels.push(label.clone()),
+            }
         }
-        _ => (Span::new(""), Span::new(""))
-    };
 
-    if text_section.fragment().is_empty() {
-        return Err(nom::Err::Failure(ParserVerboseError {
-            line: 0,
-            column: 0,
-            input: src_in.to_string(),
-            msg: "missing .text section".to_string(),
-        }));
-    }
-
-    let mut datastore_source : Vec<DataMap>= Vec::new();
-    // parse data section
-    if !data_section.fragment().is_empty() {
-        loop {
-            match parse_data(data_section) {
-                Ok((remaining, parsed_result)) => {
-                    datastore_source.push(parsed_result);
-
-                    if remaining.fragment().is_empty() {
-                        break;
+        let head = match &stmt.head {
+            Some(head) => head.clone(),
+            None => return Ok(()),
+        };
+
+        match &head.token {
+            Token::DIRECTIVE(directive) => match directive.as_str() {
+                ".text" | ".data" | ".kdata" => {
+                    self.flush_pending_labels()?;
+                    self.section = if directive == ".text" { Section::Text } else { Section::Data };
+                }
+                // kernel text follows the program's own, the first
+                // instruction in it is the exception handler
+                ".ktext" => {
+                    self.flush_pending_labels()?;
+                    self.section = Section::Text;
+                    self.kernel_text.get_or_insert(self.text_statements.len());
+                }
+                ".globl" | ".global" => {}
+                ".eqv" => {
+                    define_constant(&stmt, SymbolKind::Equate, &mut self.symbols)?;
+                }
+                ".set" => {
+                    let (name, value) = define_constant(&stmt, SymbolKind::Set, &mut self.symbols)?;
+                    self.sets.push((self.text_statements.len(), name.to_string(), value));
+                }
+                d if DATA_DIRECTIVES.contains(&d) => {
+                    if self.section != Section::Data {
+                        self.warnings.push(statement_warning(head.span, WarningKind::DataInText, format!("{directive} in the .text section is placed in the .data section")));
                     }
+                    let directives = parse_data(&stmt, &self.symbols, &mut self.warnings)?;
+
+                    let alignment = directives[0].alignment();
+                    self.data_offset = self.data_offset.div_ceil(alignment) * alignment;
+                    let name = self.pending_labels.first().map(|l| l.span.fragment().to_string());
+                    self.flush_pending_labels()?;
+                    self.data_offset += directives.iter().map(|d| d.size()).sum::<u32>();
 
-                    // Update the remaining input for the next iteration
-                    data_section = remaining;
+                    self.datastore_source.push(DataMap::new(name, directives, stmt.source_span(self.file_name, self.src)));
                 }
-                Err(err) => {
-                    eprintln!("Parsing error: {:?}", err);
-                    return Err(err);
+                _ => return Err(statement_error(head.span, ErrorCode::UnknownDirective, format!("unknown directive: {directive}"))),
+            },
+            Token::INSTRUCTION(_) => {
+                if self.section != Section::Text {
+                    return Err(statement_error(head.span, ErrorCode::WrongSection, "instructions are only allowed in the .text section".to_string()));
                 }
+                self.text_statements.push((stmt, self.current_label.clone()));
             }
+            _ => unreachable!("statement head is always a directive or instruction"),
         }
+        Ok(())
     }
 
-    let mut bytecode_source = Vec::new();
-    // parse instructions
-    loop {
-        match parse_instruction(text_section) {
-            Ok((remaining, parsed_result)) => {
-                bytecode_source.push(parsed_result);
-
-                if remaining.fragment().is_empty() {
-                    return Ok((remaining, bytecode_source, datastore_source));
-                }
+}
 
-                // Update the remaining input for the next iteration
-                text_section = remaining;
-            }
-            Err(err) => {
-                eprintln!("Parsing error: {:?}", err);
-                return Err(err);
+/// parses source that did not come from a file
+pub fn mock_parser(src_in: &str) -> Result<ParsedProgram, Vec<ParserVerboseError>> {
+    parse_source("<source>", src_in)
+}
+
+/// parses a whole source file, the first pass defines labels and
+/// constants and lays out the data section, the second parses
+/// instructions so that they can refer to labels defined later on
+///
+/// a line with an error is skipped and parsing carries on, so that
+/// every error in the file is returned, ordered by position
+pub fn parse_source(file_name: &str, src_in: &str) -> Result<ParsedProgram, Vec<ParserVerboseError>> {
+
+    let mut errors: Vec<ParserVerboseError> = Vec::new();
+    let mut first_pass = FirstPass::new(file_name, src_in);
+
+    let mut lexer = Lexer::new(src_in);
+    while let Some(line) = lexer.next_line() {
+        let result = line
+            .map_err(nom::Err::Failure)
+            .and_then(|tokens| {
+                let tokens: Vec<Lexeme> = tokens.into_iter().filter(|l| l.token != Token::NEWLINE).collect();
+                split_statement(&tokens)
+            })
+            .and_then(|stmt| first_pass.statement(stmt));
+        if let Err(err) = result {
+            errors.push(err.into());
+        }
+    }
+    if let Err(err) = first_pass.flush_pending_labels() {
+        errors.push(err.into());
+    }
+
+    let FirstPass { symbols, text_labels, text_statements, sets, datastore_source, labels, references, kernel_text, mut warnings, .. } = first_pass;
+
+    // .set symbols are replayed so that each instruction sees the value
+    // in force where it appears, uses before the first .set see the last
+    let mut scoped = symbols.clone();
+    let mut sets = sets.into_iter().peekable();
+    let mut bytecode_source = Vec::new();
+    for (index, (stmt, label)) in text_statements.iter().enumerate() {
+        while let Some((_, name, value)) = sets.next_if(|(at, _, _)| *at <= index) {
+            scoped.define(&name, value, SymbolKind::Set).expect("a .set symbol can be redefined");
+        }
+        match parse_instruction(stmt, &scoped, &text_labels) {
+            Ok(asm_ins) => {
+                check_registers(stmt, &asm_ins, kernel_text.is_some_and(|start| index >= start), &mut warnings);
+                bytecode_source.push(ParsedInstruction {
+                    asm_ins,
+                    span: stmt.source_span(file_name, src_in),
+                    label: label.clone(),
+                })
             }
+            Err(err) => errors.push(err.into()),
+        }
+    }
+
+    // only the first instruction after a jump is reported, the
+    // rest of the block is unreachable because of it, the exception
+    // handler is reached by the hardware
+    let targets: HashSet<usize> = text_labels.values().copied().chain(kernel_text).collect();
+    for (index, pair) in text_statements.windows(2).enumerate() {
+        let jumps = matches!(pair[0].0.head.as_ref().map(|h| h.span.fragment()), Some(&"j" | &"jr" | &"eret"));
+        if jumps && !targets.contains(&(index + 1)) {
+            warnings.push(statement_warning(pair[1].0.span(), WarningKind::UnreachableCode, "unreachable instruction".to_string()));
+        }
+    }
+
+    // kernel code is entered through exceptions rather than jumps
+    let kernel = |name: &str| kernel_text.is_some_and(|start| text_labels.get(name).is_some_and(|index| *index >= start));
+    for label in &labels {
+        let name = label.span.fragment();
+        if *name != "main" && !kernel(name) && !references.contains(*name) {
+            warnings.push(statement_warning(label.span, WarningKind::UnusedLabel, format!("label {name} is never used")));
         }
     }
+    warnings.sort_by_key(|w| (w.line, w.column));
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|e| (e.line, e.column));
+        return Err(errors);
+    }
+    resolve_labels(&mut bytecode_source, &text_labels);
 
+    Ok(ParsedProgram {
+        instructions: bytecode_source,
+        data: datastore_source,
+        symbols,
+        text_labels,
+        exception_handler: kernel_text,
+        warnings,
+        file_name: file_name.to_string(),
+        source: src_in.to_string(),
+    })
 }
 
 
@@ -235,55 +770,49 @@ pub fn mock_parser(src_in: &str) -> Result<(LocatedSpan<&str>, Vec<AsmInstructio
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_eol_comment() {
-        let input = "# This is a comment\n";
-        let result = eol_comment(Span::new(input));
-        assert!(result.is_ok());
-        let (i, comment) = result.unwrap();
-        assert_eq!(i.fragment(), &"\n");
-        assert_eq!(comment, " This is a comment");
-
-        let input = "// This is NOT a comment";
-        let result = eol_comment(Span::new(input));
-        assert!(result.is_err());
-
-        let err = result.unwrap_err();
-        let pve: ParserVerboseError = err.into();
-        assert!(pve.msg.contains("failed to parse comment"));
+    /// parses the first line of src as an instruction
+    fn parse_line(src: &str, symbols: &SymbolTable) -> Result<AsmInstruction, nom::Err<ParserVerboseError>> {
+        let tokens = Lexer::new(src).tokenize().unwrap();
+        let line: Vec<Lexeme> = tokens.into_iter().take_while(|l| !matches!(l.token, Token::NEWLINE | Token::EOF)).collect();
+        parse_instruction(&split_statement(&line)?, symbols, &HashMap::new())
     }
 
     #[test]
     fn test_parse_instruction() {
         let input = "li $t1, 45";
-        let result = parse_instruction(Span::new(input));
+        let result = parse_line(input, &SymbolTable::new());
         assert!(result.is_ok());
-        let (_, instruction) = result.unwrap();
+        let instruction = result.unwrap();
         assert_eq!(instruction, AsmInstruction::LI("$t1".to_string(), 45));
 
         let input = "li $t1, -6";
-        let result = parse_instruction(Span::new(input));
+        let result = parse_line(input, &SymbolTable::new());
         assert!(result.is_ok());
-        let (_, instruction) = result.unwrap();
+        let instruction = result.unwrap();
         assert_eq!(instruction, AsmInstruction::LI("$t1".to_string(), -6));
+
+        let input = "add $8, $t1,$9";
+        let result = parse_line(input, &SymbolTable::new());
+        assert_eq!(result.unwrap(), AsmInstruction::ADD("$8".to_string(), "$t1".to_string(), "$9".to_string()));
+
+        let result = parse_line("add $t1, $t1 $t1", &SymbolTable::new());
+        assert!(result.is_err());
+        let result = parse_line("add $t1, $t32, $t1", &SymbolTable::new());
+        assert!(result.is_err());
     }
 
 
     #[test]
     fn test_parse_instruction_multiline() {
         let input = "li $t1, 45\nadd $t1, $t1, $t1";
-        let result = parse_instruction(Span::new(input));
-        assert!(result.is_ok());
-        let (i, instruction) = result.unwrap();
-        assert_eq!(i.fragment(), &"add $t1, $t1, $t1");
-        assert_eq!(instruction, AsmInstruction::LI("$t1".to_string(), 45));
-
-        let result = parse_instruction(i);
+        let result = mock_parser(input);
         assert!(result.is_ok());
-        let (i, instruction) = result.unwrap();
-        assert_eq!(i.fragment(), &"");
-        assert_eq!(instruction, AsmInstruction::ADD("$t1".to_string(), "$t1".to_string(), "$t1".to_string()));
-
+        let instructions = result.unwrap().instructions;
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(instructions[0].asm_ins, AsmInstruction::LI("$t1".to_string(), 45));
+        assert_eq!(instructions[0].span.line, 1);
+        assert_eq!(instructions[1].asm_ins, AsmInstruction::ADD("$t1".to_string(), "$t1".to_string(), "$t1".to_string()));
+        assert_eq!(instructions[1].span.line, 2);
     }
 
     #[test]
@@ -292,12 +821,275 @@ mod tests {
         li $t0, 1
         li $t1, 9"#;
 
-        let result = parse_instruction(Span::new(input));
+        let result = mock_parser(input);
+        assert!(result.is_ok());
+        let instructions = result.unwrap().asm_instructions();
+        assert_eq!(instructions, vec![
+            AsmInstruction::LI("$t0".to_string(), 1),
+            AsmInstruction::LI("$t1".to_string(), 9),
+        ]);
+    }
+
+    #[test]
+    fn test_source_spans() {
+        let input = "\n.data\nmsg: .asciiz \"hi\" # greeting\n.text\nmain:   la  $a0,  msg   # load\n  syscall";
+        let program = parse_source("prog.s", input).unwrap();
+
+        let span = &program.instructions[0].span;
+        assert_eq!(span.to_string(), "prog.s:5:9 la  $a0,  msg");
+        assert_eq!(program.instructions[1].span.to_string(), "prog.s:6:3 syscall");
+        assert_eq!(program.data[0].span().to_string(), "prog.s:3:6 .asciiz \"hi\"");
+    }
+
+    #[test]
+    fn test_parse_labels_and_jumps() {
+        let input = r#"
+        .text
+        main: li $t0, 1     # label and instruction on the same line
+        loop:
+        end:  j loop
+              li $v0, 10
+              j end
+        "#;
+
+        let program = mock_parser(input).unwrap();
+        assert_eq!(program.instructions[0].label, "main");
+        assert_eq!(program.instructions[1].label, "end");
+        // li lowers to two bytecodes so loop starts at bytecode 2
+        assert_eq!(program.instructions[1].asm_ins, AsmInstruction::JUMP(WhereTo::Line(2)));
+        assert_eq!(program.instructions[3].asm_ins, AsmInstruction::JUMP(WhereTo::Line(2)));
+        assert_eq!(program.symbols.get("loop"), Some(TEXT_SEGMENT_BASE as i64 + 4));
+
+        let result = mock_parser("j nowhere");
+        let pve = &result.unwrap_err()[0];
+        assert!(pve.msg.contains("undefined label: nowhere"));
+    }
+
+    #[test]
+    fn test_parse_calls() {
+        let symbols = SymbolTable::new();
+        assert_eq!(parse_line("jr $ra", &symbols).unwrap(), AsmInstruction::JR("$ra".to_string()));
+        assert_eq!(parse_line("jalr $t0", &symbols).unwrap(), AsmInstruction::JALR("$ra".to_string(), "$t0".to_string()));
+        assert_eq!(parse_line("jalr $s0, $t0", &symbols).unwrap(), AsmInstruction::JALR("$s0".to_string(), "$t0".to_string()));
+        assert!(parse_line("jalr", &symbols).is_err());
+        assert!(parse_line("jr fn", &symbols).is_err());
+
+        let program = mock_parser("main: jal fn
+fn: jr $ra").unwrap();
+        assert_eq!(program.asm_instructions()[0], AsmInstruction::JAL(WhereTo::Line(1)));
+    }
+
+    #[test]
+    fn test_parse_kernel_text() {
+        let symbols = SymbolTable::new();
+        assert_eq!(parse_line("mfc0 $k0, $13", &symbols).unwrap(), AsmInstruction::MFC0("$k0".to_string(), 13));
+        assert_eq!(parse_line("mtc0 $t0, $12", &symbols).unwrap(), AsmInstruction::MTC0("$t0".to_string(), 12));
+        assert_eq!(parse_line("eret", &symbols).unwrap(), AsmInstruction::ERET);
+        assert!(parse_line("mfc0 $k0", &symbols).is_err());
+        let err: ParserVerboseError = parse_line("mfc0 $t0, $t5", &symbols).unwrap_err().into();
+        assert_eq!(err.code, ErrorCode::InvalidRegister);
+        assert!(err.msg.contains("coprocessor 0 register"));
+        assert!(parse_line("mtc0 $t0, $32", &symbols).is_err());
+
+        let program = mock_parser("main: li $v0, 10
+    syscall
+    .ktext
+    mfc0 $k0, $13
+    eret
+    .kdata
+save: .word 0").unwrap();
+        assert_eq!(program.exception_handler, Some(2));
+        assert_eq!(program.data.len(), 1);
+        // $k0 is only reserved for kernel code
+        assert!(program.warnings.iter().all(|w| w.kind != WarningKind::KernelRegister));
+        assert_eq!(mock_parser("li $v0, 10").unwrap().exception_handler, None);
+    }
+
+    #[test]
+    fn test_parse_memory_operands() {
+        let symbols = SymbolTable::new();
+        assert_eq!(parse_line("lw $t0, -4($sp)", &symbols).unwrap(), AsmInstruction::LW("$t0".to_string(), -4i32 as u32, "$sp".to_string()));
+        assert_eq!(parse_line("sw $t0, ($a0)", &symbols).unwrap(), AsmInstruction::SW("$t0".to_string(), 0, "$a0".to_string()));
+
+        let program = mock_parser(".text\nlw $t0, arr+4\n.data\narr: .word 1, 2").unwrap();
+        assert_eq!(program.asm_instructions(), vec![AsmInstruction::LW("$t0".to_string(), DATA_SEGMENT_BASE + 4, "$zero".to_string())]);
+
+        assert!(parse_line("lw $t0, 4($bogus)", &symbols).is_err());
+        assert!(parse_line("sw $t0, 40000($sp)", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_parse_data_section() {
+        let input = r#"
+        .data
+        bytes: .byte 1, 2, 'c'
+        arr:   .word 1, 2, 3
+               .word 4
+        label_only:
+        buf:   .space 3
+        .text
+        la $a0, arr
+        la $a1, buf
+        "#;
+
+        let program = mock_parser(input).unwrap();
+        assert_eq!(program.data.len(), 4);
+        assert_eq!(program.data[1].name(), Some("arr"));
+        assert_eq!(program.data[2].name(), None);
+        assert!(matches!(program.data[1].data(), [DataDirective::Word(1), DataDirective::Word(2), DataDirective::Word(3)]));
+        assert_eq!(program.asm_instructions(), vec![
+            AsmInstruction::LA("$a0".to_string(), DATA_SEGMENT_BASE + 4),
+            AsmInstruction::LA("$a1".to_string(), DATA_SEGMENT_BASE + 20),
+        ]);
+        assert_eq!(program.symbols.get("label_only"), Some(DATA_SEGMENT_BASE as i64 + 20));
+
+        let result = mock_parser(".data\nli $t0, 1");
+        let pve = &result.unwrap_err()[0];
+        assert_eq!(pve.line, 2);
+        assert!(pve.msg.contains("only allowed in the .text section"));
+
+        let result = mock_parser(".text\n.bogus 1");
+        let pve = &result.unwrap_err()[0];
+        assert!(pve.msg.contains("unknown directive: .bogus"));
+
+        let program = mock_parser(".data\nf: .float 1.5, -2e1\nd: .double 0.25").unwrap();
+        assert!(matches!(program.data[0].data(), [DataDirective::Float(a), DataDirective::Float(b)] if *a == 1.5 && *b == -20.0));
+        // doubles are aligned on 8 bytes
+        assert_eq!(program.symbols.get("d"), Some(DATA_SEGMENT_BASE as i64 + 8));
+        let result = mock_parser(".data\n.float one");
+        assert!(result.unwrap_err()[0].msg.contains("expected a floating point number"));
+    }
+
+    #[test]
+    fn test_multiple_errors() {
+        let input = r#"
+        .text
+        main:   addd $t0, $t0, $t0
+                li $t10, 1
+                @
+                j main
+                li $t0, 1,
+        .data
+        .bogus
+        msg:    .asciiz "ok"
+        "#;
+
+        let errors = mock_parser(input).unwrap_err();
+        let lines: Vec<u32> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 7, 9]);
+        assert!(errors[0].msg.contains("invalid instruction: addd"));
+        assert!(errors[1].msg.contains("$t10 is not a valid register"));
+        assert!(errors[4].msg.contains("unknown directive: .bogus"));
+    }
+
+    #[test]
+    fn test_parse_constant_expressions() {
+        let input = r#"
+        .eqv BUF_SIZE 8
+        .set STEP, 2
+        .text
+        li $t0, BUF_SIZE*4
+        li $t1, 'A'
+        li $t2, 0b1010 + STEP
+        li $t3, %lo(buf+8)
+        li $t4, %hi(buf)
+        .set STEP, STEP * 3     # redefined, later uses see 6
+        li $t5, STEP
+        li $t6, %hi(0xffff0000)
+        .data
+        msg: .asciiz "a # b\n"   # not part of the string
+        buf: .word BUF_SIZE - 1
+        "#;
+        let program = mock_parser(input).unwrap();
+        assert_eq!(program.asm_instructions(), vec![
+            AsmInstruction::LI("$t0".to_string(), 32),
+            AsmInstruction::LI("$t1".to_string(), 65),
+            AsmInstruction::LI("$t2".to_string(), 12),
+            // msg takes 8 bytes, buf is word aligned at 0x10010008
+            AsmInstruction::LI("$t3".to_string(), 0x10),
+            AsmInstruction::LI("$t4".to_string(), 0x1001),
+            AsmInstruction::LI("$t5".to_string(), 6),
+            // %hi of an MMIO address is a bit pattern rather than a signed value
+            AsmInstruction::LI("$t6".to_string(), -1),
+        ]);
+        assert!(matches!(program.data[0].data(), [DataDirective::AsciiZero(s)] if s == "a # b\n"));
+        assert!(matches!(program.data[1].data(), [DataDirective::Word(7)]));
+    }
+
+    #[test]
+    fn test_parse_immediate_overflow() {
+        let mut symbols = SymbolTable::new();
+        symbols.define("BIG", 40000, SymbolKind::Equate).unwrap();
+        let result = parse_line("li $t0, BIG", &symbols);
+        let pve: ParserVerboseError = result.unwrap_err().into();
+        assert!(pve.msg.contains("does not fit in a 16-bit signed field"));
+
+        let result = mock_parser(".eqv N 1\n.eqv N 2\n.text\nli $t0, N");
+        let pve = &result.unwrap_err()[0];
+        assert_eq!(pve.line, 2);
+        assert!(pve.msg.contains("already defined"));
+    }
+
+    #[test]
+    fn test_kernel_warnings() {
+        let input = r#"
+        .text
+        main:   li $v0, 10
+                syscall
+        .ktext
+        handler:
+                mfc0 $k0, $14
+                eret
+                li $t0, 1
+        "#;
+        let program = mock_parser(input).unwrap();
+        let kinds: Vec<(u32, WarningKind)> = program.warnings.iter().map(|w| (w.line, w.kind)).collect();
+        assert_eq!(kinds, vec![(9, WarningKind::UnreachableCode)]);
+    }
+
+    #[test]
+    fn test_undefined_symbols() {
+        let errors = mock_parser(".eqv PTR buf+4\n.text\nli $t0, nosuch+1\nli $t1, %lo(buff)\n.data\nbuf: .word 0").unwrap_err();
+        assert!(errors.iter().all(|e| e.code == ErrorCode::UndefinedSymbol));
+        assert_eq!(errors[0].msg, "undefined symbol: buf");
+        assert_eq!(errors[1].msg, "undefined symbol: nosuch");
+        assert_eq!(errors[2].suggestion.as_deref(), Some("buf"));
+
+        let errors = mock_parser(".text\nmain: j man").unwrap_err();
+        assert_eq!(errors[0].code, ErrorCode::UndefinedSymbol);
+        assert_eq!(errors[0].suggestion.as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let input = r#"
+        .text
+        main:   add $at, $t0, $t0
+                j main
+                li $t0, 1
+                .byte 300
+        .data
+        half:   .half -1, 70000
+        "#;
+
+        let program = mock_parser(input).unwrap();
+        let warnings: Vec<(u32, WarningKind)> = program.warnings.iter().map(|w| (w.line, w.kind)).collect();
+        assert_eq!(warnings, vec![
+            (3, WarningKind::AtRegister),
+            (5, WarningKind::UnreachableCode),
+            (6, WarningKind::DataInText),
+            (6, WarningKind::TruncatedImmediate),
+            (8, WarningKind::UnusedLabel),
+            (8, WarningKind::TruncatedImmediate),
+        ]);
+        assert!(program.warnings[3].msg.contains("value 300 is truncated to 8 bits (44)"));
+        assert!(matches!(program.data[0].data(), [DataDirective::Byte(44)]));
+        assert_eq!(program.symbols.get("half"), Some(DATA_SEGMENT_BASE as i64 + 2));
     }
 
     #[test]
     fn test_integrated_all() {
-        let _src = r#"
+        let src = r#"
         # Program File: Program2-1.asm 
         # Author: Charles Kann
         # Purpose: First program, Hello World
@@ -313,7 +1105,21 @@ mod tests {
         .data                   # Define the program data.
         greeting: .asciiz "Hello World" #The string to print.
         "#;
-        unimplemented!("integrated tests")
+
+        let program = mock_parser(src).unwrap();
+        assert_eq!(program.asm_instructions(), vec![
+            AsmInstruction::LI("$v0".to_string(), 4),
+            AsmInstruction::LA("$a0".to_string(), DATA_SEGMENT_BASE),
+            AsmInstruction::SYSCALL,
+            AsmInstruction::LI("$v0".to_string(), 10),
+            AsmInstruction::SYSCALL,
+        ]);
+        assert!(program.instructions.iter().all(|i| i.label == "main"));
+        assert_eq!(program.instructions[1].span.line, 8);
+
+        assert_eq!(program.data.len(), 1);
+        assert_eq!(program.data[0].name(), Some("greeting"));
+        assert!(matches!(program.data[0].data(), [DataDirective::AsciiZero(s)] if s == "Hello World"));
     }
 
 
@@ -1,15 +1,18 @@
 use nom_locate::LocatedSpan;
-use crate::parser::ParserVerboseError;
+use crate::parser::{ErrorCode, ParserVerboseError};
 use crate::err_util::map_parse_error;
 use crate::registers::register_to_addr;
+use crate::diagnostics::closest;
+use crate::expr::{evaluate, parse_expr, Field, SymbolTable};
 
 /// this function should check is args.len() == expected if not then call on map_parse_error
 /// and then return and propogate the error upwards to be handled by the caller
-pub fn check_argument_counts(args: &Vec<String>, expected: usize, i: LocatedSpan<&str>) -> Result<(), nom::Err<ParserVerboseError>> {
+pub fn check_argument_counts(args: &[String], expected: usize, i: LocatedSpan<&str>) -> Result<(), nom::Err<ParserVerboseError>> {
 
     let actual = args.len();
     map_parse_error(
         i,
+        ErrorCode::OperandCount,
         || {
             if actual != expected {
                 return Err(nom::Err::Failure(ParserVerboseError {
@@ -17,6 +20,8 @@ pub fn check_argument_counts(args: &Vec<String>, expected: usize, i: LocatedSpan
                     column: i.get_column(),
                     input: i.fragment().to_string(),
                     msg: Default::default(),
+                    code: ErrorCode::OperandCount,
+                    suggestion: None,
                 }));
             }
             Ok(())
@@ -33,6 +38,7 @@ pub fn ensure_register(arg: &str, i: LocatedSpan<&str>) -> Result<(), nom::Err<P
 
     map_parse_error(
         i,
+        ErrorCode::InvalidRegister,
         || {
             if register_to_addr(arg.to_string()).is_none() {
                 return Err(nom::Err::Failure(ParserVerboseError {
@@ -40,6 +46,8 @@ pub fn ensure_register(arg: &str, i: LocatedSpan<&str>) -> Result<(), nom::Err<P
                     column: i.get_column(),
                     input: i.fragment().to_string(),
                     msg: format!("expected register, got {arg}"),
+                    code: ErrorCode::InvalidRegister,
+                    suggestion: None,
                 }));
             }
             Ok(())
@@ -47,3 +55,55 @@ pub fn ensure_register(arg: &str, i: LocatedSpan<&str>) -> Result<(), nom::Err<P
         Some(&format!("{arg} is not a valid register"))
     )
 }
+
+/// number of a coprocessor 0 register, written as `$0` to `$31` since
+/// the names such as `$t5` belong to the general purpose registers
+pub fn cp0_register(arg: &str, i: LocatedSpan<&str>) -> Result<u32, nom::Err<ParserVerboseError>> {
+
+    map_parse_error(
+        i,
+        ErrorCode::InvalidRegister,
+        || arg.strip_prefix('$')
+            .filter(|n| n.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|n| n.parse::<u32>().ok())
+            .filter(|n| *n < 32)
+            .ok_or("not a coprocessor 0 register"),
+        Some(&format!("expected a coprocessor 0 register $0 to $31, got {arg}"))
+    )
+}
+
+/// an error for a name that is not defined, suggesting the closest
+/// of the names that are
+pub fn undefined_symbol<'a>(i: LocatedSpan<&str>, msg: String, name: &str, known: impl IntoIterator<Item = &'a str>) -> nom::Err<ParserVerboseError> {
+    nom::Err::Failure(ParserVerboseError {
+        line: i.location_line(),
+        column: i.get_column(),
+        input: i.fragment().to_string(),
+        msg,
+        code: ErrorCode::UndefinedSymbol,
+        suggestion: closest(name, known).map(|s| s.to_string()),
+    })
+}
+
+/// ensures every symbol a constant expression refers to is defined,
+/// an expression that does not parse is left for evaluation to report
+pub fn check_symbols(arg: &str, symbols: &SymbolTable, i: LocatedSpan<&str>) -> Result<(), nom::Err<ParserVerboseError>> {
+    match parse_expr(arg).ok().as_ref().and_then(|e| e.undefined(symbols)) {
+        Some(name) => Err(undefined_symbol(i, format!("undefined symbol: {name}"), name, symbols.names())),
+        None => Ok(()),
+    }
+}
+
+/// evaluates an immediate operand written as a constant expression
+/// and checks that it fits in the field it is encoded into
+pub fn parse_immediate(arg: &str, symbols: &SymbolTable, field: Field, i: LocatedSpan<&str>) -> Result<i64, nom::Err<ParserVerboseError>> {
+
+    check_symbols(arg, symbols, i)?;
+
+    map_parse_error(
+        i,
+        ErrorCode::InvalidExpression,
+        || evaluate(arg, symbols, field),
+        None
+    )
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::memory::scramble;
+use crate::snapshot::Fields;
+
+/// pseudo random generators of syscalls 40 to 44, numbered by the
+/// program, each one yields the same values for the same seed
+#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Random {
+    // generators not seeded by the program derive their seed from
+    // this one, None until it is needed
+    seed: Option<u64>,
+    // seed of each generator and the number of values it yielded
+    generators: BTreeMap<u32, (u64, u64)>,
+}
+
+impl Random {
+
+    pub fn new() -> Random {
+        Random::default()
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// seed of generators the program does not seed itself
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// restarts a generator, like syscall 40
+    pub fn seed_generator(&mut self, id: u32, seed: u64) {
+        self.generators.insert(id, (seed, 0));
+    }
+
+    /// the generators for a snapshot, the default seed if there is
+    /// one, then the number of generators and each one's number,
+    /// seed and the number of values it yielded
+    pub fn save(&self) -> Vec<u8> {
+        let mut section = vec![self.seed.is_some() as u8];
+        section.extend(self.seed.unwrap_or_default().to_le_bytes());
+        section.extend((self.generators.len() as u32).to_le_bytes());
+        for (id, (seed, count)) in &self.generators {
+            section.extend(id.to_le_bytes());
+            section.extend(seed.to_le_bytes());
+            section.extend(count.to_le_bytes());
+        }
+        section
+    }
+
+    pub fn restore(fields: &mut Fields) -> std::io::Result<Random> {
+        let seeded = fields.bool()?;
+        let seed = fields.u64()?;
+        let mut generators = BTreeMap::new();
+        for _ in 0..fields.u32()? {
+            let id = fields.u32()?;
+            generators.insert(id, (fields.u64()?, fields.u64()?));
+        }
+        Ok(Random { seed: seeded.then_some(seed), generators })
+    }
+
+    fn next(&mut self, id: u32) -> u64 {
+        let seed = self.seed.unwrap_or_default();
+        let (seed, count) = self.generators.entry(id).or_insert((scramble(seed, id as u64), 0));
+        *count += 1;
+        scramble(*seed, *count)
+    }
+
+    pub fn next_int(&mut self, id: u32) -> u32 {
+        (self.next(id) >> 32) as u32
+    }
+
+    /// uniform in 0..bound, bound is not 0
+    pub fn next_below(&mut self, id: u32, bound: u32) -> u32 {
+        (((self.next(id) >> 32) * bound as u64) >> 32) as u32
+    }
+
+    /// uniform in 0.0..1.0
+    pub fn next_float(&mut self, id: u32) -> f32 {
+        (self.next(id) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// uniform in 0.0..1.0
+    pub fn next_double(&mut self, id: u32) -> f64 {
+        (self.next(id) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_random() {
+        let mut a = Random::new();
+        a.set_seed(7);
+        let mut b = a.clone();
+        let values: Vec<u32> = (0..4).map(|_| a.next_int(0)).collect();
+        assert_eq!(values, (0..4).map(|_| b.next_int(0)).collect::<Vec<_>>());
+        // generators are independent
+        assert_ne!(a.next_int(1), a.next_int(0));
+
+        a.seed_generator(3, 99);
+        b.seed_generator(3, 99);
+        assert_eq!(a.next_below(3, 10), b.next_below(3, 10));
+        assert!((0..1000).all(|_| a.next_below(3, 10) < 10));
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&a.next_float(3))));
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&a.next_double(3))));
+    }
+}
@@ -16,8 +16,12 @@ impl Register {
 
 }
 
+/// accepts both names like `$t0` and numbers like `$8`
 pub fn register_to_addr(reg: String) -> Option<u32> {
     let reg_name = reg.as_str();
+    if let Some(number) = reg_name.strip_prefix('$').filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) {
+        return number.parse::<u32>().ok().filter(|n| *n < 32);
+    }
     match reg_name {
         "$zero" => { Some(0) }
         "$at" => { Some(1) }
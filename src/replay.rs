@@ -0,0 +1,132 @@
+use std::io::{BufRead, Write};
+
+use serde::{Serialize, Deserialize};
+
+use crate::debug_table::MachineException;
+
+/// input a syscall took from outside the program
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Event {
+    // bytecodes executed when the syscall ran, itself included
+    pub step: u64,
+    pub syscall: u32,
+    pub input: Vec<u8>,
+}
+
+/// every input a run took, in order, saved as one JSON event per line
+#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ReplayLog {
+    events: Vec<Event>,
+}
+
+impl ReplayLog {
+
+    pub fn new() -> ReplayLog {
+        ReplayLog::default()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn read<R: BufRead>(input: R) -> std::io::Result<ReplayLog> {
+        let mut events = Vec::new();
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = serde_json::from_str(&line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            events.push(event);
+        }
+        Ok(ReplayLog { events })
+    }
+
+    pub fn write<W: Write>(&self, mut output: W) -> std::io::Result<()> {
+        for event in &self.events {
+            serde_json::to_writer(&mut output, event)?;
+            writeln!(output)?;
+        }
+        output.flush()
+    }
+
+}
+
+/// records what syscalls take as input, or feeds a recording back
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub enum Replay {
+    Recording(ReplayLog),
+    // index of the next event to feed back
+    Replaying(ReplayLog, usize),
+}
+
+impl Replay {
+
+    pub fn log(&self) -> &ReplayLog {
+        match self {
+            Replay::Recording(log) | Replay::Replaying(log, _) => log,
+        }
+    }
+
+    /// input of a syscall, None while recording, which has to be
+    /// read and then passed to `record`
+    pub fn next(&mut self, step: u64, syscall: u32) -> Result<Option<Vec<u8>>, MachineException> {
+        let Replay::Replaying(log, next) = self else { return Ok(None) };
+        let event = log.events.get(*next).ok_or(MachineException::Io(format!("the replay log has no input for syscall {syscall} at step {step}")))?;
+        if event.step != step || event.syscall != syscall {
+            return Err(MachineException::Io(format!(
+                "the run diverged from the replay log, which expects syscall {} at step {} but got syscall {syscall} at step {step}",
+                event.syscall, event.step,
+            )));
+        }
+        *next += 1;
+        Ok(Some(event.input.clone()))
+    }
+
+    pub fn record(&mut self, event: Event) {
+        if let Replay::Recording(log) = self {
+            log.push(event);
+        }
+    }
+
+    /// forgets the last input taken, for undo
+    pub fn rewind(&mut self) {
+        match self {
+            Replay::Recording(log) => { log.events.pop(); }
+            Replay::Replaying(_, next) => *next = next.saturating_sub(1),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let mut log = ReplayLog::new();
+        log.push(Event { step: 3, syscall: 5, input: b"21".to_vec() });
+        log.push(Event { step: 9, syscall: 12, input: vec![0xff] });
+        let mut text = Vec::new();
+        log.write(&mut text).unwrap();
+        assert_eq!(String::from_utf8_lossy(&text).lines().next(), Some(r#"{"step":3,"syscall":5,"input":[50,49]}"#));
+        let log = ReplayLog::read(text.as_slice()).unwrap();
+
+        let mut replay = Replay::Replaying(log, 0);
+        assert_eq!(replay.next(3, 5).unwrap(), Some(b"21".to_vec()));
+        assert!(replay.next(9, 5).is_err());
+        replay.rewind();
+        assert_eq!(replay.next(3, 5).unwrap(), Some(b"21".to_vec()));
+        assert_eq!(replay.next(9, 12).unwrap(), Some(vec![0xff]));
+        assert!(replay.next(12, 5).is_err());
+    }
+}
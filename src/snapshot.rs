@@ -0,0 +1,224 @@
+use std::io::{Read, Write};
+
+use crate::checksum::crc32;
+
+/// first bytes of every snapshot
+const MAGIC: &[u8; 8] = b"MIPSNAP\0";
+
+/// version of the container and of the layout of every section,
+/// snapshots of another version are refused rather than misread
+pub const VERSION: u32 = 1;
+
+/// a saved machine, laid out as the magic bytes, the version, the
+/// number of sections and then each section as a 4 byte tag, the
+/// length and CRC-32 of its contents and the contents, integers are
+/// little endian, readers skip sections they do not know
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    sections: Vec<([u8; 4], Vec<u8>)>,
+}
+
+pub fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// name of a section as shown in errors
+fn section_name(tag: &[u8; 4]) -> String {
+    String::from_utf8_lossy(tag).trim_end_matches(['\0', ' ']).to_string()
+}
+
+/// appends bytes prefixed with their length, as `Fields::bytes` reads them
+pub fn put_bytes(section: &mut Vec<u8>, bytes: &[u8]) {
+    section.extend((bytes.len() as u32).to_le_bytes());
+    section.extend_from_slice(bytes);
+}
+
+/// reads the fields of a section in the order they were written
+pub struct Fields<'a> {
+    name: String,
+    contents: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+
+    fn take(&mut self, len: usize) -> std::io::Result<&'a [u8]> {
+        if self.contents.len() < len {
+            return Err(invalid(format!("section {} of the snapshot is cut short", self.name)));
+        }
+        let (field, rest) = self.contents.split_at(len);
+        self.contents = rest;
+        Ok(field)
+    }
+
+    pub fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> std::io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// bytes written by `put_bytes`
+    pub fn bytes(&mut self) -> std::io::Result<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    pub fn string(&mut self) -> std::io::Result<String> {
+        let name = self.name.clone();
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid(format!("section {name} of the snapshot holds invalid text")))
+    }
+
+    /// a value that was checked when it was read is out of range
+    pub fn error(&self, what: &str) -> std::io::Error {
+        invalid(format!("section {} of the snapshot has {what}", self.name))
+    }
+
+    /// every field must have been read
+    pub fn finish(self) -> std::io::Result<()> {
+        match self.contents.is_empty() {
+            true => Ok(()),
+            false => Err(invalid(format!("section {} of the snapshot is too long", self.name))),
+        }
+    }
+
+}
+
+impl Snapshot {
+
+    pub fn new() -> Snapshot {
+        Snapshot::default()
+    }
+
+    pub fn add(&mut self, tag: &[u8; 4], contents: Vec<u8>) {
+        self.sections.push((*tag, contents));
+    }
+
+    pub fn section(&self, tag: &[u8; 4]) -> Option<&[u8]> {
+        self.sections.iter().find(|(t, _)| t == tag).map(|(_, contents)| contents.as_slice())
+    }
+
+    /// the fields of a section every snapshot has
+    pub fn fields(&self, tag: &[u8; 4]) -> std::io::Result<Fields<'_>> {
+        let name = section_name(tag);
+        let contents = self.section(tag).ok_or_else(|| invalid(format!("the snapshot has no {name} section")))?;
+        Ok(Fields { name, contents })
+    }
+
+    /// reads a section with decode, which must read all of it
+    pub fn decode<'a, T>(&'a self, tag: &[u8; 4], decode: impl FnOnce(&mut Fields<'a>) -> std::io::Result<T>) -> std::io::Result<T> {
+        let mut fields = self.fields(tag)?;
+        let value = decode(&mut fields)?;
+        fields.finish()?;
+        Ok(value)
+    }
+
+    pub fn write<W: Write>(&self, mut output: W) -> std::io::Result<()> {
+        output.write_all(MAGIC)?;
+        output.write_all(&VERSION.to_le_bytes())?;
+        output.write_all(&(self.sections.len() as u32).to_le_bytes())?;
+        for (tag, contents) in &self.sections {
+            output.write_all(tag)?;
+            output.write_all(&(contents.len() as u32).to_le_bytes())?;
+            output.write_all(&crc32(contents).to_le_bytes())?;
+            output.write_all(contents)?;
+        }
+        output.flush()
+    }
+
+    pub fn read<R: Read>(mut input: R) -> std::io::Result<Snapshot> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic).map_err(|_| invalid("not a snapshot".to_string()))?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot".to_string()));
+        }
+        let version = read_u32(&mut input)?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported snapshot version {version}, expected {VERSION}")));
+        }
+        let mut sections = Vec::new();
+        for _ in 0..read_u32(&mut input)? {
+            let mut tag = [0; 4];
+            input.read_exact(&mut tag)?;
+            let name = section_name(&tag);
+            let len = read_u32(&mut input)?;
+            let crc = read_u32(&mut input)?;
+            let mut contents = Vec::new();
+            (&mut input).take(len as u64).read_to_end(&mut contents)?;
+            if contents.len() != len as usize {
+                return Err(invalid(format!("section {name} of the snapshot is cut short")));
+            }
+            if crc32(&contents) != crc {
+                return Err(invalid(format!("section {name} of the snapshot is corrupted")));
+            }
+            sections.push((tag, contents));
+        }
+        Ok(Snapshot { sections })
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let mut snapshot = Snapshot::new();
+        snapshot.add(b"INFO", b"{}".to_vec());
+        snapshot.add(b"VM\0\0", vec![1, 2, 3]);
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+        assert_eq!(&bytes[..12], b"MIPSNAP\0\x01\0\0\0");
+        let read = Snapshot::read(bytes.as_slice()).unwrap();
+        assert_eq!(read, snapshot);
+        assert_eq!(read.section(b"VM\0\0"), Some([1, 2, 3].as_slice()));
+        assert_eq!(read.section(b"MEM\0"), None);
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(Snapshot::read(corrupted.as_slice()).unwrap_err().to_string(), "section VM of the snapshot is corrupted");
+        assert!(Snapshot::read(&bytes[..bytes.len() - 1]).unwrap_err().to_string().contains("cut short"));
+        let mut newer = bytes.clone();
+        newer[8] = 2;
+        assert!(Snapshot::read(newer.as_slice()).unwrap_err().to_string().contains("unsupported snapshot version 2"));
+        assert_eq!(Snapshot::read(&b"\x00asm"[..]).unwrap_err().to_string(), "not a snapshot");
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut section = vec![7];
+        section.extend(0x1234_5678u32.to_le_bytes());
+        section.extend(u64::MAX.to_le_bytes());
+        put_bytes(&mut section, b"main.s");
+        let mut snapshot = Snapshot::new();
+        snapshot.add(b"REGS", section);
+
+        let mut fields = snapshot.fields(b"REGS").unwrap();
+        assert_eq!(fields.u8().unwrap(), 7);
+        assert_eq!(fields.u32().unwrap(), 0x1234_5678);
+        assert_eq!(fields.u64().unwrap(), u64::MAX);
+        assert_eq!(fields.string().unwrap(), "main.s");
+        assert_eq!(fields.u8().unwrap_err().to_string(), "section REGS of the snapshot is cut short");
+
+        let mut fields = snapshot.fields(b"REGS").unwrap();
+        fields.u8().unwrap();
+        assert_eq!(fields.finish().unwrap_err().to_string(), "section REGS of the snapshot is too long");
+        assert_eq!(snapshot.fields(b"PC\0\0").err().unwrap().to_string(), "the snapshot has no PC section");
+    }
+}
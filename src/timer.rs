@@ -0,0 +1,178 @@
+use serde::{Serialize, Deserialize};
+
+use crate::debug_table::MachineException;
+use crate::snapshot::Fields;
+
+/// registers of the interval timer, bit 0 of the control register is
+/// set once the period elapsed and cleared by writing the register,
+/// bit 1 enables its interrupt
+pub const TIMER_CONTROL: u32 = 0xffff0020;
+/// instructions between expirations, writing it restarts the timer
+/// and 0 stops it
+pub const TIMER_PERIOD: u32 = 0xffff0024;
+
+/// Cause bits of Count reaching Compare and of the interval timer
+pub const COMPARE_INTERRUPT: u32 = 1 << 15;
+pub const TIMER_INTERRUPT: u32 = 1 << 10;
+
+const EXPIRED: u32 = 1;
+const INTERRUPT_ENABLE: u32 = 1 << 1;
+
+/// time counted in instructions executed, CP0 Count goes up by one
+/// each instruction and raises an interrupt on reaching Compare,
+/// the interval timer expires every period instructions
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Timer {
+    count: u32,
+    compare: u32,
+    // Count reached Compare, until Compare is written
+    matched: bool,
+    period: u32,
+    // instructions until the interval timer expires
+    remaining: u32,
+    expired: bool,
+    interrupts: bool,
+}
+
+impl Timer {
+
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+
+    /// one instruction is about to execute
+    pub fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+        if self.count == self.compare {
+            self.matched = true;
+        }
+        if self.period != 0 {
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.expired = true;
+                self.remaining = self.period;
+            }
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn set_count(&mut self, count: u32) {
+        self.count = count;
+    }
+
+    pub fn compare(&self) -> u32 {
+        self.compare
+    }
+
+    /// also acknowledges the interrupt, as on MIPS
+    pub fn set_compare(&mut self, compare: u32) {
+        self.compare = compare;
+        self.matched = false;
+    }
+
+    /// value of an interval timer register, None if addr is not one
+    pub fn read(&self, addr: u32) -> Option<u32> {
+        match addr {
+            TIMER_CONTROL => Some(if self.expired { EXPIRED } else { 0 } | if self.interrupts { INTERRUPT_ENABLE } else { 0 }),
+            TIMER_PERIOD => Some(self.period),
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, value: u32) -> Result<(), MachineException> {
+        match addr {
+            TIMER_CONTROL => {
+                self.interrupts = value & INTERRUPT_ENABLE != 0;
+                self.expired = false;
+            }
+            TIMER_PERIOD => {
+                self.period = value;
+                self.remaining = value;
+                self.expired = false;
+            }
+            _ => return Err(MachineException::AddressError),
+        }
+        Ok(())
+    }
+
+    /// whether the interval timer is running with its interrupt enabled
+    pub fn raises_interrupts(&self) -> bool {
+        self.interrupts && self.period != 0
+    }
+
+    /// the timer for a snapshot, Count, Compare, the period and the
+    /// instructions remaining of it, then whether Count reached
+    /// Compare, the period expired and the interrupt is enabled
+    pub fn save(&self) -> Vec<u8> {
+        let mut section = Vec::new();
+        for value in [self.count, self.compare, self.period, self.remaining] {
+            section.extend(value.to_le_bytes());
+        }
+        section.extend([self.matched, self.expired, self.interrupts].map(u8::from));
+        section
+    }
+
+    pub fn restore(fields: &mut Fields) -> std::io::Result<Timer> {
+        let [count, compare, period, remaining] = [fields.u32()?, fields.u32()?, fields.u32()?, fields.u32()?];
+        let [matched, expired, interrupts] = [fields.bool()?, fields.bool()?, fields.bool()?];
+        if period != 0 && !(1..=period).contains(&remaining) {
+            return Err(fields.error("an interval timer outside of its period"));
+        }
+        Ok(Timer { count, compare, matched, period, remaining, expired, interrupts })
+    }
+
+    /// interrupts the timers are raising, as Cause bits, both keep
+    /// raising them until acknowledged
+    pub fn pending(&self) -> u32 {
+        let timer = self.interrupts && self.expired;
+        (if self.matched { COMPARE_INTERRUPT } else { 0 }) | (if timer { TIMER_INTERRUPT } else { 0 })
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Timer::new();
+        timer.set_compare(3);
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.pending(), 0);
+        timer.tick();
+        assert_eq!(timer.count(), 3);
+        assert_eq!(timer.pending(), COMPARE_INTERRUPT);
+        timer.tick();
+        assert_eq!(timer.pending(), COMPARE_INTERRUPT);
+        timer.set_compare(10);
+        assert_eq!(timer.pending(), 0);
+
+        timer.write(TIMER_PERIOD, 2).unwrap();
+        timer.tick();
+        assert_eq!(timer.read(TIMER_CONTROL), Some(0));
+        timer.tick();
+        // expired without raising an interrupt
+        assert_eq!(timer.read(TIMER_CONTROL), Some(1));
+        assert_eq!(timer.pending(), 0);
+        timer.write(TIMER_CONTROL, 2).unwrap();
+        assert_eq!(timer.read(TIMER_CONTROL), Some(2));
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.pending(), TIMER_INTERRUPT);
+        timer.write(TIMER_PERIOD, 0).unwrap();
+        (0..10).for_each(|_| timer.tick());
+        assert_eq!(timer.pending() & TIMER_INTERRUPT, 0);
+        assert!(timer.write(TIMER_PERIOD + 4, 0).is_err());
+
+        let mut snapshot = crate::snapshot::Snapshot::new();
+        snapshot.add(b"TIMR", timer.save());
+        assert_eq!(Timer::restore(&mut snapshot.fields(b"TIMR").unwrap()).unwrap(), timer);
+    }
+}
@@ -1,7 +1,7 @@
-use std::{io::Write, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::{BTreeMap, VecDeque}, fs::File, io::{BufReader, BufWriter}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
 use serde::{Serialize, Deserialize};
-use crate::{bytecode::Bytecode, registers::PrettyFmtRegister, debug_table::{RuntimeDebugInfo, CompileDebugInfo, MachineException, MachineState}, memory::Memory};
+use crate::{bitmap::{frame_path, Bitmap, FRAME_REGISTER}, bytecode::Bytecode, console::{Console, ConsoleIo}, expr::SymbolTable, files::FileTable, mmio::{is_mmio, KeyboardDisplay, KEYBOARD_INTERRUPT, RECEIVER_CONTROL, RECEIVER_DATA}, random::Random, timer::{Timer, COMPARE_INTERRUPT, TIMER_CONTROL, TIMER_INTERRUPT, TIMER_PERIOD}, replay::{Event, Replay, ReplayLog}, snapshot::{invalid, put_bytes, Snapshot}, parser::{parse_source, ParsedProgram}, registers::PrettyFmtRegister, debug_table::{RuntimeDebugInfo, CompileDebugInfo, CallFrame, ConventionViolation, HotLoop, ViolationKind, UninitRead, MachineException, MachineState, WatchHit, WatchKind, WatchTarget, Watchpoint}, memory::{scramble, DataMap, Memory, GLOBAL_POINTER, STACK_POINTER, TEXT_SEGMENT_BASE}};
 
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
@@ -29,134 +29,394 @@ impl Stack {
     }
 }
 
-#[derive(Debug)]
+// $s0-$s7, $gp, $sp, $fp and $ra, which a callee must restore
+const CALLEE_SAVED: [u32; 12] = [16, 17, 18, 19, 20, 21, 22, 23, 28, 29, 30, 31];
+// $a0-$a3 and $t0-$t9, which a callee may clobber
+const CALLER_SAVED: [u32; 14] = [4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 24, 25];
+// written to caller saved registers on return while calls are checked
+const POISON: u32 = 0xdead_beef;
+
+/// what to do when a register or memory word is read before
+/// anything was written to it
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[derive(Serialize, Deserialize)]
-pub enum ConsoleLocation {
-    Socket,
-    Terminal,
+pub enum UninitCheck {
+    // keep going, see `take_uninit_reads`
+    Warn,
+    // raise an exception
+    Trap,
 }
 
-impl Default for ConsoleLocation {
-    fn default() -> Self {
-        ConsoleLocation::Terminal
+impl std::str::FromStr for UninitCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(UninitCheck::Warn),
+            "trap" => Ok(UninitCheck::Trap),
+            _ => Err(format!("unknown check: {s}, expected warn or trap")),
+        }
     }
 }
 
-#[derive(Debug)]
+/// where syscalls 30 and 32 get the time from
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[derive(Serialize, Deserialize)]
-pub struct Console {
-    console: Vec<u8>,
-    location: ConsoleLocation,
+pub enum Clock {
+    Real,
+    // milliseconds since the epoch, only moved forward by sleeping
+    Virtual(u64),
 }
 
-impl Console {
+// $zero, $gp and $sp are the only registers set on start
+const DEFINED_ON_START: u64 = 1 | 1 << 28 | 1 << 29;
 
-    pub fn new() -> Console {
-        Console {
-            console: Vec::new(),
-            location: Default::default(),
-        }
-    }
+// sections of a snapshot, each with a layout of its own
+const SNAPSHOT_PROGRAM: &[u8; 4] = b"PROG";
+const SNAPSHOT_PC: &[u8; 4] = b"PC\0\0";
+const SNAPSHOT_REGISTERS: &[u8; 4] = b"REGS";
+const SNAPSHOT_CP0: &[u8; 4] = b"CP0\0";
+const SNAPSHOT_DATA: &[u8; 4] = b"MDAT";
+const SNAPSHOT_DYNAMIC: &[u8; 4] = b"MDYN";
+const SNAPSHOT_TIMER: &[u8; 4] = b"TIMR";
+const SNAPSHOT_DEVICES: &[u8; 4] = b"DEVS";
+const SNAPSHOT_RANDOM: &[u8; 4] = b"RAND";
+const SNAPSHOT_CLOCK: &[u8; 4] = b"CLCK";
+const SNAPSHOT_CONSOLE: &[u8; 4] = b"CONS";
 
-    pub fn syscall(&self, v: u32) {
-        match v {
-            // print string
-            10 => {
-            },
-            _ => {
-                unimplemented!("Syscall not implemented");
-            }
-        }
-    }
+// CP0 registers, numbered as in MIPS
+const COUNT: u32 = 9;
+const COMPARE: u32 = 11;
+pub const STATUS: u32 = 12;
+pub const CAUSE: u32 = 13;
+const EPC: u32 = 14;
 
-    pub fn set_location(&mut self, location: ConsoleLocation) {
-        self.location = location;
-    }
+// interrupts enabled, the exception level, and which interrupts are masked
+const STATUS_IE: u32 = 1;
+const STATUS_EXL: u32 = 1 << 1;
+const STATUS_IM: u32 = 0xff00;
+// user mode with every interrupt enabled, as in MARS
+const STATUS_ON_START: u32 = 0x0000ff11;
 
-    pub fn read_from_console(&mut self) {
-        match self.location {
-            ConsoleLocation::Socket => {
-                unimplemented!()
-            },
-            ConsoleLocation::Terminal => {
-                let mut input = String::new();
-                std::io::stdin().read_line(&mut input).expect("Failed to read from stdin");
-                self.console = input.as_bytes().to_vec();
-            },
-        }
-    }
-
-    pub fn write_to_console(&self) {
-        match self.location {
-            ConsoleLocation::Socket => {
-                unimplemented!()
-            },
-            ConsoleLocation::Terminal => {
-                println!("{}", String::from_utf8(self.console.clone()).unwrap());
-            },
-        }
-    }
+/// where the value of a register came from, kept while calls are checked
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+struct RegisterOrigin {
+    // bytecode that last wrote the register
+    writer: Option<usize>,
+    // entry of the call that poisoned the register on return
+    poisoned: Option<usize>,
+}
 
+/// what executing one bytecode changed, enough to undo it
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+struct Delta {
+    pc: usize,
+    stack: Vec<u32>,
+    // register or memory word and the value it held before,
+    // along with whether the memory word was defined
+    registers: Vec<(u32, u32)>,
+    memory: Vec<(u32, u32, bool)>,
+    defined: u64,
+    console: usize,
+    // console input consumed
+    input: Vec<u8>,
+    // whether an input was taken from or added to the replay log
+    replayed: bool,
+    steps: u64,
+    frames: u64,
+    timer: Timer,
+    // generators before a random syscall changed them
+    random: Option<Random>,
+    clock: Clock,
+    // CP0 register and the value it held before
+    cp0: Vec<(u32, u32)>,
+    // devices before a register of theirs was accessed
+    devices: Option<KeyboardDisplay>,
+    exception: Option<MachineException>,
+    traced: bool,
+    call: Option<CallChange>,
+    origins: Vec<(u32, RegisterOrigin)>,
 }
 
-// #[derive(Debug)]
+/// how a bytecode changed the call stack
+#[derive(Debug)]
 #[derive(Serialize, Deserialize)]
+enum CallChange {
+    Entered,
+    Returned(CallFrame),
+}
+
 pub struct VirtualMachine {
     registers: [u32; 32],
     memory: Memory,
     hilo: [u32; 2],
     pc: usize,
     program: Vec<Bytecode>,
+    // bytecode index of every instruction and of the terminator,
+    // the nth is at text address TEXT_SEGMENT_BASE + 4 * n
+    text: Vec<usize>,
     stack: Stack,
     console: Console,
     pub runtime_dbg: RuntimeDebugInfo,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint: usize,
+    // hits since they were last taken
+    watch_hits: Vec<WatchHit>,
+    // undo records of the most recent bytecodes, oldest first
+    history: VecDeque<Delta>,
+    history_budget: usize,
+    recording: Option<Delta>,
+    check_calls: bool,
+    // indexed by register
+    origins: Vec<RegisterOrigin>,
+    violations: Vec<ConventionViolation>,
+    // bit n is set once register n was written
+    defined: u64,
+    uninit_check: Option<UninitCheck>,
+    uninit_reads: Vec<UninitRead>,
+    // times each backward jump was taken, keyed by target and jump
+    loops: BTreeMap<(usize, usize), u64>,
+    // bytecodes executed
+    steps: u64,
+    replay: Option<Replay>,
+    files: FileTable,
+    random: Random,
+    clock: Clock,
+    // Count and Compare are kept by the timer
+    cp0: [u32; 32],
+    devices: KeyboardDisplay,
+    timer: Timer,
+    // bytecode index interrupts enter, the start of .ktext
+    exception_handler: Option<usize>,
+    bitmap: Option<Bitmap>,
+    // frames ended by writes to the frame register
+    frames: u64,
+    // where every nth frame is saved
+    frame_output: Option<(PathBuf, u64)>,
+    symbols: SymbolTable,
+    // file name and text the program was assembled from
+    source: Option<(String, String)>,
+    // where the DUMP bytecode saves snapshots
+    snapshot_dir: Option<PathBuf>,
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VirtualMachine {
     
     pub fn new() -> VirtualMachine {
+        let mut registers = [0; 32];
+        registers[28] = GLOBAL_POINTER;
+        registers[29] = STACK_POINTER;
         VirtualMachine {
-            registers: [0; 32],
+            registers,
             hilo: [0; 2],
             memory: Memory::new(),
             pc: 0,
             program: Vec::new(),
+            text: Vec::new(),
             stack: Stack::new(),
             console: Console::new(),
             runtime_dbg: RuntimeDebugInfo::new(),
+            watchpoints: BTreeMap::new(),
+            next_watchpoint: 1,
+            watch_hits: Vec::new(),
+            history: VecDeque::new(),
+            history_budget: 0,
+            recording: None,
+            check_calls: false,
+            origins: vec![RegisterOrigin::default(); 34],
+            violations: Vec::new(),
+            defined: DEFINED_ON_START,
+            uninit_check: None,
+            uninit_reads: Vec::new(),
+            loops: BTreeMap::new(),
+            steps: 0,
+            replay: None,
+            files: FileTable::new(),
+            random: Random::new(),
+            clock: Clock::Real,
+            cp0: cp0_on_start(),
+            devices: KeyboardDisplay::new(),
+            timer: Timer::new(),
+            exception_handler: None,
+            bitmap: None,
+            frames: 0,
+            frame_output: None,
+            symbols: SymbolTable::new(),
+            source: None,
+            snapshot_dir: None,
         }
     }
 
+    /// sets the bytecode to execute, jumps through registers need
+    /// the text layout that only `load_program` provides
     pub fn set_program(&mut self, program: Vec<Bytecode>) {
         self.program = program;
+        self.text.clear();
     }
 
     pub fn set_memory(&mut self, memory: &[u8]) {
         self.memory.write(self.memory.last(), memory);
     }
 
+    /// loads the bytecode, data section and debug information
+    /// of an assembled program
+    pub fn load_program(&mut self, program: &ParsedProgram) {
+        let mut bytecode = Vec::new();
+        let mut text = Vec::new();
+        for instruction in program.asm_instructions() {
+            text.push(bytecode.len());
+            bytecode.extend(instruction.to_bytecode());
+        }
+        text.push(bytecode.len());
+        bytecode.push(Bytecode::TERMINATOR);
+        self.set_program(bytecode);
+        self.exception_handler = program.exception_handler.map(|index| text[index]);
+        self.text = text;
+        self.load_data(&program.data);
+        self.symbols = program.symbols.clone();
+        self.source = Some((program.file_name.clone(), program.source.clone()));
+        self.setup_debug(CompileDebugInfo::from_program(program));
+    }
+
+    /// places the assembled data section in memory
+    pub fn load_data(&mut self, data: &[DataMap]) {
+        self.memory.load_data(data);
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
     pub fn setup_debug(&mut self, debug: CompileDebugInfo) {
         self.runtime_dbg = RuntimeDebugInfo::new();
         self.runtime_dbg.attach_compile_debug_info(debug);
     }
 
-    pub fn load(&mut self, filename: &str) -> VirtualMachine {
-        let file = std::fs::File::open(filename).unwrap();
-        let vm: VirtualMachine = bincode::deserialize_from(file).unwrap();
-        vm
+    /// symbols of the loaded program
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
     }
 
-    pub fn dump(&self) {
-        let uid = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string() + ".bin";
-		match bincode::serialize(&self) {
-            Ok(s) => {
-                let mut file = std::fs::File::create(uid).unwrap();
-                file.write_all(s.as_slice()).unwrap();
-            },
-            Err(_) => {
-                eprintln!("Error serializing VM");
+    /// saves the state of the machine so that `load_snapshot` can carry
+    /// on from here, the program is saved as its source and assembled
+    /// again on loading, debugging state such as the undo history,
+    /// the files the program opened and the console backend are not
+    pub fn save_snapshot(&self, path: &Path) -> std::io::Result<()> {
+        let (file_name, source) = self.source.as_ref().ok_or_else(|| invalid("only an assembled program can be saved".to_string()))?;
+        let start = self.instruction_start(self.pc);
+        let addr = self.text_address(start).ok_or_else(|| invalid(format!("bytecode {} is not part of the program", self.pc)))?;
+        let mut snapshot = Snapshot::new();
+
+        let mut program = Vec::new();
+        put_bytes(&mut program, file_name.as_bytes());
+        put_bytes(&mut program, source.as_bytes());
+        snapshot.add(SNAPSHOT_PROGRAM, program);
+
+        // text address of the instruction, how many of its bytecodes
+        // were executed, the operand stack and the bytecodes executed
+        let mut pc = Vec::new();
+        pc.extend(addr.to_le_bytes());
+        pc.extend(((self.pc - start) as u32).to_le_bytes());
+        pc.extend((self.stack.data.len() as u32).to_le_bytes());
+        pc.extend(self.stack.data.iter().flat_map(|value| value.to_le_bytes()));
+        pc.extend(self.steps.to_le_bytes());
+        snapshot.add(SNAPSHOT_PC, pc);
+
+        // $0 to $31, $hi, $lo and a bit per register set once it is written
+        let mut registers: Vec<u8> = self.registers.iter().chain(&self.hilo).flat_map(|value| value.to_le_bytes()).collect();
+        registers.extend(self.defined.to_le_bytes());
+        snapshot.add(SNAPSHOT_REGISTERS, registers);
+
+        // Count and Compare are in the timer's section
+        snapshot.add(SNAPSHOT_CP0, self.cp0.iter().flat_map(|value| value.to_le_bytes()).collect());
+        snapshot.add(SNAPSHOT_DATA, self.memory.save_data());
+        snapshot.add(SNAPSHOT_DYNAMIC, self.memory.save_dynamic());
+        snapshot.add(SNAPSHOT_TIMER, self.timer.save());
+        // followed by the frames ended on the bitmap display
+        let mut devices = self.devices.save();
+        devices.extend(self.frames.to_le_bytes());
+        snapshot.add(SNAPSHOT_DEVICES, devices);
+        snapshot.add(SNAPSHOT_RANDOM, self.random.save());
+        // 0 for the real clock, 1 for a virtual one, then its milliseconds
+        let (virtual_clock, millis) = match self.clock {
+            Clock::Real => (0, 0),
+            Clock::Virtual(millis) => (1, millis),
+        };
+        snapshot.add(SNAPSHOT_CLOCK, [virtual_clock].into_iter().chain(millis.to_le_bytes()).collect());
+        snapshot.add(SNAPSHOT_CONSOLE, self.console.save());
+
+        snapshot.write(BufWriter::new(File::create(path)?))
+    }
+
+    /// a machine saved by `save_snapshot`, its program is assembled
+    /// from the saved source and must lay out the same text and data
+    pub fn load_snapshot(path: &Path) -> std::io::Result<VirtualMachine> {
+        let snapshot = Snapshot::read(BufReader::new(File::open(path)?))?;
+        let (file_name, source) = snapshot.decode(SNAPSHOT_PROGRAM, |f| Ok((f.string()?, f.string()?)))?;
+        let program = parse_source(&file_name, &source)
+            .map_err(|errors| invalid(format!("the program of the snapshot does not assemble: {}", errors[0].msg)))?;
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+
+        snapshot.decode(SNAPSHOT_PC, |f| {
+            let addr = f.u32()?;
+            let offset = f.u32()? as usize;
+            let start = vm.text_pc(addr).ok_or_else(|| f.error(&format!("no instruction at 0x{addr:08x}")))?;
+            let end = vm.text.iter().copied().find(|pc| *pc > start).unwrap_or(vm.program.len());
+            if start + offset >= end {
+                return Err(f.error("a bytecode past the end of its instruction"));
             }
-        }
+            vm.pc = start + offset;
+            vm.stack.data = (0..f.u32()?).map(|_| f.u32()).collect::<std::io::Result<_>>()?;
+            vm.steps = f.u64()?;
+            Ok(())
+        })?;
+        snapshot.decode(SNAPSHOT_REGISTERS, |f| {
+            for value in vm.registers.iter_mut().chain(vm.hilo.iter_mut()) {
+                *value = f.u32()?;
+            }
+            if vm.registers[0] != 0 {
+                return Err(f.error("a value in $zero"));
+            }
+            vm.defined = f.u64()?;
+            Ok(())
+        })?;
+        snapshot.decode(SNAPSHOT_CP0, |f| {
+            for value in vm.cp0.iter_mut() {
+                *value = f.u32()?;
+            }
+            Ok(())
+        })?;
+        snapshot.decode(SNAPSHOT_DATA, |f| vm.memory.restore_data(f))?;
+        snapshot.decode(SNAPSHOT_DYNAMIC, |f| vm.memory.restore_dynamic(f))?;
+        vm.timer = snapshot.decode(SNAPSHOT_TIMER, Timer::restore)?;
+        (vm.devices, vm.frames) = snapshot.decode(SNAPSHOT_DEVICES, |f| Ok((KeyboardDisplay::restore(f)?, f.u64()?)))?;
+        vm.random = snapshot.decode(SNAPSHOT_RANDOM, Random::restore)?;
+        vm.clock = snapshot.decode(SNAPSHOT_CLOCK, |f| match (f.u8()?, f.u64()?) {
+            (0, _) => Ok(Clock::Real),
+            (1, millis) => Ok(Clock::Virtual(millis)),
+            _ => Err(f.error("an unknown clock")),
+        })?;
+        snapshot.decode(SNAPSHOT_CONSOLE, |f| vm.console.restore(f))?;
+        Ok(vm)
+    }
+
+    /// directory the DUMP bytecode saves a snapshot to, named after
+    /// the step it was taken at, nothing is saved without one
+    pub fn set_snapshot_dir(&mut self, dir: Option<PathBuf>) {
+        self.snapshot_dir = dir;
     }
 
     pub fn reg_set(&mut self,reg: u32, value: u32) {
@@ -167,16 +427,19 @@ impl VirtualMachine {
         } 
         if reg == 32 {
             self.hilo[0] = value;
+            self.defined |= 1 << reg;
             return;
         } else if reg == 33 {
             self.hilo[1] = value;
+            self.defined |= 1 << reg;
             return;
         }
         self.registers[reg as usize] = value;
+        self.defined |= 1 << reg;
     }
 
     pub fn reg_get(&self, reg: u32) -> u32 {
-        if reg > 32 {
+        if reg > 33 {
             panic!("Invalid register");
         }
         if reg == 32 {
@@ -187,70 +450,995 @@ impl VirtualMachine {
         self.registers[reg as usize]
     }
 
-    pub fn read_from_console(&mut self) {
-        return self.console.read_from_console();
+    /// watches a register or memory range, returning the watchpoint's id
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_watchpoint;
+        self.watchpoints.insert(id, watchpoint);
+        self.next_watchpoint += 1;
+        id
     }
 
-    pub fn write_to_console(&self) {
-        return self.console.write_to_console();
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.remove(&id)
     }
 
-    // only executes the next instruction
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints.iter().map(|(id, w)| (*id, w))
+    }
+
+    /// watchpoints triggered since the last call, in order
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    /// records a hit for every watchpoint the access triggers,
+    /// a read is passed as a write that leaves the value as is
+    fn watch(&mut self, target: WatchTarget, write: bool, old: u32, new: u32) {
+        for (id, watchpoint) in &self.watchpoints {
+            let triggered = match watchpoint.kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::Change => write && old != new,
+            };
+            if triggered && watchpoint.target.overlaps(&target) {
+                self.watch_hits.push(WatchHit { id: *id, pc: self.pc, target: target.clone(), kind: watchpoint.kind, old, new });
+            }
+        }
+    }
+
+    /// register read by the program, as opposed to the debugger
+    fn read_reg(&mut self, reg: u32) -> Result<u32, MachineException> {
+        let value = self.reg_get(reg);
+        if self.defined & 1 << reg == 0 {
+            self.uninit_read(WatchTarget::Register(reg))?;
+            // reported once
+            self.defined |= 1 << reg;
+        }
+        self.watch(WatchTarget::Register(reg), false, value, value);
+        if let Some(entry) = self.origins[reg as usize].poisoned.filter(|_| self.check_calls) {
+            self.violations.push(ConventionViolation { entry, register: reg, pc: self.pc, kind: ViolationKind::ReadAfterCall });
+            // reported once per call
+            self.set_origin(reg, RegisterOrigin { poisoned: None, ..self.origins[reg as usize] });
+        }
+        Ok(value)
+    }
+
+    fn uninit_read(&mut self, target: WatchTarget) -> Result<(), MachineException> {
+        match self.uninit_check {
+            None => Ok(()),
+            Some(UninitCheck::Warn) => {
+                self.uninit_reads.push(UninitRead { pc: self.pc, target });
+                Ok(())
+            }
+            Some(UninitCheck::Trap) => Err(self.raise(MachineException::UninitializedRead(target))),
+        }
+    }
+
+    /// reports reads of registers and memory that were never written
+    pub fn set_uninit_check(&mut self, check: Option<UninitCheck>) {
+        self.uninit_check = check;
+    }
+
+    /// uninitialized reads since the last call, in order
+    pub fn take_uninit_reads(&mut self) -> Vec<UninitRead> {
+        std::mem::take(&mut self.uninit_reads)
+    }
+
+    /// fills registers other than $zero, $gp and $sp, and memory that
+    /// was not initialized, with noise derived from the seed so that
+    /// programs relying on zeroed state misbehave
+    pub fn randomize(&mut self, seed: u64) {
+        for reg in 1..34 {
+            if DEFINED_ON_START & 1 << reg == 0 {
+                let value = scramble(seed, reg) as u32;
+                match reg {
+                    32 | 33 => self.hilo[reg as usize - 32] = value,
+                    _ => self.registers[reg as usize] = value,
+                }
+            }
+        }
+        self.memory.randomize(seed);
+    }
+
+    fn write_reg(&mut self, reg: u32, value: u32) {
+        let old = self.reg_get(reg);
+        if let Some(delta) = self.recording.as_mut() {
+            delta.registers.push((reg, old));
+        }
+        self.reg_set(reg, value);
+        self.watch(WatchTarget::Register(reg), true, old, self.reg_get(reg));
+        if self.check_calls {
+            self.set_origin(reg, RegisterOrigin { writer: Some(self.pc), poisoned: None });
+        }
+    }
+
+    /// checks every call against the O32 calling convention, callee
+    /// saved registers must be restored on `jr $ra` and caller saved
+    /// registers are poisoned so that reading them is reported
+    pub fn set_convention_check(&mut self, check: bool) {
+        self.check_calls = check;
+    }
+
+    /// violations of the calling convention since the last call, in order
+    pub fn take_violations(&mut self) -> Vec<ConventionViolation> {
+        std::mem::take(&mut self.violations)
+    }
+
+    fn set_origin(&mut self, reg: u32, origin: RegisterOrigin) {
+        let old = std::mem::replace(&mut self.origins[reg as usize], origin);
+        if let Some(delta) = self.recording.as_mut() {
+            delta.origins.push((reg, old));
+        }
+    }
+
+    /// compares callee saved registers with their values at the call
+    /// and poisons the caller saved ones, $v0 and $v1 hold results
+    /// so they are only poisoned when the callee did not set them
+    fn check_return(&mut self, frame: &CallFrame) {
+        for (reg, expected) in &frame.saved {
+            let actual = self.reg_get(*reg);
+            if actual != *expected {
+                let pc = self.origins[*reg as usize].writer.unwrap_or(self.pc);
+                self.violations.push(ConventionViolation { entry: frame.entry, register: *reg, pc, kind: ViolationKind::NotPreserved { expected: *expected, actual } });
+            }
+        }
+        let results = [2, 3].into_iter().filter(|reg| self.origins[*reg as usize].writer.is_none());
+        let poisoned: Vec<u32> = CALLER_SAVED.into_iter().chain(results).collect();
+        for reg in poisoned {
+            let old = self.reg_get(reg);
+            if let Some(delta) = self.recording.as_mut() {
+                delta.registers.push((reg, old));
+            }
+            self.reg_set(reg, POISON);
+            self.set_origin(reg, RegisterOrigin { writer: Some(self.pc), poisoned: Some(frame.entry) });
+        }
+    }
+
+    pub fn console(&self) -> &Console {
+        &self.console
+    }
+
+    /// where console syscalls read input and write output
+    pub fn set_console(&mut self, io: Box<dyn ConsoleIo>) {
+        self.console.set_io(io);
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// moves execution to a bytecode, which should be the first
+    /// bytecode of an instruction as the operand stack is cleared
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+        self.stack.data.clear();
+    }
+
+    /// text address of the instruction starting at a bytecode index
+    fn text_address(&self, pc: usize) -> Option<u32> {
+        self.text.binary_search(&pc).ok().map(|index| TEXT_SEGMENT_BASE + 4 * index as u32)
+    }
+
+    /// bytecode index of the instruction at a text address
+    fn text_pc(&self, addr: u32) -> Option<usize> {
+        let offset = addr.checked_sub(TEXT_SEGMENT_BASE).filter(|offset| offset.is_multiple_of(4))?;
+        self.text.get(offset as usize / 4).copied()
+    }
+
+    /// bytecode index of the instruction that contains pc
+    fn instruction_start(&self, pc: usize) -> usize {
+        match self.text.binary_search(&pc) {
+            Err(index) if index > 0 => self.text[index - 1],
+            _ => pc,
+        }
+    }
+
+    /// a jump to the start of its own instruction changes
+    /// nothing, so the program can never get past it, unless
+    /// it is waiting for an interrupt
+    fn check_jump(&mut self, target: usize) -> Result<(), MachineException> {
+        if target == self.instruction_start(self.pc) && !self.awaits_interrupt() {
+            return Err(self.raise(MachineException::InfiniteLoop));
+        }
+        Ok(())
+    }
+
+    /// moves pc to target, counting backward jumps other than
+    /// returns as loop iterations
+    fn jump(&mut self, target: usize) -> Result<MachineState, MachineException> {
+        if target < self.pc && self.program[self.pc] != Bytecode::RETURN {
+            *self.loops.entry((target, self.pc)).or_default() += 1;
+        }
+        self.runtime_dbg.push_stack_trace(self.pc);
+        self.pc = target;
+        Ok(MachineState::Running)
+    }
+
+    /// the loop that ran the most iterations so far
+    pub fn hottest_loop(&self) -> Option<HotLoop> {
+        self.loops.iter()
+            .max_by_key(|(_, iterations)| **iterations)
+            .map(|((start, end), iterations)| HotLoop { start: *start, end: *end, iterations: *iterations })
+    }
+
+    /// links the return address and enters a call frame, the
+    /// call is the last bytecode of its instruction
+    fn call(&mut self, target: usize, link: u32) -> Result<MachineState, MachineException> {
+        let ret = self.text_address(self.pc + 1).ok_or_else(|| self.raise(MachineException::AddressError))?;
+        self.write_reg(link, ret);
+        let sp = self.reg_get(29);
+        let saved = match self.check_calls {
+            true => {
+                // so that results set by the callee can be told apart
+                for reg in [2, 3] {
+                    self.set_origin(reg, RegisterOrigin::default());
+                }
+                CALLEE_SAVED.iter().map(|reg| (*reg, self.reg_get(*reg))).collect()
+            }
+            false => Vec::new(),
+        };
+        self.runtime_dbg.push_call(CallFrame { call_site: self.pc, entry: target, sp, saved });
+        if let Some(delta) = self.recording.as_mut() {
+            delta.call = Some(CallChange::Entered);
+        }
+        self.runtime_dbg.push_stack_trace(self.pc);
+        self.pc = target;
+        Ok(MachineState::Running)
+    }
+
+    /// console syscalls, numbered as in MARS
+    fn syscall(&mut self, code: u32) -> Result<(), MachineException> {
+        match code {
+            // print_int
+            1 => {
+                let value = self.read_reg(4)? as i32;
+                self.console.print(value.to_string().as_bytes())?;
+            }
+            // print_string
+            4 => {
+                let addr = self.read_reg(4)?;
+                let bytes = self.read_string(addr)?;
+                self.console.print(&bytes)?;
+            }
+            // read_int
+            5 => {
+                let line = self.external(code, VirtualMachine::read_line)?;
+                let line = String::from_utf8_lossy(&line).trim().to_string();
+                let value = line.parse::<i32>().map_err(|_| MachineException::Io(format!("invalid integer input: {line}")))?;
+                self.write_reg(2, value as u32);
+            }
+            // read_string, at most $a1 - 1 bytes are stored followed by a null
+            8 => {
+                let addr = self.read_reg(4)?;
+                let max = self.read_reg(5)? as i32;
+                if max < 1 {
+                    return Ok(());
+                }
+                let mut bytes = self.external(code, VirtualMachine::read_line)?;
+                if (bytes.len() as i32) < max - 1 {
+                    bytes.push(b'\n');
+                }
+                bytes.truncate(max as usize - 1);
+                bytes.push(0);
+                self.store_bytes(addr, &bytes)?;
+            }
+            // print_char
+            11 => {
+                let value = self.read_reg(4)?;
+                self.console.print(&[value as u8])?;
+            }
+            // read_char
+            12 => {
+                let byte = self.external(code, |vm| vm.console.read_char().map(|byte| vec![byte]))?;
+                self.write_reg(2, byte.first().copied().unwrap_or_default() as u32);
+            }
+            // open, $v0 is the descriptor or -1
+            13 => {
+                let addr = self.read_reg(4)?;
+                // the mode in $a2 is ignored, like MARS does
+                let flags = self.read_reg(5)?;
+                let name = String::from_utf8_lossy(&self.read_string(addr)?).to_string();
+                let fd = self.files.open(&name, flags).map_or(-1, |fd| fd as i32);
+                self.write_reg(2, fd as u32);
+            }
+            // read, $v0 is the number of bytes read, 0 at the end or -1
+            14 => {
+                let fd = self.read_reg(4)?;
+                let addr = self.read_reg(5)?;
+                let max = self.read_reg(6)? as i32;
+                let read = match fd {
+                    0 if max >= 0 => Some(self.external(code, |vm| vm.console.read_bytes(max as usize))?),
+                    fd if max >= 0 && self.files.is_readable(fd) => {
+                        let read = |vm: &mut VirtualMachine| vm.files.read(fd, max as usize).map_err(|e| MachineException::Io(e.to_string()));
+                        Some(self.external(code, read)?)
+                    }
+                    _ => None,
+                };
+                if let Some(bytes) = &read {
+                    self.store_bytes(addr, bytes)?;
+                }
+                self.write_reg(2, read.map_or(-1, |bytes| bytes.len() as i32) as u32);
+            }
+            // write, $v0 is the number of bytes written or -1
+            15 => {
+                let fd = self.read_reg(4)?;
+                let addr = self.read_reg(5)?;
+                let len = self.read_reg(6)? as i32;
+                // stdout and stderr are always writable, memory is only
+                // read once the descriptor is known to be
+                let writable = matches!(fd, 1 | 2) || self.files.is_writable(fd);
+                let written = match writable && len >= 0 {
+                    true => {
+                        let bytes = (0..len as u32).map(|i| self.memory.read_byte(addr.wrapping_add(i))).collect::<Result<Vec<u8>, _>>()?;
+                        match fd {
+                            1 | 2 => {
+                                self.console.print(&bytes)?;
+                                len
+                            }
+                            fd => self.files.write(fd, &bytes).map_or(-1, |n| n as i32),
+                        }
+                    }
+                    false => -1,
+                };
+                self.write_reg(2, written as u32);
+            }
+            // close
+            16 => {
+                let fd = self.read_reg(4)?;
+                self.files.close(fd);
+            }
+            // time, milliseconds since the epoch in $a0 (low) and $a1 (high)
+            30 => {
+                let now = match self.clock {
+                    Clock::Virtual(now) => now,
+                    Clock::Real => {
+                        let now = self.external(code, |_| Ok(epoch_millis().to_le_bytes().to_vec()))?;
+                        u64::from_le_bytes(now.try_into().unwrap_or_default())
+                    }
+                };
+                self.write_reg(4, now as u32);
+                self.write_reg(5, (now >> 32) as u32);
+            }
+            // sleep for $a0 milliseconds
+            32 => {
+                let millis = self.read_reg(4)?;
+                match self.clock {
+                    Clock::Virtual(now) => self.clock = Clock::Virtual(now + millis as u64),
+                    Clock::Real => std::thread::sleep(std::time::Duration::from_millis(millis as u64)),
+                }
+            }
+            // set seed of generator $a0 to $a1
+            40 => {
+                let id = self.read_reg(4)?;
+                let seed = self.read_reg(5)?;
+                self.save_random();
+                self.random.seed_generator(id, seed as u64);
+            }
+            // random int from generator $a0 in $a0
+            41 => {
+                let id = self.read_reg(4)?;
+                self.seed_random(code)?;
+                let value = self.random.next_int(id);
+                self.write_reg(4, value);
+            }
+            // random int in 0..$a1 from generator $a0 in $a0
+            42 => {
+                let id = self.read_reg(4)?;
+                let bound = self.read_reg(5)?;
+                if bound as i32 <= 0 {
+                    return Err(MachineException::Io(format!("upper bound of random range must be positive, got {}", bound as i32)));
+                }
+                self.seed_random(code)?;
+                let value = self.random.next_below(id, bound);
+                self.write_reg(4, value);
+            }
+            // random float and double from generator $a0, there are no
+            // floating point registers so the bits are put in $v0 and,
+            // for the high word of a double, $v1
+            43 => {
+                let id = self.read_reg(4)?;
+                self.seed_random(code)?;
+                let value = self.random.next_float(id);
+                self.write_reg(2, value.to_bits());
+            }
+            44 => {
+                let id = self.read_reg(4)?;
+                self.seed_random(code)?;
+                let bits = self.random.next_double(id).to_bits();
+                self.write_reg(2, bits as u32);
+                self.write_reg(3, (bits >> 32) as u32);
+            }
+            _ => return Err(MachineException::InvalidSyscall(code)),
+        }
+        Ok(())
+    }
+
+    /// keeps the generators as they were before the syscall for undo
+    fn save_random(&mut self) {
+        if let Some(delta) = self.recording.as_mut() {
+            delta.random.get_or_insert_with(|| self.random.clone());
+        }
+    }
+
+    /// seeds generators from the clock on first use, unless a seed was
+    /// given, the seed is an input that is recorded and replayed
+    fn seed_random(&mut self, syscall: u32) -> Result<(), MachineException> {
+        self.save_random();
+        if self.random.seed().is_none() {
+            let seed = self.external(syscall, |_| Ok(epoch_nanos().to_le_bytes().to_vec()))?;
+            self.random.set_seed(u64::from_le_bytes(seed.try_into().unwrap_or_default()));
+        }
+        Ok(())
+    }
+
+    /// seed of random generators the program does not seed itself,
+    /// by default they are seeded from the clock
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.random.set_seed(seed);
+    }
+
+    /// where syscalls 30 and 32 get the time from
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// the null terminated string at addr
+    fn read_string(&self, mut addr: u32) -> Result<Vec<u8>, MachineException> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.memory.read_byte(addr)? {
+                0 => return Ok(bytes),
+                byte => bytes.push(byte),
+            }
+            addr = addr.wrapping_add(1);
+        }
+    }
+
+    /// lets the file syscalls open files inside dir, which they cannot
+    /// do by default, opened files are not closed by undo
+    pub fn set_sandbox(&mut self, dir: Option<std::path::PathBuf>) {
+        self.files.set_sandbox(dir);
+    }
+
+    /// input a syscall takes from outside the program, which comes
+    /// from the replay log while replaying and is added to it while
+    /// recording
+    fn external(&mut self, syscall: u32, read: impl FnOnce(&mut VirtualMachine) -> Result<Vec<u8>, MachineException>) -> Result<Vec<u8>, MachineException> {
+        let step = self.steps;
+        let replayed = match self.replay.as_mut() {
+            Some(replay) => replay.next(step, syscall)?,
+            None => None,
+        };
+        let input = match replayed {
+            Some(input) => input,
+            None => {
+                let input = read(self);
+                // keeps the console input consumed so undo can give it back
+                let consumed = self.console.take_consumed();
+                if let Some(delta) = self.recording.as_mut() {
+                    delta.input.extend(consumed);
+                }
+                input?
+            }
+        };
+        if let Some(replay) = self.replay.as_mut() {
+            replay.record(Event { step, syscall, input: input.clone() });
+            if let Some(delta) = self.recording.as_mut() {
+                delta.replayed = true;
+            }
+        }
+        Ok(input)
+    }
+
+    fn read_line(&mut self) -> Result<Vec<u8>, MachineException> {
+        self.console.read_line()
+    }
+
+    /// adds the input of every syscall to a new replay log
+    pub fn record(&mut self) {
+        self.replay = Some(Replay::Recording(ReplayLog::new()));
+    }
+
+    /// takes syscall input from a recorded log instead of the console
+    pub fn replay(&mut self, log: ReplayLog) {
+        self.replay = Some(Replay::Replaying(log, 0));
+    }
+
+    /// the log being recorded or replayed
+    pub fn replay_log(&self) -> Option<&ReplayLog> {
+        self.replay.as_ref().map(|replay| replay.log())
+    }
+
+    /// bytecodes executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn cp0(&self, reg: u32) -> u32 {
+        match reg {
+            COUNT => self.timer.count(),
+            COMPARE => self.timer.compare(),
+            _ => self.cp0[reg as usize],
+        }
+    }
+
+    /// sets a CP0 register as `mtc0` would
+    pub fn set_cp0(&mut self, reg: u32, value: u32) {
+        self.write_cp0(reg, value);
+    }
+
+    fn write_cp0(&mut self, reg: u32, value: u32) {
+        match reg {
+            COUNT => return self.timer.set_count(value),
+            COMPARE => return self.timer.set_compare(value),
+            _ => {}
+        }
+        if let Some(delta) = self.recording.as_mut() {
+            delta.cp0.push((reg, self.cp0[reg as usize]));
+        }
+        self.cp0[reg as usize] = value;
+    }
+
+    /// keeps the devices as they were before the bytecode for undo
+    fn save_devices(&mut self) {
+        if let Some(delta) = self.recording.as_mut() {
+            delta.devices.get_or_insert_with(|| self.devices.clone());
+        }
+    }
+
+    /// gives the keyboard the next byte of console input if it has
+    /// none, the input is recorded and replayed under the address of
+    /// the data register
+    fn poll_keyboard(&mut self) -> Result<(), MachineException> {
+        if self.devices.wants_input() {
+            let input = self.external(RECEIVER_DATA, |vm| vm.console.read_bytes(1))?;
+            self.save_devices();
+            self.devices.receive(input.first().copied());
+        }
+        Ok(())
+    }
+
+    /// reads a keyboard or display register, a byte is typed
+    /// whenever the keyboard's ready bit is polled
+    fn read_device(&mut self, addr: u32) -> Result<u32, MachineException> {
+        if let Some(value) = self.timer.read(addr) {
+            return Ok(value);
+        }
+        if addr == FRAME_REGISTER && self.bitmap.is_some() {
+            return Ok(self.frames as u32);
+        }
+        if addr == RECEIVER_CONTROL {
+            self.poll_keyboard()?;
+        }
+        self.save_devices();
+        self.devices.read(addr).ok_or(MachineException::AddressError)
+    }
+
+    fn write_device(&mut self, addr: u32, value: u32) -> Result<(), MachineException> {
+        if matches!(addr, TIMER_CONTROL | TIMER_PERIOD) {
+            return self.timer.write(addr, value);
+        }
+        if addr == FRAME_REGISTER && self.bitmap.is_some() {
+            return self.end_frame();
+        }
+        self.save_devices();
+        if let Some(byte) = self.devices.write(addr, value)? {
+            self.console.print(&[byte])?;
+        }
+        Ok(())
+    }
+
+    /// attaches a bitmap display, whose framebuffer is ordinary memory
+    pub fn set_bitmap(&mut self, bitmap: Option<Bitmap>) {
+        self.bitmap = bitmap;
+    }
+
+    pub fn bitmap(&self) -> Option<&Bitmap> {
+        self.bitmap.as_ref()
+    }
+
+    /// writes the bitmap display as it is now to an image file
+    pub fn save_bitmap(&self, path: &std::path::Path) -> std::io::Result<()> {
+        let bitmap = self.bitmap.as_ref().ok_or(std::io::Error::other("there is no bitmap display"))?;
+        bitmap.save(&self.memory, path)
+    }
+
+    /// saves every nth frame to an image numbered by the frame, the
+    /// 4th frame of `out.png` is `out-0004.png`, images saved are
+    /// not removed by undo
+    pub fn save_frames(&mut self, path: std::path::PathBuf, every: u64) {
+        self.frame_output = Some((path, every.max(1)));
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn end_frame(&mut self) -> Result<(), MachineException> {
+        self.frames += 1;
+        if let Some((path, every)) = &self.frame_output {
+            if self.frames.is_multiple_of(*every) {
+                self.save_bitmap(&frame_path(path, self.frames)).map_err(|e| MachineException::Io(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// enters the exception handler before the instruction at epc if a
+    /// device raises an interrupt that is enabled, unless an exception
+    /// is already being handled, Cause holds the interrupts pending and
+    /// EPC the address of the instruction to return to
+    fn interrupt(&mut self, epc: u32) -> Result<(), MachineException> {
+        let Some(handler) = self.exception_handler else { return Ok(()) };
+        let status = self.cp0[STATUS as usize];
+        if status & STATUS_IE == 0 || status & STATUS_EXL != 0 {
+            return Ok(());
+        }
+        if self.devices.keyboard_interrupts() {
+            self.poll_keyboard()?;
+        }
+        let pending = self.devices.pending() | self.timer.pending();
+        if pending & status & STATUS_IM == 0 {
+            return Ok(());
+        }
+        self.save_devices();
+        self.devices.acknowledge();
+        // exception code 0 is an interrupt
+        self.write_cp0(CAUSE, pending);
+        self.write_cp0(EPC, epc);
+        self.write_cp0(STATUS, status | STATUS_EXL);
+        self.pc = handler;
+        Ok(())
+    }
+
+    /// whether an interrupt can still be taken without the program
+    /// doing anything, Count reaches Compare again once it wraps
+    fn awaits_interrupt(&self) -> bool {
+        let status = self.cp0[STATUS as usize];
+        if self.exception_handler.is_none() || status & STATUS_IE == 0 || status & STATUS_EXL != 0 {
+            return false;
+        }
+        let timer = if self.timer.raises_interrupts() { TIMER_INTERRUPT } else { 0 };
+        let keyboard = if self.devices.keyboard_interrupts() && self.devices.wants_input() { KEYBOARD_INTERRUPT } else { 0 };
+        let sources = COMPARE_INTERRUPT | timer | keyboard | self.devices.pending() | self.timer.pending();
+        sources & status & STATUS_IM != 0
+    }
+
+    /// writes bytes to memory one at a time, recording the words
+    /// they are in for undo and checking watchpoints
+    fn store_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<(), MachineException> {
+        for (i, byte) in bytes.iter().enumerate() {
+            let addr = addr.wrapping_add(i as u32);
+            let word = addr & !3;
+            let old = self.memory.read_word(word)?;
+            let defined = self.memory.is_defined(word, 4);
+            self.memory.write_byte(addr, *byte)?;
+            if let Some(delta) = self.recording.as_mut() {
+                delta.memory.push((word, old, defined));
+            }
+            let new = self.memory.read_word(word)?;
+            self.watch(WatchTarget::Memory(word..word + 4), true, old, new);
+        }
+        Ok(())
+    }
+
+    /// records the exception so that execution cannot continue
+    fn raise(&mut self, exception: MachineException) -> MachineException {
+        self.runtime_dbg.set_exception(exception.clone());
+        exception
+    }
+
+    fn pop(&mut self) -> Result<u32, MachineException> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.raise(MachineException::StackUnderflow)),
+        }
+    }
+
+    /// error message for an exception raised by execute, citing
+    /// the source of the instruction that raised it
+    pub fn describe_exception(&self, exception: &MachineException) -> String {
+        self.runtime_dbg.describe_exception(self.pc, exception)
+    }
+
+    /// number of bytecodes that can be undone, 0 turns recording off,
+    /// the oldest records are dropped once the budget is exceeded
+    pub fn set_history_budget(&mut self, budget: usize) {
+        self.history_budget = budget;
+        while self.history.len() > budget {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// forgets the recorded history, for changes made from outside
+    /// the program that undoing its steps would not account for
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// undoes the last executed bytecode, returns false once the
+    /// recorded history is exhausted, writes that are undone trigger
+    /// write and change watchpoints as they did when executed
+    pub fn step_back(&mut self) -> bool {
+        let Some(delta) = self.history.pop_back() else { return false };
+        self.pc = delta.pc;
+        self.stack.data = delta.stack;
+        for (addr, old, defined) in delta.memory.into_iter().rev() {
+            let new = self.memory.read_word(addr).unwrap_or_default();
+            // the address was accessed, so it is valid
+            let _ = self.memory.write_word(addr, old);
+            self.memory.set_defined(addr, 4, defined);
+            if old != new || defined {
+                self.watch(WatchTarget::Memory(addr..addr + 4), true, old, new);
+            }
+        }
+        for (reg, old) in delta.registers.into_iter().rev() {
+            let new = self.reg_get(reg);
+            self.reg_set(reg, old);
+            self.watch(WatchTarget::Register(reg), true, old, new);
+        }
+        self.defined = delta.defined;
+        self.console.truncate_output(delta.console);
+        self.console.unread(&delta.input);
+        self.steps = delta.steps;
+        self.frames = delta.frames;
+        self.timer = delta.timer;
+        self.clock = delta.clock;
+        if let Some(random) = delta.random {
+            self.random = random;
+        }
+        for (reg, old) in delta.cp0.into_iter().rev() {
+            self.cp0[reg as usize] = old;
+        }
+        if let Some(devices) = delta.devices {
+            self.devices = devices;
+        }
+        if delta.replayed {
+            if let Some(replay) = self.replay.as_mut() {
+                replay.rewind();
+            }
+        }
+        match delta.exception {
+            Some(exception) => self.runtime_dbg.set_exception(exception),
+            None => self.runtime_dbg.clear_exception(),
+        }
+        if delta.traced {
+            self.runtime_dbg.pop_stack_trace();
+        }
+        for (reg, origin) in delta.origins.into_iter().rev() {
+            self.origins[reg as usize] = origin;
+        }
+        match delta.call {
+            Some(CallChange::Entered) => {
+                self.runtime_dbg.pop_call();
+            }
+            Some(CallChange::Returned(frame)) => self.runtime_dbg.push_call(frame),
+            None => {}
+        }
+        true
+    }
+
+    /// executes the next bytecode, recording how to undo it
+    /// while a history budget is set
     pub fn execute(&mut self) -> Result<MachineState, MachineException> {
+        if let Some(exception) = self.runtime_dbg.get_exception() {
+            return Err(exception);
+        }
+        if self.history_budget == 0 {
+            return self.execute_bytecode();
+        }
+
+        self.recording = Some(Delta {
+            pc: self.pc,
+            stack: self.stack.data.clone(),
+            registers: Vec::new(),
+            memory: Vec::new(),
+            console: self.console.output_len(),
+            input: Vec::new(),
+            replayed: false,
+            steps: self.steps,
+            frames: self.frames,
+            timer: self.timer,
+            random: None,
+            clock: self.clock,
+            cp0: Vec::new(),
+            devices: None,
+            exception: None,
+            traced: false,
+            call: None,
+            origins: Vec::new(),
+            defined: self.defined,
+        });
+        let result = self.execute_bytecode();
+        let mut delta = self.recording.take().unwrap();
+        // every bytecode that executes without an exception is traced
+        delta.traced = result.is_ok();
+        if self.history.len() >= self.history_budget {
+            self.history.pop_front();
+        }
+        self.history.push_back(delta);
+        result
+    }
+
+    // only executes the next instruction
+    fn execute_bytecode(&mut self) -> Result<MachineState, MachineException> {
         if self.pc >= self.program.len() {
-            panic!("Program counter out of bounds");
-        } else if self.runtime_dbg.get_exception().is_some() {
-            return Err(self.runtime_dbg.get_exception().unwrap());
+            return Err(self.raise(MachineException::PcOutOfBounds(self.pc)));
         }
 
-        let current_instruction = &self.program[self.pc];
+        self.steps += 1;
+        // at the start of an instruction, which may be the handler's
+        if let Some(addr) = self.text_address(self.pc) {
+            self.interrupt(addr).map_err(|e| self.raise(e))?;
+            self.timer.tick();
+        }
+        let current_instruction = self.program[self.pc].clone();
         match current_instruction {
             Bytecode::PUSH(val) => {
                 self.stack.push(val.lift_immediate());
             },
             // gets value from register and pushes it to the stack
             Bytecode::GETP(reg) => {
-                let value = self.reg_get(reg.lift_register());
+                let reg = reg.lift_register();
+                if reg > 33 {
+                    return Err(self.raise(MachineException::InvalidRegister(reg)));
+                }
+                let value = self.read_reg(reg)?;
                 self.stack.push(value);
             },
             // pops value from stack and sets it to register
             Bytecode::SETO(reg) => {
-                let value = self.stack.pop().expect("Stack underflow");
-                self.reg_set(reg.lift_register(), value);
+                let reg = reg.lift_register();
+                if reg > 33 {
+                    return Err(self.raise(MachineException::InvalidRegister(reg)));
+                }
+                let value = self.pop()?;
+                self.write_reg(reg, value);
             },
             // adds two values from the stack and pushes the result
             Bytecode::ADD => {
-                let op1 = self.stack.pop().expect("Stack underflow");
-                let op2 = self.stack.pop().expect("Stack underflow");
-                self.stack.push(op1 + op2);
+                let op1 = self.pop()?;
+                let op2 = self.pop()?;
+                let result = (op1 as i32).checked_add(op2 as i32).ok_or_else(|| self.raise(MachineException::Overflow))?;
+                self.stack.push(result as u32);
+            },
+            Bytecode::LOAD(offset) => {
+                let addr = self.pop()?.wrapping_add(offset);
+                if is_mmio(addr) {
+                    let value = self.read_device(addr).map_err(|e| self.raise(e))?;
+                    self.stack.push(value);
+                    self.runtime_dbg.push_stack_trace(self.pc);
+                    self.pc += 1;
+                    return Ok(MachineState::Running);
+                }
+                let value = self.memory.read_word(addr).map_err(|e| self.raise(e))?;
+                self.watch(WatchTarget::Memory(addr..addr + 4), false, value, value);
+                if !self.memory.is_defined(addr, 4) {
+                    self.uninit_read(WatchTarget::Memory(addr..addr + 4))?;
+                    // reported once
+                    if let Some(delta) = self.recording.as_mut() {
+                        delta.memory.push((addr, value, false));
+                    }
+                    self.memory.set_defined(addr, 4, true);
+                }
+                self.stack.push(value);
+            },
+            Bytecode::STORE(offset) => {
+                let addr = self.pop()?.wrapping_add(offset);
+                let value = self.pop()?;
+                if is_mmio(addr) {
+                    self.write_device(addr, value).map_err(|e| self.raise(e))?;
+                    self.runtime_dbg.push_stack_trace(self.pc);
+                    self.pc += 1;
+                    return Ok(MachineState::Running);
+                }
+                let old = self.memory.read_word(addr).map_err(|e| self.raise(e))?;
+                let defined = self.memory.is_defined(addr, 4);
+                self.memory.write_word(addr, value).map_err(|e| self.raise(e))?;
+                if let Some(delta) = self.recording.as_mut() {
+                    delta.memory.push((addr, old, defined));
+                }
+                self.watch(WatchTarget::Memory(addr..addr + 4), true, old, value);
             },
             Bytecode::TERMINATOR => {
                 eprintln!("Reached end of program without exit instruction");
-                return Err(MachineException::AddressError);
+                return Err(self.raise(MachineException::AddressError));
             },
             Bytecode::JUMP(where_to) => {
+                self.check_jump(where_to as usize)?;
+                return self.jump(where_to as usize);
+            },
+            Bytecode::CALL(target) => {
+                return self.call(target as usize, 31);
+            },
+            Bytecode::CALLR(link) => {
+                let addr = self.pop()?;
+                let target = self.text_pc(addr).ok_or_else(|| self.raise(MachineException::AddressError))?;
+                return self.call(target, link.lift_register());
+            },
+            Bytecode::JUMPR | Bytecode::RETURN => {
+                let addr = self.pop()?;
+                let target = self.text_pc(addr).ok_or_else(|| self.raise(MachineException::AddressError))?;
+                self.check_jump(target)?;
+                if current_instruction == Bytecode::RETURN {
+                    if let Some(frame) = self.runtime_dbg.pop_call() {
+                        if self.check_calls {
+                            self.check_return(&frame);
+                        }
+                        if let Some(delta) = self.recording.as_mut() {
+                            delta.call = Some(CallChange::Returned(frame));
+                        }
+                    }
+                }
+                return self.jump(target);
+            },
+            Bytecode::GETC(reg) => {
+                if reg > 31 {
+                    return Err(self.raise(MachineException::InvalidRegister(reg)));
+                }
+                self.stack.push(self.cp0(reg));
+            },
+            Bytecode::SETC(reg) => {
+                if reg > 31 {
+                    return Err(self.raise(MachineException::InvalidRegister(reg)));
+                }
+                let value = self.pop()?;
+                self.write_cp0(reg, value);
+            },
+            Bytecode::ERET => {
+                let target = self.text_pc(self.cp0[EPC as usize]).ok_or_else(|| self.raise(MachineException::AddressError))?;
+                self.write_cp0(STATUS, self.cp0[STATUS as usize] & !STATUS_EXL);
                 self.runtime_dbg.push_stack_trace(self.pc);
-                self.pc = where_to.to_owned() as usize;
+                self.pc = target;
                 return Ok(MachineState::Running);
             },
             Bytecode::DUMP => {
-                self.dump();
+                if let Some(dir) = &self.snapshot_dir {
+                    let path = dir.join(format!("vm-{}.snapshot", self.steps));
+                    self.save_snapshot(&path).map_err(|e| self.raise(MachineException::Io(e.to_string())))?;
+                }
             },
             Bytecode::HALT => {
                 return Ok(MachineState::Halted);
             },
             Bytecode::SYSCALL => {
-                self.console.syscall(self.reg_get(2))
+                let code = self.read_reg(2)?;
+                match code {
+                    // exit
+                    10 => {
+                        self.runtime_dbg.push_stack_trace(self.pc);
+                        return Ok(MachineState::Halted);
+                    },
+                    _ => self.syscall(code).map_err(|e| self.raise(e))?,
+                }
             }
             _ => { unimplemented!("Instruction not implemented: {:?}", current_instruction) }
         }
         self.runtime_dbg.push_stack_trace(self.pc);
         self.pc += 1;
-        return Ok(MachineState::Running);
+        Ok(MachineState::Running)
     }
 
 }
 
+fn cp0_on_start() -> [u32; 32] {
+    let mut cp0 = [0; 32];
+    cp0[STATUS as usize] = STATUS_ON_START;
+    cp0
+}
+
+fn epoch_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn epoch_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
 impl std::fmt::Debug for VirtualMachine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // Manually implement the Debug trait for VirtualMachine
@@ -331,7 +1519,7 @@ mod tests {
         assert!(matches!(vm.reg_get(register_to_addr("$t1".to_string()).unwrap()), 1));
         assert!(matches!(vm.reg_get(register_to_addr("$t2".to_string()).unwrap()), 1));
         assert!(matches!(vm.reg_get(register_to_addr("$t3".to_string()).unwrap()), 2));
-        assert!(vm.stack.peek() == None);
+        assert!(vm.stack.peek().is_none());
 
     }
 
@@ -354,7 +1542,693 @@ mod tests {
         // SET $t1
         vm.execute().unwrap();
         assert!(matches!(vm.reg_get(register_to_addr("$t1".to_string()).unwrap()) as i16, -6));
-        assert!(vm.stack.peek() == None);
+        assert!(vm.stack.peek().is_none());
     }
 
+    #[test]
+    fn test_watchpoints() {
+        let src = "main: li $s0, 7\n  sw $s0, -4($sp)\n  lw $t0, -4($sp)\n  li $s0, 7\n  syscall";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.set_program(program.asm_instructions().iter().flat_map(|i| i.to_bytecode()).collect());
+
+        let slot = STACK_POINTER - 4;
+        let change = vm.add_watchpoint(Watchpoint { target: WatchTarget::Register(16), kind: WatchKind::Change });
+        let read = vm.add_watchpoint(Watchpoint { target: WatchTarget::Memory(slot..slot + 4), kind: WatchKind::Read });
+        let write = vm.add_watchpoint(Watchpoint { target: WatchTarget::Memory(slot + 2..slot + 3), kind: WatchKind::Write });
+
+        while vm.execute().is_ok() {}
+        let hits: Vec<(usize, usize, u32, u32)> = vm.take_watch_hits().iter().map(|h| (h.id, h.pc, h.old, h.new)).collect();
+        // the second li does not change $s0
+        assert_eq!(hits, vec![(change, 1, 0, 7), (write, 4, 0, 7), (read, 6, 7, 7)]);
+        assert!(vm.take_watch_hits().is_empty());
+
+        assert!(vm.remove_watchpoint(read).is_some());
+        assert_eq!(vm.watchpoints().count(), 2);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let src = "main: li $s0, 7\n  sw $s0, -4($sp)\n  li $s0, 9\n  add $s0, $s0, $s0\n  li $v0, 10\n  syscall";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.set_program(program.asm_instructions().iter().flat_map(|i| i.to_bytecode()).collect());
+        vm.set_history_budget(8);
+
+        while let Ok(MachineState::Running) = vm.execute() {}
+        // 14 bytecodes ran but only the last 8 are kept
+        assert_eq!(vm.history_len(), 8);
+        assert_eq!(vm.reg_get(16), 18);
+
+        let watch = vm.add_watchpoint(Watchpoint { target: WatchTarget::Register(16), kind: WatchKind::Change });
+        for _ in 0..4 {
+            assert!(vm.step_back());
+        }
+        // back before the SETO of add
+        assert_eq!(vm.pc(), 10);
+        assert_eq!(vm.reg_get(16), 9);
+        assert_eq!(vm.take_watch_hits(), vec![WatchHit { id: watch, pc: 10, target: WatchTarget::Register(16), kind: WatchKind::Change, old: 9, new: 18 }]);
+
+        while vm.step_back() {}
+        assert_eq!(vm.pc(), 6);
+        assert_eq!(vm.reg_get(16), 7);
+        assert_eq!(vm.memory().read_word(STACK_POINTER - 4).unwrap(), 7);
+
+        vm.set_history_budget(0);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert_eq!(vm.reg_get(16), 18);
+        assert!(!vm.step_back());
+
+        let mut vm = VirtualMachine::new();
+        vm.set_program(program.asm_instructions().iter().flat_map(|i| i.to_bytecode()).collect());
+        vm.set_history_budget(100);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        while vm.step_back() {}
+        assert_eq!(vm.pc(), 0);
+        assert_eq!(vm.memory().read_word(STACK_POINTER - 4).unwrap(), 0);
+        assert!(vm.runtime_dbg.stack_trace().is_empty());
+    }
+
+    #[test]
+    fn test_exception_cites_source() {
+        let program = crate::parser::parse_source("prog.s", "main: li $t0, 0x7fff\n  add $t0, $t0, $t0\n  syscall").unwrap();
+        let mut bytecode = program.asm_instructions().iter().flat_map(|i| i.to_bytecode()).collect::<Vec<Bytecode>>();
+        bytecode.push(Bytecode::TERMINATOR);
+
+        let mut vm = VirtualMachine::new();
+        vm.set_program(bytecode);
+        vm.setup_debug(CompileDebugInfo::from_program(&program));
+
+        let exception = loop {
+            match vm.execute() {
+                Ok(MachineState::Running) => {},
+                Ok(MachineState::Halted) => panic!("program should not halt"),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(exception, MachineException::InvalidSyscall(0)));
+        assert_eq!(vm.describe_exception(&exception), "invalid syscall: 0\n  --> prog.s:3:3 syscall");
+        // the machine stays stopped once an exception was raised
+        assert!(vm.execute().is_err());
+    }
+
+    #[test]
+    fn test_calls() {
+        let src = "\
+main:   jal leaf
+        li $t0, -8
+        add $sp, $sp, $t0
+        la $t1, outer
+        jalr $t1
+        li $v0, 10
+        syscall
+leaf:   li $s0, 3
+        jr $ra
+outer:  jal inner
+        jr $ra
+inner:  lw $t2, 1($zero)
+        jr $ra
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_history_budget(100);
+
+        // the return from leaf comes back to the instruction after jal
+        for _ in 0..5 {
+            vm.execute().unwrap();
+        }
+        assert_eq!(vm.reg_get(31), TEXT_SEGMENT_BASE + 4);
+        assert_eq!(vm.reg_get(16), 3);
+        assert!(vm.runtime_dbg.call_stack().is_empty());
+
+        let exception = loop {
+            if let Err(e) = vm.execute() {
+                break e;
+            }
+        };
+        let frames: Vec<&str> = vm.runtime_dbg.call_stack().iter().map(|f| vm.runtime_dbg.frame_name(f.entry)).collect();
+        assert_eq!(frames, vec!["outer", "inner"]);
+        assert_eq!(vm.describe_exception(&exception), "\
+address error
+  --> prog.s:12:9 lw $t2, 1($zero)
+backtrace: main → outer → inner
+  #0 inner called at prog.s:10:9 jal inner, $sp = 0x7fffeff4
+  #1 outer called at prog.s:5:9 jalr $t1, $sp = 0x7fffeff4
+  #2 main");
+
+        // undoing the calls leaves their frames
+        while vm.step_back() {}
+        assert_eq!(vm.pc(), 0);
+        assert!(vm.runtime_dbg.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_convention_check() {
+        let src = "\
+main:   li $s0, 1
+        li $t0, 2
+        jal bad
+        add $t1, $t0, $t0
+        jal good
+        add $t2, $v0, $v0
+        li $v0, 10
+        syscall
+bad:    li $s0, 5
+        jr $ra
+good:   li $v0, 3
+        jr $ra
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_convention_check(true);
+        while let Ok(MachineState::Running) = vm.execute() {}
+
+        let violations: Vec<String> = vm.take_violations().iter().map(|v| vm.runtime_dbg.describe_violation(v)).collect();
+        assert_eq!(violations, vec![
+            "bad does not preserve $s0, 0x00000001 became 0x00000005\n  --> prog.s:9:9 li $s0, 5",
+            "$t0 is read after a call to bad, which need not preserve it\n  --> prog.s:4:9 add $t1, $t0, $t0",
+        ]);
+        assert_eq!(vm.reg_get(8), POISON);
+        // the result of good is not poisoned
+        assert_eq!(vm.reg_get(10), 6);
+
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(vm.take_violations().is_empty());
+        assert_eq!(vm.reg_get(9), 4);
+    }
+
+    #[test]
+    fn test_uninit_reads() {
+        let src = "\
+        .data
+buf:    .space 8
+        .text
+main:   add $t1, $t0, $t0
+        add $t1, $t0, $t0
+        lw $t2, buf
+        sw $t1, buf
+        lw $t2, buf
+        lw $t3, 0($sp)
+        li $v0, 10
+        syscall
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_uninit_check(Some(UninitCheck::Warn));
+        while let Ok(MachineState::Running) = vm.execute() {}
+        let reads: Vec<String> = vm.take_uninit_reads().iter().map(|read| vm.runtime_dbg.describe_uninit_read(read)).collect();
+        assert_eq!(reads, vec![
+            "read of uninitialized $t0\n  --> prog.s:4:9 add $t1, $t0, $t0",
+            "read of uninitialized 0x10010000..0x10010004\n  --> prog.s:6:9 lw $t2, buf",
+            "read of uninitialized 0x7fffeffc..0x7ffff000\n  --> prog.s:9:9 lw $t3, 0($sp)",
+        ]);
+
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_uninit_check(Some(UninitCheck::Trap));
+        let result = loop {
+            match vm.execute() {
+                Ok(MachineState::Running) => {}
+                result => break result,
+            }
+        };
+        assert!(matches!(result, Err(MachineException::UninitializedRead(WatchTarget::Register(8)))));
+
+        // the same seed gives the same noise
+        let run = |seed| {
+            let mut vm = VirtualMachine::new();
+            vm.load_program(&program);
+            vm.randomize(seed);
+            while let Ok(MachineState::Running) = vm.execute() {}
+            (vm.reg_get(8), vm.reg_get(11), vm.reg_get(29))
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        assert_eq!(run(7).2, STACK_POINTER);
+    }
+
+    #[test]
+    fn test_loops() {
+        let src = "\
+main:   li $t0, 1
+loop:   add $t0, $t0, $t0
+        j loop
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        assert_eq!(vm.hottest_loop(), None);
+        for _ in 0..40 {
+            vm.execute().unwrap();
+        }
+        let hot = vm.hottest_loop().unwrap();
+        assert_eq!(vm.runtime_dbg.describe_loop(&hot), "loop (prog.s:2-3), 7 iterations");
+
+        let program = crate::parser::parse_source("prog.s", "main: li $t0, 1\nspin: j spin\n").unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.execute().unwrap();
+        vm.execute().unwrap();
+        assert!(matches!(vm.execute(), Err(MachineException::InfiniteLoop)));
+    }
+
+    #[test]
+    fn test_console_syscalls() {
+        let src = "\
+        .data
+buf:    .space 8
+        .text
+main:   li $v0, 5
+        syscall
+        add $a0, $v0, $v0
+        li $v0, 1
+        syscall
+        li $v0, 8
+        la $a0, buf
+        li $a1, 4
+        syscall
+        li $v0, 4
+        syscall
+        li $v0, 12
+        syscall
+        add $t0, $v0, $zero
+        li $v0, 10
+        syscall
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_console(Box::new(crate::console::Buffer::new("21\nhello\nx")));
+        vm.set_history_budget(100);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert_eq!(vm.console().output(), b"42hel");
+        assert_eq!(vm.reg_get(8), b'x' as u32);
+        assert_eq!(vm.memory().read_word(0x1001_0000).unwrap(), u32::from_le_bytes(*b"hel\0"));
+
+        // undone reads give their input back
+        while vm.step_back() {}
+        assert!(vm.console().output().is_empty());
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert_eq!(vm.console().output(), b"42hel");
+
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_console(Box::new(crate::console::Buffer::new("twenty\n")));
+        let result = loop {
+            match vm.execute() {
+                Ok(MachineState::Running) => {}
+                result => break result,
+            }
+        };
+        assert!(matches!(result, Err(MachineException::Io(message)) if message == "invalid integer input: twenty"));
+    }
+
+    #[test]
+    fn test_keyboard_display() {
+        let src = "\
+main:   la $s0, 0xffff0000
+        lw $t0, 0($s0)
+        lw $t1, 4($s0)
+        sw $t1, 12($s0)
+        li $t2, 2
+        sw $t2, 0($s0)
+        li $t3, 1
+        li $t3, 2
+        li $t3, 3
+        li $v0, 10
+        syscall
+        .ktext
+handler: lw $k0, 4($s0)
+        sw $k0, 12($s0)
+        mfc0 $k1, $13
+        eret
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_console(Box::new(crate::console::Buffer::new("ab\n")));
+        vm.set_history_budget(1000);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(vm.runtime_dbg.get_exception().is_none());
+        // the ready bit was polled, the rest of the input was echoed by the handler
+        assert_eq!(vm.reg_get(8), 1);
+        assert_eq!(vm.console().output(), b"ab\n");
+        assert_eq!(vm.reg_get(27), crate::mmio::KEYBOARD_INTERRUPT);
+        assert_eq!(vm.cp0(STATUS) & STATUS_EXL, 0);
+        assert_eq!(vm.reg_get(11), 3);
+
+        while vm.step_back() {}
+        assert_eq!(vm.cp0(STATUS), STATUS_ON_START);
+        assert!(vm.console().output().is_empty());
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert_eq!(vm.console().output(), b"ab\n");
+
+        // without a handler interrupts are never taken
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", "la $s0, 0xffff0000\nli $t2, 2\nsw $t2, 0($s0)\nli $v0, 10\nsyscall").unwrap());
+        vm.set_console(Box::new(crate::console::Buffer::new("ab\n")));
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(vm.console().output().is_empty());
+        assert!(vm.runtime_dbg.get_exception().is_none());
+    }
+
+    #[test]
+    fn test_timer_interrupts() {
+        let src = "\
+main:   li $s2, 1
+        li $t0, 6
+        mtc0 $t0, $11
+        li $t3, 1
+        li $t3, 2
+        li $t3, 3
+        li $t3, 4
+        li $v0, 10
+        syscall
+        .ktext
+handler: add $s1, $s1, $s2
+        mfc0 $k1, $13
+        la $k0, 1000
+        mtc0 $k0, $11
+        eret
+";
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", src).unwrap());
+        vm.set_history_budget(1000);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(vm.runtime_dbg.get_exception().is_none());
+        // Count reached Compare with the 6th instruction
+        assert_eq!(vm.reg_get(17), 1);
+        assert_eq!(vm.reg_get(27), crate::timer::COMPARE_INTERRUPT);
+        assert_eq!(vm.cp0(EPC), TEXT_SEGMENT_BASE + 4 * 6);
+        assert_eq!(vm.reg_get(11), 4);
+        assert_eq!(vm.cp0(COUNT), 14);
+        while vm.step_back() {}
+        assert_eq!(vm.cp0(COUNT), 0);
+        assert_eq!(vm.cp0(COMPARE), 0);
+
+        // the interval timer keeps interrupting until the program ends
+        let src = "\
+main:   li $s2, 1
+        la $s0, 0xffff0020
+        li $t0, 2
+        sw $t0, 0($s0)
+        li $t0, 5
+        sw $t0, 4($s0)
+        li $t3, 1
+        li $t3, 2
+        li $t3, 3
+        li $t3, 4
+        li $t3, 5
+        li $v0, 10
+        syscall
+        .ktext
+handler: add $s1, $s1, $s2
+        li $k0, 2
+        sw $k0, 0($s0)
+        eret
+";
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", src).unwrap());
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(vm.runtime_dbg.get_exception().is_none());
+        assert_eq!(vm.reg_get(11), 5);
+        assert_eq!(vm.reg_get(17), 2);
+        // the handler takes 4 of every 5 instructions
+        assert_eq!(vm.cp0(EPC), TEXT_SEGMENT_BASE + 4 * 12);
+
+        // a jump to itself idles until Count reaches Compare
+        let src = "\
+main:   li $t0, 20
+        mtc0 $t0, $11
+wait:   j wait
+        .ktext
+handler: li $v0, 10
+        syscall
+";
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", src).unwrap());
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(vm.runtime_dbg.get_exception().is_none());
+        assert_eq!(vm.cp0(EPC), TEXT_SEGMENT_BASE + 4 * 2);
+        assert_eq!(vm.cp0(COUNT), 22);
+
+        // with interrupts disabled it can never get out
+        let src = src.replace("wait:", "        mtc0 $zero, $12\nwait:");
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", &src).unwrap());
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(matches!(vm.runtime_dbg.get_exception(), Some(MachineException::InfiniteLoop)));
+    }
+
+    #[test]
+    fn test_bitmap() {
+        let src = "\
+main:   la $s0, 0x10010000
+        la $s1, 0xffff0010
+        la $t0, 0x00ff0000
+        sw $t0, 0($s0)
+        sw $zero, 0($s1)
+        la $t0, 0x000000ff
+        sw $t0, 4($s0)
+        sw $zero, 0($s1)
+        lw $t1, 0($s1)
+        li $v0, 10
+        syscall
+";
+        let dir = std::env::temp_dir().join(format!("mipstenite-bitmap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", src).unwrap());
+        vm.set_bitmap(Some(Bitmap::new(0x1001_0000, (2, 1), (1, 1)).unwrap()));
+        vm.save_frames(dir.join("frame.ppm"), 1);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(vm.runtime_dbg.get_exception().is_none());
+        assert_eq!(vm.frames(), 2);
+        assert_eq!(vm.reg_get(9), 2);
+        assert_eq!(std::fs::read(dir.join("frame-0001.ppm")).unwrap(), b"P6\n2 1\n255\n\xff\0\0\0\0\0");
+        assert_eq!(std::fs::read(dir.join("frame-0002.ppm")).unwrap(), b"P6\n2 1\n255\n\xff\0\0\0\0\xff");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the frame register only exists along with the display
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", src).unwrap());
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert!(matches!(vm.runtime_dbg.get_exception(), Some(MachineException::AddressError)));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let src = "\
+        .data
+total:  .word 0
+        .text
+main:   li $v0, 5
+        syscall
+        sw $v0, total
+        sw $v0, -4($sp)
+        lw $t1, total
+        lw $t2, -4($sp)
+        add $a0, $t1, $t2
+        li $v0, 1
+        syscall
+        li $v0, 10
+        syscall
+";
+        let path = std::env::temp_dir().join(format!("mipstenite-snapshot-{}.snapshot", std::process::id()));
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&crate::parser::parse_source("prog.s", src).unwrap());
+        vm.set_console(Box::new(crate::console::Buffer::new("21\n")));
+        while vm.reg_get(9) != 21 {
+            vm.execute().unwrap();
+        }
+        // part way through `lw $t2, -4($sp)`
+        vm.execute().unwrap();
+        assert_ne!(vm.pc(), vm.instruction_start(vm.pc()));
+        vm.save_snapshot(&path).unwrap();
+
+        let mut resumed = VirtualMachine::load_snapshot(&path).unwrap();
+        assert_eq!(resumed.steps(), vm.steps());
+        assert_eq!(resumed.pc(), vm.pc());
+        assert_eq!(resumed.reg_get(9), 21);
+        resumed.set_console(Box::new(crate::console::Buffer::new("")));
+        while let Ok(MachineState::Running) = resumed.execute() {}
+        assert!(resumed.runtime_dbg.get_exception().is_none());
+        assert_eq!(resumed.console().output(), b"42");
+        assert!(resumed.symbols().get("main").is_some());
+
+        // a snapshot of another format version is refused
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[8] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(VirtualMachine::load_snapshot(&path).unwrap_err().to_string().contains("version"));
+        bytes[8] ^= 0xff;
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert!(VirtualMachine::load_snapshot(&path).unwrap_err().to_string().contains("corrupted"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let src = "\
+main:   li $v0, 5
+        syscall
+        add $a0, $v0, $v0
+        li $v0, 1
+        syscall
+        li $v0, 12
+        syscall
+        li $v0, 10
+        syscall
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_console(Box::new(crate::console::Buffer::new("21\nx")));
+        vm.set_history_budget(100);
+        vm.record();
+        while let Ok(MachineState::Running) = vm.execute() {}
+        // stepping back over a read takes it out of the log
+        for _ in 0..4 {
+            vm.step_back();
+        }
+        while let Ok(MachineState::Running) = vm.execute() {}
+        let log = vm.replay_log().unwrap().clone();
+        assert_eq!(log.events(), [
+            Event { step: 3, syscall: 5, input: b"21".to_vec() },
+            Event { step: 13, syscall: 12, input: b"x".to_vec() },
+        ]);
+
+        // no console input is needed to replay
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_console(Box::new(crate::console::Buffer::new("")));
+        vm.replay(log);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert_eq!(vm.console().output(), b"42");
+        assert_eq!(vm.reg_get(4), 42);
+    }
+
+    #[test]
+    fn test_file_syscalls() {
+        let src = "\
+        .data
+name:   .asciiz \"data.txt\"
+escape: .asciiz \"../data.txt\"
+text:   .ascii \"abc\"
+buf:    .space 8
+        .text
+main:   li $v0, 13
+        la $a0, name
+        li $a1, 1
+        syscall
+        add $a0, $v0, $zero
+        li $v0, 15
+        la $a1, text
+        li $a2, 3
+        syscall
+        li $v0, 16
+        syscall
+        li $v0, 13
+        la $a0, name
+        li $a1, 0
+        syscall
+        add $a0, $v0, $zero
+        li $v0, 14
+        la $a1, buf
+        li $a2, 8
+        syscall
+        add $s0, $v0, $zero
+        li $v0, 15
+        li $a0, 1
+        add $a2, $s0, $zero
+        syscall
+        li $v0, 13
+        la $a0, escape
+        li $a1, 0
+        syscall
+        add $s1, $v0, $zero
+        li $v0, 15
+        add $a0, $s1, $zero
+        li $a1, 0
+        li $a2, 8
+        syscall
+        add $s2, $v0, $zero
+        li $v0, 10
+        syscall
+";
+        let dir = std::env::temp_dir().join(format!("mipstenite-vm-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_console(Box::new(crate::console::Buffer::new("")));
+        vm.set_sandbox(Some(dir.clone()));
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert_eq!(std::fs::read(dir.join("data.txt")).unwrap(), b"abc");
+        assert_eq!(vm.reg_get(16), 3);
+        assert_eq!(vm.console().output(), b"abc");
+        assert_eq!(vm.reg_get(17) as i32, -1);
+        // a bad descriptor fails before the bad buffer is read
+        assert!(vm.runtime_dbg.get_exception().is_none());
+        assert_eq!(vm.reg_get(18) as i32, -1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_random_and_time() {
+        let src = "\
+main:   li $a0, 0
+        li $a1, 10
+        li $v0, 42
+        syscall
+        add $s0, $a0, $zero
+        li $a0, 500
+        li $v0, 32
+        syscall
+        li $v0, 30
+        syscall
+        li $a1, 0
+        li $v0, 10
+        syscall
+";
+        let program = crate::parser::parse_source("prog.s", src).unwrap();
+        let run = |setup: &dyn Fn(&mut VirtualMachine)| {
+            let mut vm = VirtualMachine::new();
+            vm.load_program(&program);
+            vm.set_clock(Clock::Virtual(1000));
+            setup(&mut vm);
+            while let Ok(MachineState::Running) = vm.execute() {}
+            vm
+        };
+        let vm = run(&|vm| vm.set_random_seed(5));
+        assert!(vm.reg_get(16) < 10);
+        assert_eq!(vm.reg_get(4), 1500);
+        assert_eq!(run(&|vm| vm.set_random_seed(5)).reg_get(16), vm.reg_get(16));
+
+        // a seed taken from the clock is replayed
+        let recorded = run(&|vm| vm.record());
+        let log = recorded.replay_log().unwrap().clone();
+        assert_eq!(log.events().len(), 1);
+        let replayed = run(&move |vm| vm.replay(log.clone()));
+        assert_eq!(replayed.reg_get(16), recorded.reg_get(16));
+
+        // undo restores the generators and the clock
+        let mut vm = VirtualMachine::new();
+        vm.load_program(&program);
+        vm.set_clock(Clock::Virtual(0));
+        vm.set_random_seed(1);
+        vm.set_history_budget(100);
+        while let Ok(MachineState::Running) = vm.execute() {}
+        let first = vm.reg_get(16);
+        while vm.step_back() {}
+        while let Ok(MachineState::Running) = vm.execute() {}
+        assert_eq!(vm.reg_get(16), first);
+        assert_eq!(vm.reg_get(4), 500);
+    }
 }